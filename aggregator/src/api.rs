@@ -1,20 +1,31 @@
+use std::collections::{BTreeMap, VecDeque};
 use std::sync::{atomic::Ordering, Arc};
 use std::thread;
 
-use failure::Error;
-use futures::{channel::mpsc, future};
+use failure::{bail, format_err, Error};
+use futures::{
+    channel::{mpsc, oneshot},
+    future,
+};
 use futures_util::{FutureExt, SinkExt, StreamExt, TryStreamExt};
-use log::{debug, info, trace};
-use serde_derive::Deserialize;
+use log::{debug, info, trace, warn};
+use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
 use tokio::runtime::Runtime;
 use warp::{self, ws::WebSocket, Filter, Future, Rejection};
 
-use common::aggregator::{EnvName, Message, ResourceId};
+use common::aggregator::{
+    ClientMessage, EnvName, FullStatus, Message, ResourceId, VersionsAnalysis,
+};
 use common::repo::Id;
+use common::shutdown::Shutdown;
 
+use super::query;
 use super::ServiceState;
 
+/// How many commits of history are sent at once.
+const HISTORY_PAGE_SIZE: usize = 50;
+
 fn health(_state: Arc<ServiceState>) -> impl warp::Reply {
     warp::reply::json(&json!({}))
 }
@@ -31,11 +42,62 @@ async fn deploy(
     // TODO this should be done by another thread...
     // TODO return commit ID
     let _result_commit =
-        do_deploy(state, body).map_err(|e| warp::reject::custom(DeployError(e)))?;
+        do_deploy(state.clone(), body).map_err(|e| warp::reject::custom(DeployError(e)))?;
+    request_fetches(&state);
     Ok(warp::reply())
 }
 
-pub fn start(service_state: Arc<ServiceState>) -> thread::JoinHandle<()> {
+#[derive(Debug)]
+struct CreateVersionError(failure::Error);
+impl warp::reject::Reject for CreateVersionError {}
+
+/// Called by CI when a new version of a resource was built.
+async fn create_version(
+    state: Arc<ServiceState>,
+    body: NewVersionData,
+) -> Result<impl warp::Reply, Rejection> {
+    info!("create version {:?}", body);
+    let result =
+        do_create_version(&state, body).map_err(|e| warp::reject::custom(CreateVersionError(e)))?;
+    if result.created {
+        request_fetches(&state);
+    }
+    Ok(warp::reply::json(&result))
+}
+
+/// Called by webhooks when the versions repo changed.
+fn trigger(state: Arc<ServiceState>) -> impl warp::Reply {
+    state.versions_trigger.request_fetch();
+    warp::reply()
+}
+
+/// Asks all services to fetch the versions repo, so that they pick up a
+/// commit made here without waiting for their poll interval.
+fn request_fetches(state: &ServiceState) {
+    state.versions_trigger.request_fetch();
+    let urls = vec![
+        state.env.deployer_url.clone(),
+        state.env.transitioner_url.clone(),
+    ];
+    thread::spawn(move || {
+        let client = reqwest::blocking::Client::new();
+        for url in urls.into_iter().flatten() {
+            let result = client
+                .post(&format!("{}/trigger", url))
+                .send()
+                .and_then(|r| r.error_for_status());
+            if let Err(e) = result {
+                warn!("Asking {} to fetch failed: {}", url, e);
+            }
+        }
+    });
+}
+
+pub fn start(service_state: Arc<ServiceState>, shutdown: &Shutdown) -> thread::JoinHandle<()> {
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    shutdown.on_shutdown(move || {
+        let _ = shutdown_tx.send(());
+    });
     thread::spawn(move || {
         let rt = Runtime::new().expect("Could not create runtime");
         let port = service_state.env.api_port.unwrap_or(9001);
@@ -47,10 +109,13 @@ pub fn start(service_state: Arc<ServiceState>) -> thread::JoinHandle<()> {
             .and(state.clone())
             .map(health);
         let service_state_1 = service_state.clone();
-        let ws_handler = warp::ws().map(move |ws: warp::ws::Ws| {
-            let service_state = service_state_1.clone();
-            ws.on_upgrade(move |websocket| user_connected(websocket, service_state))
-        });
+        let ws_handler =
+            warp::ws()
+                .and(warp::query())
+                .map(move |ws: warp::ws::Ws, query: ConnectQuery| {
+                    let service_state = service_state_1.clone();
+                    ws.on_upgrade(move |websocket| user_connected(websocket, service_state, query))
+                });
         let api = warp::path("api");
         let ws = api.and(warp::path::end()).and(ws_handler);
         let deploy = api
@@ -59,6 +124,149 @@ pub fn start(service_state: Arc<ServiceState>) -> thread::JoinHandle<()> {
             .and(state.clone())
             .and(warp::body::json())
             .and_then(deploy);
+        let create_version = api
+            .and(warp::path("versions"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(state.clone())
+            .and(warp::body::json())
+            .and_then(create_version);
+        let env_lock = api
+            .and(warp::path("envs"))
+            .and(warp::path::param())
+            .and(warp::path("lock"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(state.clone())
+            .and(warp::body::json())
+            .and_then(|env: String, state: Arc<ServiceState>, data: LockData| {
+                change_lock(state, env, None, LockChange::Lock(data))
+            });
+        let env_unlock = api
+            .and(warp::path("envs"))
+            .and(warp::path::param())
+            .and(warp::path("unlock"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(state.clone())
+            .and(warp::body::json())
+            .and_then(|env: String, state: Arc<ServiceState>, data: UnlockData| {
+                change_lock(state, env, None, LockChange::Unlock(data))
+            });
+        let resource_lock = api
+            .and(warp::path("envs"))
+            .and(warp::path::param())
+            .and(warp::path("resources"))
+            .and(warp::path::param())
+            .and(warp::path("lock"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(state.clone())
+            .and(warp::body::json())
+            .and_then(
+                |env: String, resource: String, state: Arc<ServiceState>, data: LockData| {
+                    change_lock(state, env, Some(resource), LockChange::Lock(data))
+                },
+            );
+        let resource_unlock = api
+            .and(warp::path("envs"))
+            .and(warp::path::param())
+            .and(warp::path("resources"))
+            .and(warp::path::param())
+            .and(warp::path("unlock"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(state.clone())
+            .and(warp::body::json())
+            .and_then(
+                |env: String, resource: String, state: Arc<ServiceState>, data: UnlockData| {
+                    change_lock(state, env, Some(resource), LockChange::Unlock(data))
+                },
+            );
+        let approve = api
+            .and(warp::path("transitions"))
+            .and(warp::path::param())
+            .and(warp::path("approve"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(state.clone())
+            .and(warp::body::json())
+            .and_then(
+                |transition: String, state: Arc<ServiceState>, data: ApprovalData| {
+                    approve(state, transition, data)
+                },
+            );
+        let trigger_transition = api
+            .and(warp::path("transitions"))
+            .and(warp::path::param())
+            .and(warp::path("trigger"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(state.clone())
+            .and(warp::body::json())
+            .and_then(
+                |transition: String, state: Arc<ServiceState>, data: ScheduleData| {
+                    change_schedule(state, transition, ScheduleChange::Trigger(data))
+                },
+            );
+        let postpone = api
+            .and(warp::path("transitions"))
+            .and(warp::path::param())
+            .and(warp::path("postpone"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(state.clone())
+            .and(warp::body::json())
+            .and_then(
+                |transition: String, state: Arc<ServiceState>, data: PostponeData| {
+                    change_schedule(state, transition, ScheduleChange::Postpone(data))
+                },
+            );
+        let skip = api
+            .and(warp::path("transitions"))
+            .and(warp::path::param())
+            .and(warp::path("skip"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(state.clone())
+            .and(warp::body::json())
+            .and_then(
+                |transition: String, state: Arc<ServiceState>, data: ScheduleData| {
+                    change_schedule(state, transition, ScheduleChange::Skip(data))
+                },
+            );
+        let pause = api
+            .and(warp::path("transitions"))
+            .and(warp::path::param())
+            .and(warp::path("pause"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(state.clone())
+            .and(warp::body::json())
+            .and_then(
+                |transition: String, state: Arc<ServiceState>, data: PauseData| {
+                    change_schedule(state, transition, ScheduleChange::Pause(data))
+                },
+            );
+        let resume = api
+            .and(warp::path("transitions"))
+            .and(warp::path::param())
+            .and(warp::path("resume"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(state.clone())
+            .and(warp::body::json())
+            .and_then(
+                |transition: String, state: Arc<ServiceState>, data: ScheduleData| {
+                    change_schedule(state, transition, ScheduleChange::Resume(data))
+                },
+            );
+        let trigger = api
+            .and(warp::path("trigger"))
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(state.clone())
+            .map(trigger);
         let ui = warp::fs::dir(
             service_state
                 .env
@@ -66,16 +274,97 @@ pub fn start(service_state: Arc<ServiceState>) -> thread::JoinHandle<()> {
                 .clone()
                 .unwrap_or("/ui/dist".into()),
         );
-        let routes = health.or(ws).or(deploy).or(ui);
-        rt.spawn(warp::serve(routes).run(([0, 0, 0, 0], port)));
+        let query = query::routes(service_state.clone());
+        let routes = health
+            .or(ws)
+            .or(deploy)
+            .or(create_version)
+            .or(env_lock)
+            .or(env_unlock)
+            .or(resource_lock)
+            .or(resource_unlock)
+            .or(approve)
+            .or(trigger_transition)
+            .or(postpone)
+            .or(skip)
+            .or(pause)
+            .or(resume)
+            .or(trigger)
+            .or(query)
+            .or(ui);
+        let (_, server) = warp::serve(routes)
+            .bind_with_graceful_shutdown(([0, 0, 0, 0], port), shutdown_rx.map(|_| ()));
+        rt.block_on(server);
 
-        rt.shutdown_on_idle();
+        // don't wait for open websocket connections
+        rt.shutdown_now();
     })
 }
 
+#[derive(Debug, Deserialize)]
+struct ConnectQuery {
+    /// The counter of the last update the client got before reconnecting.
+    since: Option<usize>,
+}
+
+/// Returns the messages that bring a client that last saw the update with
+/// counter `since` up to date. That's the missed updates if they are still
+/// known, and the full status otherwise.
+fn initial_messages(
+    full_status: &FullStatus,
+    recent_messages: &VecDeque<Message>,
+    since: Option<usize>,
+) -> Vec<Message> {
+    if let Some(since) = since {
+        if since == full_status.counter {
+            return Vec::new();
+        }
+        let can_resume = since < full_status.counter
+            && recent_messages
+                .front()
+                .and_then(Message::counter)
+                .map_or(false, |first| first <= since + 1);
+        if can_resume {
+            return recent_messages
+                .iter()
+                .filter(|m| m.counter().map_or(false, |c| c > since))
+                .cloned()
+                .collect();
+        }
+    }
+
+    let history = &full_status.analysis.history;
+    let history_offset = history.len().saturating_sub(HISTORY_PAGE_SIZE);
+    let status = FullStatus {
+        counter: full_status.counter,
+        deployers: full_status.deployers.clone(),
+        transitions: full_status.transitions.clone(),
+        transitioner_config_error: full_status.transitioner_config_error.clone(),
+        analysis: VersionsAnalysis {
+            resources: full_status.analysis.resources.clone(),
+            history: history[history_offset..].to_vec(),
+        },
+    };
+    vec![Message::FullStatus {
+        status,
+        history_offset,
+    }]
+}
+
+fn history_page(full_status: &FullStatus, before: usize, limit: usize) -> Message {
+    let history = &full_status.analysis.history;
+    let end = before.min(history.len());
+    let start = end.saturating_sub(limit.min(HISTORY_PAGE_SIZE));
+    Message::History {
+        offset: start,
+        commits: history[start..end].to_vec(),
+    }
+}
+
 fn user_connected(
     websocket: WebSocket,
     service_state: Arc<ServiceState>,
+    query: ConnectQuery,
 ) -> impl Future<Output = ()> {
     let (mut ws_tx, rx) = websocket.split();
 
@@ -105,30 +394,44 @@ fn user_connected(
         }
     });
 
-    // TODO add a counter query parameter
-    let full_status = service_state.full_status.read().unwrap().clone();
-    let tx2 = tx.clone();
-    warp::spawn(async move {
-        if let Err(err) = tx2
-            .clone()
-            .send(Message::FullStatus((*full_status).clone()))
-            .await
-        {
-            info!(
-                "Could not send first message to WebSocket {}: {}",
-                client_id, err
-            );
-        }
-    });
-
     {
+        // registering the client while holding the status lock makes sure
+        // that it gets exactly the updates after the initial messages
+        let full_status = service_state.full_status.read().unwrap();
+        let recent_messages = service_state.recent_messages.lock().unwrap();
+        for msg in initial_messages(&full_status, &recent_messages, query.since) {
+            if let Err(err) = tx.clone().try_send(msg) {
+                info!(
+                    "Could not send first messages to WebSocket {}: {}",
+                    client_id, err
+                );
+            }
+        }
         let mut receivers = service_state.receivers.write().unwrap();
-        receivers.push((client_id, tx));
+        receivers.push((client_id, tx.clone()));
     }
 
+    let service_state_1 = service_state.clone();
     rx.try_for_each(move |msg| {
         trace!("Websocket message for {}: {:?}", client_id, msg);
 
+        if let Ok(text) = msg.to_str() {
+            match serde_json::from_str(text) {
+                Ok(ClientMessage::GetHistory { before, limit }) => {
+                    let page = {
+                        let full_status = service_state_1.full_status.read().unwrap();
+                        history_page(&full_status, before, limit)
+                    };
+                    if let Err(err) = tx.clone().try_send(page) {
+                        info!("Could not send history to WebSocket {}: {}", client_id, err);
+                    }
+                }
+                Err(err) => {
+                    warn!("Invalid message from WebSocket {}: {}", client_id, err);
+                }
+            }
+        }
+
         future::ok(())
     })
     .then(move |r| {
@@ -145,9 +448,13 @@ fn user_connected(
 }
 
 // TODO move this stuff to a better place, and clean it up
+use common::chrono::{DateTime, Utc};
 use common::git::{self, TreeZipper};
 use common::repo;
-use common::transitions::{Lock, Locks};
+use common::transition_state::{Pause, TransitionState, TransitionStates};
+use common::transitions::{
+    Approval, Approvals, Lock, LockKind, LockReason, Locks, TransitionNames, TransitionResult,
+};
 
 use failure::ResultExt;
 use git2::Signature;
@@ -177,17 +484,7 @@ fn do_deploy(service_state: Arc<ServiceState>, data: DeploymentData) -> Result<I
 
         if let Some(version_id) = deployment.version_id {
             zip.descend("version")?;
-
-            zip.rebuild(|b| {
-                // FIXME instead find the actual location of the version file for the resource
-                b.insert(
-                    format!("{}.yaml", deployment.resource.0),
-                    repo::id_to_oid(version_id),
-                    0o100644,
-                )?;
-                Ok(())
-            })?;
-
+            insert_version_file(&mut zip, &deployment.resource, repo::id_to_oid(version_id))?;
             zip.ascend()?;
         }
 
@@ -219,17 +516,31 @@ fn do_deploy(service_state: Arc<ServiceState>, data: DeploymentData) -> Result<I
         return Ok(repo::oid_to_id(head_commit.id()));
     }
 
-    let signature = Signature::now("DM Aggregator", "n/a")?;
+    commit_and_push(
+        &service_state,
+        &repo,
+        &head_commit,
+        &new_tree,
+        &data.message,
+    )
+}
 
-    let message = data.message;
+fn commit_and_push(
+    service_state: &ServiceState,
+    repo: &repo::GitResourceRepo,
+    head_commit: &git2::Commit,
+    new_tree: &git2::Tree,
+    message: &str,
+) -> Result<Id, Error> {
+    let signature = Signature::now("DM Aggregator", "n/a")?;
 
     let commit = repo.repo.commit(
         Some("refs/dm_head"),
         &signature,
         &signature,
-        &message,
-        &new_tree,
-        &[&head_commit],
+        message,
+        new_tree,
+        &[head_commit],
     )?;
 
     info!("Made commit {}. Pushing...", commit);
@@ -241,33 +552,955 @@ fn do_deploy(service_state: Arc<ServiceState>, data: DeploymentData) -> Result<I
     Ok(repo::oid_to_id(commit))
 }
 
+#[derive(Debug, Deserialize)]
+struct NewVersionData {
+    resource: ResourceId,
+    /// The contents of the version file, which need to include `version`.
+    fields: BTreeMap<String, String>,
+    #[serde(default)]
+    change_log: String,
+}
+
+#[derive(Debug, Serialize)]
+struct NewVersionResult {
+    /// The commit that introduced the version.
+    commit_id: Id,
+    version_id: Id,
+    /// Whether a commit was made, as opposed to the version already being
+    /// known.
+    created: bool,
+}
+
+fn new_version_message(resource: &ResourceId, version: &str, change_log: &str) -> String {
+    let mut message = format!("New version {} of {}\n\n", version, resource.0);
+    let change_log = change_log.trim();
+    if !change_log.is_empty() {
+        message.push_str(change_log);
+        message.push_str("\n\n");
+    }
+    message.push_str(&format!(
+        "DM-Resource: {}\nDM-Version: {}\n",
+        resource.0, version
+    ));
+    message
+}
+
+/// The path of the resource's version file below the `version` directory
+/// the zipper points to, if it has one.
+fn find_version_file(
+    zip: &TreeZipper<'_>,
+    resource: &ResourceId,
+) -> Result<Option<std::path::PathBuf>, Error> {
+    for (path, entry) in zip.walk(false) {
+        entry?;
+        if path.file_stem().and_then(|s| s.to_str()) == Some(resource.0.as_str()) {
+            return Ok(Some(path));
+        }
+    }
+    Ok(None)
+}
+
+/// Writes the version file of a resource into the `version` directory the
+/// zipper points to, replacing the existing one wherever it is. A new
+/// resource's version file goes to the top level.
+fn insert_version_file(
+    zip: &mut TreeZipper<'_>,
+    resource: &ResourceId,
+    blob: git2::Oid,
+) -> Result<(), Error> {
+    let path =
+        find_version_file(zip, resource)?.unwrap_or_else(|| format!("{}.yaml", resource.0).into());
+    zip.update_file(&path, |zip, name| {
+        zip.rebuild(|b| {
+            b.insert(name, blob, 0o100644)?;
+            Ok(())
+        })
+    })
+}
+
+/// Writes the version file of a resource into the `version` directory of
+/// an env. Returns the new tree, or `None` if the file already had this
+/// content.
+fn write_version_file<'repo>(
+    repo: &'repo git2::Repository,
+    tree: git2::Tree<'repo>,
+    env: &str,
+    resource: &ResourceId,
+    blob: git2::Oid,
+) -> Result<Option<git2::Tree<'repo>>, Error> {
+    let old_tree_id = tree.id();
+    let mut zip = TreeZipper::from(repo, tree);
+    zip.descend(env)?;
+    zip.descend("version")?;
+    insert_version_file(&mut zip, resource, blob)?;
+    zip.ascend()?;
+    zip.ascend()?;
+    let new_tree = zip.into_inner().expect("new tree should not be None");
+    if new_tree.id() == old_tree_id {
+        Ok(None)
+    } else {
+        Ok(Some(new_tree))
+    }
+}
+
+/// The commit that gave the version file of a resource in an env its
+/// content at `head`.
+fn version_introduced_in(
+    git_repo: &git2::Repository,
+    head: git2::Oid,
+    env: &str,
+    resource: &ResourceId,
+) -> Result<Id, Error> {
+    let mut zip = TreeZipper::from(git_repo, git_repo.find_commit(head)?.tree()?);
+    zip.descend(env)?;
+    zip.descend("version")?;
+    let path = find_version_file(&zip, resource)?
+        .ok_or_else(|| format_err!("no version file for {} in {}", resource.0, env))?;
+    let path = std::path::Path::new(env).join("version").join(path);
+    let commit = repo::determine_last_change(git_repo, head, &path)?;
+    Ok(repo::oid_to_id(commit.id()))
+}
+
+fn do_create_version(
+    service_state: &ServiceState,
+    data: NewVersionData,
+) -> Result<NewVersionResult, Error> {
+    if data.resource.0.is_empty() || data.resource.0.contains('/') {
+        bail!("invalid resource name {:?}", data.resource.0);
+    }
+    let version = data
+        .fields
+        .get("version")
+        .ok_or_else(|| format_err!("the version fields need to include `version`"))?;
+    let env = service_state
+        .env
+        .entry_env
+        .as_ref()
+        .map_or("latest", String::as_str);
+
+    let repo = repo::GitResourceRepo::open(service_state.env.common.clone())?;
+    let mut content =
+        serde_yaml::to_vec(&data.fields).context("serializing version file failed")?;
+    content.extend("\n".as_bytes());
+    let blob = repo.repo.blob(&content).context("writing blob failed")?;
+    let version_id = repo::oid_to_id(blob);
+
+    // CI might retry, and the version might even have been deployed further
+    // already
+    let introduced_in = service_state
+        .full_status
+        .read()
+        .unwrap()
+        .analysis
+        .resources
+        .get(&data.resource)
+        .and_then(|r| r.versions.get(&version_id))
+        .map(|v| v.introduced_in);
+    if let Some(commit_id) = introduced_in {
+        return Ok(NewVersionResult {
+            commit_id,
+            version_id,
+            created: false,
+        });
+    }
+
+    let head_commit = repo.repo.find_commit(repo.head)?;
+    let tree = head_commit.tree()?;
+    let new_tree = match write_version_file(&repo.repo, tree, env, &data.resource, blob)? {
+        Some(new_tree) => new_tree,
+        None => {
+            // not analyzed yet, but already committed
+            return Ok(NewVersionResult {
+                commit_id: version_introduced_in(&repo.repo, repo.head, env, &data.resource)?,
+                version_id,
+                created: false,
+            });
+        }
+    };
+
+    let message = new_version_message(&data.resource, version, &data.change_log);
+    let commit_id = commit_and_push(service_state, &repo, &head_commit, &new_tree, &message)?;
+    Ok(NewVersionResult {
+        commit_id,
+        version_id,
+        created: true,
+    })
+}
+
 fn update_locks<'repo>(
     tree: &mut TreeZipper<'repo>,
     repo: &'repo git2::Repository,
     env: &EnvName,
     mut f: impl FnMut(&mut Locks),
 ) -> Result<(), Error> {
-    let mut locks = if let Some(blob) = tree.get_blob("locks.yaml")? {
-        serde_yaml::from_slice(blob.content())
-            .with_context(|_| format!("deserializing locks.yaml for env {} failed", env.0))?
-    } else {
-        Locks::default()
-    };
+    let mut locks =
+        Locks::load(tree).with_context(|_| format!("loading locks for env {} failed", env.0))?;
 
     f(&mut locks);
-    // TODO clean locks (remove resources locks that are empty)
+    locks.remove_empty();
+
+    locks.save(repo, tree)
+}
+
+#[derive(Debug, Deserialize)]
+struct LockData {
+    reason: String,
+    author: String,
+    /// After this time, the lock doesn't block transitions anymore.
+    expires: Option<DateTime<Utc>>,
+    #[serde(default)]
+    kind: LockKind,
+}
+
+#[derive(Debug, Deserialize)]
+struct UnlockData {
+    /// The reason to remove. If not given, all reasons are removed.
+    reason: Option<String>,
+    author: String,
+}
+
+#[derive(Debug)]
+enum LockChange {
+    Lock(LockData),
+    Unlock(UnlockData),
+}
+
+#[derive(Debug)]
+struct LockError(failure::Error);
+impl warp::reject::Reject for LockError {}
+
+async fn change_lock(
+    state: Arc<ServiceState>,
+    env: String,
+    resource: Option<String>,
+    change: LockChange,
+) -> Result<impl warp::Reply, Rejection> {
+    info!("change lock of {:?} in {}: {:?}", resource, env, change);
+    let commit_id = do_change_lock(
+        &state,
+        &EnvName(env),
+        resource.map(ResourceId).as_ref(),
+        &change,
+    )
+    .map_err(|e| warp::reject::custom(LockError(e)))?;
+    request_fetches(&state);
+    Ok(warp::reply::json(&json!({ "commit_id": commit_id })))
+}
+
+fn lock_message(env: &EnvName, resource: Option<&ResourceId>, change: &LockChange) -> String {
+    let (target, trailer_target) = match resource {
+        Some(resource) => (
+            format!("{} in {}", resource.0, env.0),
+            format!("{}/{}", env.0, resource.0),
+        ),
+        None => (env.0.clone(), env.0.clone()),
+    };
+    match change {
+        LockChange::Lock(data) => {
+            let mut message = format!(
+                "Lock {}: {}\n\nLocked by {}",
+                target, data.reason, data.author
+            );
+            if let Some(expires) = data.expires {
+                message.push_str(&format!(" until {}", expires.to_rfc3339()));
+            }
+            message.push_str(&format!(
+                ".\n\nDM-Lock: {}\nDM-Author: {}\n",
+                trailer_target, data.author
+            ));
+            if let Some(expires) = data.expires {
+                message.push_str(&format!("DM-Expires: {}\n", expires.to_rfc3339()));
+            }
+            message
+        }
+        LockChange::Unlock(data) => {
+            let mut message = format!("Unlock {}", target);
+            if let Some(reason) = &data.reason {
+                message.push_str(&format!(": {}", reason));
+            }
+            message.push_str(&format!(
+                "\n\nUnlocked by {}.\n\nDM-Unlock: {}\nDM-Author: {}\n",
+                data.author, trailer_target, data.author
+            ));
+            message
+        }
+    }
+}
+
+fn apply_lock_change(lock: &mut Lock, change: &LockChange, now: DateTime<Utc>) {
+    match change {
+        LockChange::Lock(data) => {
+            let author = Some(data.author.clone());
+            // keep the original time if nothing else changes
+            let unchanged = lock.reasons.iter().any(|r| {
+                r.reason == data.reason
+                    && r.author == author
+                    && r.expires == data.expires
+                    && r.kind == data.kind
+            });
+            if !unchanged {
+                lock.add(LockReason {
+                    reason: data.reason.clone(),
+                    author,
+                    since: Some(now),
+                    expires: data.expires,
+                    kind: data.kind.clone(),
+                });
+            }
+        }
+        LockChange::Unlock(data) => match &data.reason {
+            Some(reason) => lock.remove_reason(reason),
+            None => lock.reasons.clear(),
+        },
+    }
+}
 
-    let mut serialized = serde_yaml::to_vec(&locks).context("serializing locks file failed")?;
-    serialized.extend("\n".as_bytes());
+fn do_change_lock(
+    service_state: &ServiceState,
+    env: &EnvName,
+    resource: Option<&ResourceId>,
+    change: &LockChange,
+) -> Result<Id, Error> {
+    if let LockChange::Lock(LockData {
+        kind: LockKind::PinToVersion { .. },
+        ..
+    }) = change
+    {
+        if resource.is_none() {
+            bail!("only resources can be pinned to a version");
+        }
+    }
 
-    let blob = repo.blob(&serialized).context("writing blob failed")?;
+    let repo = repo::GitResourceRepo::open(service_state.env.common.clone())?;
+
+    let head_commit = repo.repo.find_commit(repo.head)?;
+    let tree = head_commit.tree()?;
+    let mut zip = TreeZipper::from(&repo.repo, tree.clone());
+    zip.descend(&env.0)?;
+    if !zip.exists() {
+        bail!("env {} doesn't exist", env.0);
+    }
 
-    tree.rebuild(|builder| {
-        builder
-            .insert("locks.yaml", blob, 0o100644)
-            .context("updating locks file failed")?;
-        Ok(())
+    let now = Utc::now();
+    update_locks(&mut zip, &repo.repo, env, |locks| {
+        let lock = match resource {
+            Some(resource) => locks
+                .resource_locks
+                .entry(resource.0.clone())
+                .or_insert_with(Lock::default),
+            None => &mut locks.env_lock,
+        };
+        apply_lock_change(lock, change, now);
     })?;
+    zip.ascend()?;
+
+    let new_tree = zip.into_inner().expect("new tree should not be None");
+    if new_tree.id() == tree.id() {
+        // nothing changed
+        return Ok(repo::oid_to_id(head_commit.id()));
+    }
+
+    let message = lock_message(env, resource, change);
+    commit_and_push(service_state, &repo, &head_commit, &new_tree, &message)
+}
 
+#[derive(Debug, Deserialize)]
+struct ApprovalData {
+    /// The change set the transition is waiting for, so that changes made
+    /// in the meantime aren't approved by accident.
+    change_set: Id,
+    author: String,
+    #[serde(default)]
+    comment: String,
+    /// The approver group the author approves for.
+    group: Option<String>,
+}
+
+#[derive(Debug)]
+struct ApprovalError(failure::Error);
+impl warp::reject::Reject for ApprovalError {}
+
+async fn approve(
+    state: Arc<ServiceState>,
+    transition: String,
+    data: ApprovalData,
+) -> Result<impl warp::Reply, Rejection> {
+    info!("approve transition {}: {:?}", transition, data);
+    let commit_id = do_approve(&state, &transition, &data)
+        .map_err(|e| warp::reject::custom(ApprovalError(e)))?;
+    request_fetches(&state);
+    Ok(warp::reply::json(&json!({ "commit_id": commit_id })))
+}
+
+fn approval_message(transition: &str, data: &ApprovalData) -> String {
+    let mut message = format!("Approve transition {}\n\n", transition);
+    let comment = data.comment.trim();
+    if !comment.is_empty() {
+        message.push_str(comment);
+        message.push_str("\n\n");
+    }
+    message.push_str(&format!(
+        "DM-Approval: {}\nDM-Change-Set: {}\nDM-Author: {}\n",
+        transition, data.change_set, data.author
+    ));
+    if let Some(group) = &data.group {
+        message.push_str(&format!("DM-Approver-Group: {}\n", group));
+    }
+    message
+}
+
+/// Checks that the transition is waiting for an approval of this change
+/// set, by this group.
+fn check_approval(
+    full_status: &FullStatus,
+    transition: &str,
+    data: &ApprovalData,
+) -> Result<(), Error> {
+    let result = full_status
+        .transitions
+        .get(transition)
+        .and_then(|t| t.last_run.as_ref())
+        .map(|run| &run.result);
+    match result {
+        Some(TransitionResult::AwaitingApproval {
+            change_set, group, ..
+        }) => {
+            if *change_set != data.change_set {
+                bail!(
+                    "transition {} is waiting for approval of change set {}",
+                    transition,
+                    change_set
+                );
+            }
+            if group.is_some() && *group != data.group {
+                bail!(
+                    "transition {} needs an approval by group {}",
+                    transition,
+                    group.as_ref().unwrap()
+                );
+            }
+            Ok(())
+        }
+        _ => bail!("transition {} isn't waiting for an approval", transition),
+    }
+}
+
+fn do_approve(
+    service_state: &ServiceState,
+    transition: &str,
+    data: &ApprovalData,
+) -> Result<Id, Error> {
+    check_approval(&service_state.full_status.read().unwrap(), transition, data)?;
+
+    let repo = repo::GitResourceRepo::open(service_state.env.common.clone())?;
+
+    let head_commit = repo.repo.find_commit(repo.head)?;
+    let tree = head_commit.tree()?;
+    let mut zip = TreeZipper::from(&repo.repo, tree.clone());
+    let mut approvals = Approvals::load(&zip).context("loading approvals failed")?;
+    if let Some(approval) = approvals.0.get(transition) {
+        if approval.change_set == data.change_set
+            && approval.author == data.author
+            && approval.group == data.group
+        {
+            // approved already
+            return Ok(repo::oid_to_id(head_commit.id()));
+        }
+    }
+    approvals.0.insert(
+        transition.to_string(),
+        Approval {
+            change_set: data.change_set,
+            author: data.author.clone(),
+            comment: data.comment.clone(),
+            group: data.group.clone(),
+            time: Utc::now(),
+        },
+    );
+    approvals.save(&repo.repo, &mut zip)?;
+
+    let new_tree = zip.into_inner().expect("new tree should not be None");
+    let message = approval_message(transition, data);
+    commit_and_push(service_state, &repo, &head_commit, &new_tree, &message)
+}
+
+#[derive(Debug, Deserialize)]
+struct ScheduleData {
+    author: String,
+    #[serde(default)]
+    comment: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PostponeData {
+    /// The transition doesn't run before this time.
+    until: DateTime<Utc>,
+    author: String,
+    #[serde(default)]
+    comment: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct PauseData {
+    reason: String,
+    author: String,
+}
+
+#[derive(Debug)]
+enum ScheduleChange {
+    /// Runs the transition at the next opportunity, regardless of its
+    /// schedule.
+    Trigger(ScheduleData),
+    Postpone(PostponeData),
+    /// Skips the next scheduled run.
+    Skip(ScheduleData),
+    Pause(PauseData),
+    Resume(ScheduleData),
+}
+
+#[derive(Debug)]
+struct ScheduleError(failure::Error);
+impl warp::reject::Reject for ScheduleError {}
+
+async fn change_schedule(
+    state: Arc<ServiceState>,
+    transition: String,
+    change: ScheduleChange,
+) -> Result<impl warp::Reply, Rejection> {
+    info!("change schedule of transition {}: {:?}", transition, change);
+    let commit_id = do_change_schedule(&state, &transition, &change)
+        .map_err(|e| warp::reject::custom(ScheduleError(e)))?;
+    request_fetches(&state);
+    Ok(warp::reply::json(&json!({ "commit_id": commit_id })))
+}
+
+fn schedule_message(transition: &str, change: &ScheduleChange) -> String {
+    let (summary, comment, trailers) = match change {
+        ScheduleChange::Trigger(data) => (
+            format!("Trigger transition {}", transition),
+            data.comment.as_str(),
+            format!("DM-Trigger: {}\nDM-Author: {}\n", transition, data.author),
+        ),
+        ScheduleChange::Postpone(data) => (
+            format!(
+                "Postpone transition {} until {}",
+                transition,
+                data.until.to_rfc3339()
+            ),
+            data.comment.as_str(),
+            format!(
+                "DM-Postpone: {}\nDM-Until: {}\nDM-Author: {}\n",
+                transition,
+                data.until.to_rfc3339(),
+                data.author
+            ),
+        ),
+        ScheduleChange::Skip(data) => (
+            format!("Skip the next scheduled run of transition {}", transition),
+            data.comment.as_str(),
+            format!("DM-Skip: {}\nDM-Author: {}\n", transition, data.author),
+        ),
+        ScheduleChange::Pause(data) => (
+            format!("Pause transition {}: {}", transition, data.reason),
+            "",
+            format!("DM-Pause: {}\nDM-Author: {}\n", transition, data.author),
+        ),
+        ScheduleChange::Resume(data) => (
+            format!("Resume transition {}", transition),
+            data.comment.as_str(),
+            format!("DM-Resume: {}\nDM-Author: {}\n", transition, data.author),
+        ),
+    };
+    let mut message = format!("{}\n\n", summary);
+    let comment = comment.trim();
+    if !comment.is_empty() {
+        message.push_str(comment);
+        message.push_str("\n\n");
+    }
+    message.push_str(&trailers);
+    message
+}
+
+fn apply_schedule_change(
+    state: &mut TransitionState,
+    transition: &str,
+    change: &ScheduleChange,
+    now: DateTime<Utc>,
+) -> Result<(), Error> {
+    match change {
+        ScheduleChange::Trigger(_) => {
+            if state.paused.is_some() {
+                bail!("transition {} is paused", transition);
+            }
+            // keep the original time if it is triggered already
+            state.triggered = state.triggered.or(Some(now));
+        }
+        ScheduleChange::Postpone(data) => {
+            state.scheduled = Some(data.until);
+            state.triggered = None;
+            state.skipped = None;
+        }
+        ScheduleChange::Skip(_) => {
+            if state.scheduled.is_none() {
+                bail!("transition {} has no scheduled run to skip", transition);
+            }
+            state.skipped = state.scheduled;
+            state.triggered = None;
+        }
+        ScheduleChange::Pause(data) => {
+            let unchanged = state.paused.as_ref().map_or(false, |p| {
+                p.reason == data.reason && p.author == data.author
+            });
+            if !unchanged {
+                state.paused = Some(Pause {
+                    reason: data.reason.clone(),
+                    author: data.author.clone(),
+                    since: now,
+                });
+            }
+        }
+        ScheduleChange::Resume(_) => state.paused = None,
+    }
     Ok(())
 }
+
+fn do_change_schedule(
+    service_state: &ServiceState,
+    transition: &str,
+    change: &ScheduleChange,
+) -> Result<Id, Error> {
+    let repo = repo::GitResourceRepo::open(service_state.env.common.clone())?;
+
+    let head_commit = repo.repo.find_commit(repo.head)?;
+    let tree = head_commit.tree()?;
+    // the transition may not have run yet, so it's looked up in the config
+    // rather than the transitioner status
+    let names = TransitionNames::load(&TreeZipper::from(&repo.repo, tree.clone()))
+        .context("loading transitions config failed")?;
+    if !names.contains(transition) {
+        bail!("unknown transition {}", transition);
+    }
+    let mut states =
+        TransitionStates::load(&repo.repo).context("loading transition states failed")?;
+    let mut state = states.0.get(transition).cloned().unwrap_or_default();
+    apply_schedule_change(&mut state, transition, change, Utc::now())?;
+    states.insert(transition, state);
+
+    let mut zip = TreeZipper::from(&repo.repo, tree.clone());
+    zip.rebuild(|b| states.save(&repo.repo, b))?;
+    let new_tree = zip.into_inner().expect("new tree should not be None");
+    if new_tree.id() == tree.id() {
+        // nothing changed
+        return Ok(repo::oid_to_id(head_commit.id()));
+    }
+
+    let message = schedule_message(transition, change);
+    commit_and_push(service_state, &repo, &head_commit, &new_tree, &message)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::aggregator::ResourceRepoCommit;
+
+    fn commit(message: &str) -> ResourceRepoCommit {
+        ResourceRepoCommit {
+            id: "22817d2a9c7fc1f62d5670ca1e44948446543973".parse().unwrap(),
+            message: message.to_string(),
+            long_message: String::new(),
+            time: Utc::now(),
+            author_name: String::new(),
+            author_email: String::new(),
+            changes: Vec::new(),
+        }
+    }
+
+    fn versions_message(counter: usize) -> Message {
+        Message::Versions {
+            counter,
+            resources: Default::default(),
+            new_commits: vec![commit(&counter.to_string())],
+        }
+    }
+
+    fn counters(messages: &[Message]) -> Vec<Option<usize>> {
+        messages.iter().map(Message::counter).collect()
+    }
+
+    #[test]
+    fn write_version_file_idempotent() {
+        let fixture =
+            git_fixture::RepoFixture::from_str(include_str!("./fixtures/test_repo1.yaml")).unwrap();
+        let head = fixture.get_commit("head").unwrap();
+        let (repo, _tempdir) = fixture.into_inner();
+        let tree = repo.find_commit(head).unwrap().tree().unwrap();
+        let resource = ResourceId("bar".to_string());
+        let blob = repo.blob(b"version: 3\n").unwrap();
+
+        let new_tree = write_version_file(&repo, tree, "latest", &resource, blob)
+            .unwrap()
+            .expect("tree should change");
+        let entry = new_tree
+            .get_path(std::path::Path::new("latest/version/bar.yaml"))
+            .unwrap();
+        assert_eq!(entry.id(), blob);
+        assert!(new_tree
+            .get_path(std::path::Path::new("dev/base/foo"))
+            .is_ok());
+
+        assert!(
+            write_version_file(&repo, new_tree.clone(), "latest", &resource, blob)
+                .unwrap()
+                .is_none()
+        );
+
+        // an existing version file is replaced where it is
+        let resource = ResourceId("foo".to_string());
+        let new_tree = write_version_file(&repo, new_tree, "dev", &resource, blob)
+            .unwrap()
+            .expect("tree should change");
+        let entry = new_tree
+            .get_path(std::path::Path::new("dev/version/foo"))
+            .unwrap();
+        assert_eq!(entry.id(), blob);
+        assert!(new_tree
+            .get_path(std::path::Path::new("dev/version/foo.yaml"))
+            .is_err());
+    }
+
+    #[test]
+    fn version_introduced_in_later_commit() {
+        let fixture =
+            git_fixture::RepoFixture::from_str(include_str!("./fixtures/test_repo1.yaml")).unwrap();
+        let head = fixture.get_commit("head").unwrap();
+        let (repo, _tempdir) = fixture.into_inner();
+        let head_commit = repo.find_commit(head).unwrap();
+        let blob = repo.blob(b"version: 1\n").unwrap();
+        let tree = write_version_file(
+            &repo,
+            head_commit.tree().unwrap(),
+            "latest",
+            &ResourceId("bar".to_string()),
+            blob,
+        )
+        .unwrap()
+        .unwrap();
+        let signature = Signature::now("test", "test").unwrap();
+        let later = repo
+            .commit(
+                None,
+                &signature,
+                &signature,
+                "later",
+                &tree,
+                &[&head_commit],
+            )
+            .unwrap();
+
+        let foo = ResourceId("foo".to_string());
+        assert_eq!(
+            version_introduced_in(&repo, later, "dev", &foo).unwrap(),
+            repo::oid_to_id(head)
+        );
+        let bar = ResourceId("bar".to_string());
+        assert_eq!(
+            version_introduced_in(&repo, later, "latest", &bar).unwrap(),
+            repo::oid_to_id(later)
+        );
+    }
+
+    #[test]
+    fn new_version_message_trailers() {
+        let resource = ResourceId("foo".to_string());
+        assert_eq!(
+            new_version_message(&resource, "3", "Fixed things.\n"),
+            "New version 3 of foo\n\nFixed things.\n\nDM-Resource: foo\nDM-Version: 3\n"
+        );
+        assert_eq!(
+            new_version_message(&resource, "3", ""),
+            "New version 3 of foo\n\nDM-Resource: foo\nDM-Version: 3\n"
+        );
+    }
+
+    #[test]
+    fn initial_messages_resume() {
+        let full_status = FullStatus {
+            counter: 12,
+            ..Default::default()
+        };
+        let recent_messages: VecDeque<_> = (10..=12).map(versions_message).collect();
+
+        assert!(initial_messages(&full_status, &recent_messages, Some(12)).is_empty());
+        assert_eq!(
+            counters(&initial_messages(&full_status, &recent_messages, Some(10))),
+            vec![Some(11), Some(12)]
+        );
+        assert_eq!(
+            counters(&initial_messages(&full_status, &recent_messages, Some(9))),
+            vec![Some(10), Some(11), Some(12)]
+        );
+
+        // updates were missed, or the client saw a counter from before a restart
+        for since in &[None, Some(8), Some(13)] {
+            let messages = initial_messages(&full_status, &recent_messages, *since);
+            match messages.as_slice() {
+                [Message::FullStatus { status, .. }] => assert_eq!(status.counter, 12),
+                _ => panic!("expected full status, got {:?}", messages),
+            }
+        }
+    }
+
+    #[test]
+    fn initial_messages_history_page() {
+        let mut full_status = FullStatus::default();
+        full_status.analysis.history = (0..HISTORY_PAGE_SIZE + 10)
+            .map(|i| commit(&i.to_string()))
+            .collect();
+
+        match initial_messages(&full_status, &VecDeque::new(), None).as_slice() {
+            [Message::FullStatus {
+                status,
+                history_offset,
+            }] => {
+                assert_eq!(*history_offset, 10);
+                assert_eq!(status.analysis.history.len(), HISTORY_PAGE_SIZE);
+                assert_eq!(status.analysis.history[0].message, "10");
+            }
+            messages => panic!("expected full status, got {:?}", messages),
+        }
+
+        match history_page(&full_status, 10, 5) {
+            Message::History { offset, commits } => {
+                assert_eq!(offset, 5);
+                let messages: Vec<_> = commits.iter().map(|c| c.message.as_str()).collect();
+                assert_eq!(messages, vec!["5", "6", "7", "8", "9"]);
+            }
+            message => panic!("expected history, got {:?}", message),
+        }
+        match history_page(&full_status, 3, 5) {
+            Message::History { offset, commits } => {
+                assert_eq!(offset, 0);
+                assert_eq!(commits.len(), 3);
+            }
+            message => panic!("expected history, got {:?}", message),
+        }
+    }
+
+    #[test]
+    fn lock_change_is_idempotent() {
+        let lock_change = LockChange::Lock(LockData {
+            reason: "incident".to_string(),
+            author: "alice".to_string(),
+            expires: None,
+            kind: LockKind::Permanent,
+        });
+        let mut lock = Lock::default();
+        let first = Utc::now();
+        apply_lock_change(&mut lock, &lock_change, first);
+        apply_lock_change(&mut lock, &lock_change, Utc::now());
+        assert_eq!(lock.reasons.len(), 1);
+        assert_eq!(lock.reasons[0].since, Some(first));
+        assert_eq!(lock.reasons[0].author.as_ref().unwrap(), "alice");
+
+        let message = lock_message(
+            &EnvName("prod".to_string()),
+            Some(&ResourceId("foo".to_string())),
+            &lock_change,
+        );
+        assert!(message.starts_with("Lock foo in prod: incident\n"));
+        assert!(message.contains("\nDM-Lock: prod/foo\nDM-Author: alice\n"));
+
+        let unlock_change = LockChange::Unlock(UnlockData {
+            reason: None,
+            author: "bob".to_string(),
+        });
+        apply_lock_change(&mut lock, &unlock_change, Utc::now());
+        assert!(!lock.is_locked(Utc::now()));
+    }
+
+    #[test]
+    fn approval_needs_matching_change_set_and_group() {
+        use common::transitions::{TransitionRunInfo, TransitionStatusInfo};
+
+        let change_set: Id = "22817d2a9c7fc1f62d5670ca1e44948446543973".parse().unwrap();
+        let data = |change_set, group: Option<&str>| ApprovalData {
+            change_set,
+            author: "alice".to_string(),
+            comment: "looks good".to_string(),
+            group: group.map(str::to_string),
+        };
+        let mut status = FullStatus::default();
+        let mut info = TransitionStatusInfo::new();
+        info.last_run = Some(TransitionRunInfo {
+            time: Some(Utc::now()),
+            result: TransitionResult::AwaitingApproval {
+                change_set,
+                resources: vec!["foo".to_string()],
+                group: Some("release".to_string()),
+                since: Some(Utc::now()),
+            },
+        });
+        status.transitions.insert("prod".to_string(), info);
+
+        assert!(check_approval(&status, "prod", &data(change_set, Some("release"))).is_ok());
+        assert!(check_approval(&status, "prod", &data(change_set, None)).is_err());
+        let other: Id = "0000000000000000000000000000000000000001".parse().unwrap();
+        assert!(check_approval(&status, "prod", &data(other, Some("release"))).is_err());
+        assert!(check_approval(&status, "dev", &data(change_set, Some("release"))).is_err());
+
+        assert_eq!(
+            approval_message("prod", &data(change_set, Some("release"))),
+            format!(
+                "Approve transition prod\n\nlooks good\n\nDM-Approval: prod\nDM-Change-Set: {}\nDM-Author: alice\nDM-Approver-Group: release\n",
+                change_set
+            )
+        );
+    }
+
+    #[test]
+    fn schedule_changes() {
+        let data = || ScheduleData {
+            author: "alice".to_string(),
+            comment: String::new(),
+        };
+        let now = Utc::now();
+        let mut state = TransitionState::default();
+        assert!(
+            apply_schedule_change(&mut state, "prod", &ScheduleChange::Skip(data()), now).is_err()
+        );
+
+        let until = now + common::chrono::Duration::hours(2);
+        let postpone = ScheduleChange::Postpone(PostponeData {
+            until,
+            author: "alice".to_string(),
+            comment: "release party".to_string(),
+        });
+        apply_schedule_change(&mut state, "prod", &postpone, now).unwrap();
+        assert_eq!(state.scheduled, Some(until));
+        assert_eq!(
+            schedule_message("prod", &postpone),
+            format!(
+                "Postpone transition prod until {0}\n\nrelease party\n\nDM-Postpone: prod\nDM-Until: {0}\nDM-Author: alice\n",
+                until.to_rfc3339()
+            )
+        );
+
+        apply_schedule_change(&mut state, "prod", &ScheduleChange::Skip(data()), now).unwrap();
+        assert_eq!(state.skipped, Some(until));
+
+        let pause = ScheduleChange::Pause(PauseData {
+            reason: "incident".to_string(),
+            author: "bob".to_string(),
+        });
+        apply_schedule_change(&mut state, "prod", &pause, now).unwrap();
+        apply_schedule_change(&mut state, "prod", &pause, Utc::now()).unwrap();
+        assert_eq!(state.paused.as_ref().unwrap().since, now);
+        let trigger = ScheduleChange::Trigger(data());
+        assert!(apply_schedule_change(&mut state, "prod", &trigger, now).is_err());
+
+        apply_schedule_change(&mut state, "prod", &ScheduleChange::Resume(data()), now).unwrap();
+        apply_schedule_change(&mut state, "prod", &trigger, now).unwrap();
+        assert_eq!(state.paused, None);
+        assert_eq!(state.triggered, Some(now));
+    }
+}
@@ -1,69 +1,64 @@
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
 
-use failure::{bail, Error};
 use log::{error, trace};
-use reqwest;
 
 use common::aggregator::Message;
 use common::deployment::AllDeployerStatus;
+use common::shutdown::Shutdown;
 
+use super::status_stream;
 use super::ServiceState;
-use crate::Env;
 
-fn get_current_deployer_status(env: &Env) -> Result<AllDeployerStatus, Error> {
-    if let Some(deployer_url) = env.deployer_url.as_ref() {
-        Ok(reqwest::blocking::get(&format!("{}/status", deployer_url))?
-            .error_for_status()?
-            .json()?)
-    } else {
-        bail!("no deployer url configured");
-    }
+fn deployed_versions(status: &AllDeployerStatus) -> Vec<(&String, common::repo::Id)> {
+    status
+        .deployers
+        .iter()
+        .map(|(env, s)| (env, s.deployed_version))
+        .collect()
 }
 
-pub fn start(service_state: Arc<ServiceState>) -> thread::JoinHandle<()> {
+pub fn start(service_state: Arc<ServiceState>, shutdown: Arc<Shutdown>) -> thread::JoinHandle<()> {
     thread::spawn(move || {
-        let mut last_status = Default::default();
-        loop {
-            trace!("Retrieve deployer status...");
-            let status = match get_current_deployer_status(&service_state.env) {
-                Ok(status) => status,
-                Err(e) => {
-                    error!(
-                        "Retrieving deployer status failed: {}\n{}",
-                        e,
-                        e.backtrace()
-                    );
-                    for cause in e.iter_causes() {
-                        error!("caused by: {}", cause);
-                    }
-
-                    thread::sleep(Duration::from_secs(1));
-
-                    continue;
-                }
-            };
-
-            if last_status != status {
-                trace!("Deployer status changed: {:?}", status);
+        let url = match service_state.env.deployer_url.as_ref() {
+            Some(deployer_url) => format!("{}/status/events", deployer_url),
+            None => {
+                error!("No deployer url configured");
+                return;
+            }
+        };
+        let mut last_status = AllDeployerStatus::default();
+        status_stream::follow(&url, &shutdown, |status: AllDeployerStatus| {
+            trace!("Deployer status changed: {:?}", status);
 
-                let counter = service_state.update_status(|full_status| {
-                    full_status.deployers = status.clone();
-                });
+            // the deployer might have made a rollback commit
+            if deployed_versions(&last_status) != deployed_versions(&status) {
+                service_state.versions_trigger.request_fetch();
+            }
 
-                // TODO send just a diff here:
-                service_state.send_to_all_clients(Message::DeployerStatus {
+            let changed = status
+                .deployers
+                .iter()
+                .filter(|(env, s)| last_status.deployers.get(*env) != Some(s))
+                .map(|(env, s)| (env.clone(), s.clone()))
+                .collect();
+            let removed = last_status
+                .deployers
+                .keys()
+                .filter(|env| !status.deployers.contains_key(*env))
+                .cloned()
+                .collect();
+            service_state.publish(
+                |full_status| full_status.deployers = status.clone(),
+                |counter| Message::DeployerStatus {
                     counter,
-                    content: status.clone(),
-                });
-
-                last_status = status;
-            } else {
-                trace!("Deployer status unchanged");
-            }
+                    changed,
+                    removed,
+                    config_error: status.config_error.clone(),
+                },
+            );
 
-            thread::sleep(Duration::from_secs(1));
-        }
+            last_status = status;
+        });
     })
 }
@@ -1,24 +1,38 @@
+use std::collections::VecDeque;
 use std::path::PathBuf;
 use std::process;
-use std::sync::{atomic::AtomicU32, Arc, RwLock};
+use std::sync::{atomic::AtomicU32, Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use failure::Error;
 use log::{debug, info};
 use serde_derive::Deserialize;
 
 use common::aggregator::{FullStatus, Message};
+use common::shutdown::{self, Shutdown};
+use common::watch::Trigger;
 
 mod api;
 mod deployer_watch;
+mod query;
+mod status_stream;
 mod transitioner_watch;
 mod versions_watch;
 
+/// How many update messages are kept for resuming clients that reconnect.
+const RECENT_MESSAGES: usize = 100;
+
 #[derive(Debug, Deserialize)]
 struct Env {
     #[serde(flatten)]
     common: common::Env,
     api_port: Option<u16>,
+    shutdown_deadline_secs: Option<u64>,
+    /// How often to fetch the versions repo if no webhook triggers a fetch.
+    poll_interval_secs: Option<u64>,
     ui_path: Option<PathBuf>,
+    /// The env that new versions are added to (default `latest`).
+    entry_env: Option<String>,
     deployer_url: Option<String>,
     transitioner_url: Option<String>,
 }
@@ -26,19 +40,37 @@ struct Env {
 pub struct ServiceState {
     env: Env,
     full_status: RwLock<Arc<FullStatus>>,
+    /// The most recent update messages, oldest first.
+    recent_messages: Mutex<VecDeque<Message>>,
     client_counter: AtomicU32,
     receivers: RwLock<Vec<(u32, futures::channel::mpsc::Sender<Message>)>>,
+    /// Wakes up the versions watch to fetch the versions repo.
+    versions_trigger: Trigger,
 }
 
 impl ServiceState {
-    /// Updates the aggregator status, and returns a counter for the update
-    /// message.
-    pub fn update_status(&self, update_fn: impl FnOnce(&mut FullStatus)) -> usize {
+    /// Updates the aggregator status, and sends the update message built
+    /// from the new counter to all clients. This happens under the status
+    /// lock, so a connecting client either gets the status before the update
+    /// and the message, or the status after it.
+    pub fn publish(
+        &self,
+        update_fn: impl FnOnce(&mut FullStatus),
+        message_fn: impl FnOnce(usize) -> Message,
+    ) {
         let mut write_lock = self.full_status.write().unwrap();
         let full_status = Arc::make_mut(&mut write_lock);
         full_status.counter += 1;
         update_fn(full_status);
-        full_status.counter
+        let message = message_fn(full_status.counter);
+        {
+            let mut recent_messages = self.recent_messages.lock().unwrap();
+            if recent_messages.len() == RECENT_MESSAGES {
+                recent_messages.pop_front();
+            }
+            recent_messages.push_back(message.clone());
+        }
+        self.send_to_all_clients(message);
     }
 
     pub fn send_to_all_clients(&self, msg: Message) {
@@ -53,20 +85,33 @@ impl ServiceState {
     }
 }
 
-fn serve(env: Env) -> Result<(), Error> {
+fn serve(env: Env, shutdown: Arc<Shutdown>) -> Result<(), Error> {
     let receivers = RwLock::new(Vec::with_capacity(100));
-    let full_status = Default::default();
+    // start the counter at the current time, so that a client reconnecting
+    // after a restart can't mistake the new status for one it already has
+    let full_status = FullStatus {
+        counter: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_millis() as usize)
+            .unwrap_or(0),
+        ..Default::default()
+    };
     let service_state = Arc::new(ServiceState {
         env,
-        full_status,
+        full_status: RwLock::new(Arc::new(full_status)),
+        recent_messages: Mutex::new(VecDeque::with_capacity(RECENT_MESSAGES)),
         client_counter: AtomicU32::new(0),
         receivers,
+        versions_trigger: Trigger::new(),
     });
 
-    let versions_watch = versions_watch::start(service_state.clone())?;
-    let api = api::start(service_state.clone());
-    let deployer_watch = deployer_watch::start(service_state.clone());
-    let transitioner_watch = transitioner_watch::start(service_state.clone());
+    let service_state_1 = service_state.clone();
+    shutdown.on_shutdown(move || service_state_1.versions_trigger.notify());
+
+    let versions_watch = versions_watch::start(service_state.clone(), shutdown.clone())?;
+    let api = api::start(service_state.clone(), &shutdown);
+    let deployer_watch = deployer_watch::start(service_state.clone(), shutdown.clone());
+    let transitioner_watch = transitioner_watch::start(service_state.clone(), shutdown.clone());
 
     info!("Aggregator running.");
 
@@ -75,14 +120,20 @@ fn serve(env: Env) -> Result<(), Error> {
     transitioner_watch.join().unwrap();
     versions_watch.join().unwrap();
 
+    info!("Aggregator stopped.");
+
     Ok(())
 }
 
 fn run() -> Result<(), Error> {
     env_logger::init();
-    let env = envy::from_env()?;
+    let env: Env = envy::from_env()?;
+    let deadline = env
+        .shutdown_deadline_secs
+        .map_or(shutdown::DEFAULT_DEADLINE, Duration::from_secs);
+    let shutdown = Shutdown::install(deadline)?;
 
-    serve(env)
+    serve(env, shutdown)
 }
 
 fn main() {
@@ -0,0 +1,590 @@
+//! Read-only REST endpoints over the aggregated status, for scripts and
+//! dashboards that don't want to follow the WebSocket.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+
+use serde_derive::{Deserialize, Serialize};
+use serde_json::json;
+use warp::{self, http::StatusCode, Filter, Rejection};
+
+use common::aggregator::{EnvName, FullStatus, ResourceId, ResourceRepoCommit, ResourceVersion};
+use common::chrono::{DateTime, Utc};
+use common::deployment::{DeployerStatus, ResourceState, RolloutStatus};
+use common::repo::Id;
+use common::transitions::TransitionResult;
+
+use super::ServiceState;
+
+const DEFAULT_HISTORY_LIMIT: usize = 50;
+const MAX_HISTORY_LIMIT: usize = 500;
+
+#[derive(Debug, PartialEq, Serialize)]
+struct VersionRef {
+    version_id: Id,
+    /// The version name, if the version is known.
+    version: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct ResourceSummary {
+    name: String,
+    versions_by_env: BTreeMap<String, VersionRef>,
+}
+
+#[derive(Debug, Serialize)]
+struct ResourceEnvState {
+    version: Option<VersionRef>,
+    base_data: Option<Id>,
+    /// The state reported by the deployer for this env, if any.
+    deployer_state: Option<ResourceState>,
+}
+
+#[derive(Debug, Serialize)]
+struct ResourceDetail {
+    name: String,
+    /// All known versions, oldest first.
+    versions: Vec<ResourceVersion>,
+    envs: BTreeMap<String, ResourceEnvState>,
+}
+
+#[derive(Debug, Serialize)]
+struct EnvSummary {
+    name: String,
+    deployed_version: Option<Id>,
+    rollout_status: Option<RolloutStatus>,
+}
+
+#[derive(Debug, Serialize)]
+struct EnvDetail {
+    name: String,
+    deployer: Option<DeployerStatus>,
+    resources: BTreeMap<String, ResourceEnvState>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct HistoryQuery {
+    env: Option<String>,
+    resource: Option<String>,
+    /// Matches the author name or email, ignoring case.
+    author: Option<String>,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct HistoryPage {
+    /// The number of matching commits.
+    total: usize,
+    offset: usize,
+    /// The matching commits, newest first.
+    commits: Vec<ResourceRepoCommit>,
+}
+
+fn version_ref(status: &FullStatus, resource: &ResourceId, version_id: Id) -> VersionRef {
+    let version = status
+        .analysis
+        .resources
+        .get(resource)
+        .and_then(|r| r.versions.get(&version_id))
+        .map(|v| v.version.clone());
+    VersionRef {
+        version_id,
+        version,
+    }
+}
+
+/// The names of all envs that either have a deployer or appear in the
+/// versions repo.
+fn env_names(status: &FullStatus) -> BTreeSet<String> {
+    let mut envs: BTreeSet<String> = status.deployers.deployers.keys().cloned().collect();
+    for resource in status.analysis.resources.values() {
+        envs.extend(resource.version_by_env.keys().map(|e| e.0.clone()));
+        envs.extend(resource.base_data.keys().map(|e| e.0.clone()));
+    }
+    envs
+}
+
+fn resource_env_state(
+    status: &FullStatus,
+    resource: &ResourceId,
+    env: &str,
+) -> Option<ResourceEnvState> {
+    let env_name = EnvName(env.to_string());
+    let resource_status = status.analysis.resources.get(resource);
+    let version = resource_status
+        .and_then(|r| r.version_by_env.get(&env_name))
+        .map(|v| version_ref(status, resource, *v));
+    let base_data = resource_status.and_then(|r| r.base_data.get(&env_name).cloned());
+    let deployer_state = status
+        .deployers
+        .deployers
+        .get(env)
+        .and_then(|d| d.status_by_resource.get(&resource.0))
+        .cloned();
+    if version.is_none() && base_data.is_none() && deployer_state.is_none() {
+        return None;
+    }
+    Some(ResourceEnvState {
+        version,
+        base_data,
+        deployer_state,
+    })
+}
+
+fn resource_summaries(status: &FullStatus) -> Vec<ResourceSummary> {
+    let mut summaries: Vec<_> = status
+        .analysis
+        .resources
+        .iter()
+        .map(|(id, resource)| ResourceSummary {
+            name: id.0.clone(),
+            versions_by_env: resource
+                .version_by_env
+                .iter()
+                .map(|(env, v)| (env.0.clone(), version_ref(status, id, *v)))
+                .collect(),
+        })
+        .collect();
+    summaries.sort_by(|a, b| a.name.cmp(&b.name));
+    summaries
+}
+
+fn resource_detail(status: &FullStatus, name: &str) -> Option<ResourceDetail> {
+    let id = ResourceId(name.to_string());
+    let resource = status.analysis.resources.get(&id)?;
+    let envs = env_names(status)
+        .into_iter()
+        .filter_map(|env| resource_env_state(status, &id, &env).map(|s| (env, s)))
+        .collect();
+    Some(ResourceDetail {
+        name: name.to_string(),
+        versions: resource.versions.values().cloned().collect(),
+        envs,
+    })
+}
+
+fn env_summaries(status: &FullStatus) -> Vec<EnvSummary> {
+    env_names(status)
+        .into_iter()
+        .map(|name| {
+            let deployer = status.deployers.deployers.get(&name);
+            EnvSummary {
+                deployed_version: deployer.map(|d| d.deployed_version),
+                rollout_status: deployer.map(|d| d.rollout_status.clone()),
+                name,
+            }
+        })
+        .collect()
+}
+
+fn env_detail(status: &FullStatus, name: &str) -> Option<EnvDetail> {
+    if !env_names(status).contains(name) {
+        return None;
+    }
+    let resources = status
+        .analysis
+        .resources
+        .keys()
+        .filter_map(|id| resource_env_state(status, id, name).map(|s| (id.0.clone(), s)))
+        .collect();
+    Some(EnvDetail {
+        name: name.to_string(),
+        deployer: status.deployers.deployers.get(name).cloned(),
+        resources,
+    })
+}
+
+fn commit_matches(commit: &ResourceRepoCommit, query: &HistoryQuery) -> bool {
+    if let Some(env) = &query.env {
+        if !commit
+            .changes
+            .iter()
+            .any(|c| c.env().map_or(false, |e| &e.0 == env))
+        {
+            return false;
+        }
+    }
+    if let Some(resource) = &query.resource {
+        if !commit.changes.iter().any(|c| &c.resource().0 == resource) {
+            return false;
+        }
+    }
+    if let Some(author) = &query.author {
+        let author = author.to_lowercase();
+        if commit.author_name.to_lowercase() != author
+            && commit.author_email.to_lowercase() != author
+        {
+            return false;
+        }
+    }
+    if query.since.map_or(false, |since| commit.time < since) {
+        return false;
+    }
+    if query.until.map_or(false, |until| commit.time >= until) {
+        return false;
+    }
+    true
+}
+
+fn history_page(status: &FullStatus, query: &HistoryQuery) -> HistoryPage {
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .min(MAX_HISTORY_LIMIT);
+    let matching: Vec<_> = status
+        .analysis
+        .history
+        .iter()
+        .rev()
+        .filter(|c| commit_matches(c, query))
+        .collect();
+    HistoryPage {
+        total: matching.len(),
+        offset: query.offset,
+        commits: matching
+            .into_iter()
+            .skip(query.offset)
+            .take(limit)
+            .cloned()
+            .collect(),
+    }
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct PendingApproval {
+    transition: String,
+    change_set: Id,
+    resources: Vec<String>,
+    group: Option<String>,
+    /// When the transition started waiting for this change set.
+    since: Option<DateTime<Utc>>,
+}
+
+fn pending_approvals(status: &FullStatus) -> Vec<PendingApproval> {
+    status
+        .transitions
+        .iter()
+        .filter_map(|(name, info)| {
+            let run = info.last_run.as_ref()?;
+            match &run.result {
+                TransitionResult::AwaitingApproval {
+                    change_set,
+                    resources,
+                    group,
+                    since,
+                } => Some(PendingApproval {
+                    transition: name.clone(),
+                    change_set: *change_set,
+                    resources: resources.clone(),
+                    group: group.clone(),
+                    since: *since,
+                }),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+fn found_or_404<T: serde::Serialize>(value: Option<T>, what: &str) -> impl warp::Reply {
+    match value {
+        Some(value) => warp::reply::with_status(warp::reply::json(&value), StatusCode::OK),
+        None => warp::reply::with_status(
+            warp::reply::json(&json!({ "error": format!("{} not found", what) })),
+            StatusCode::NOT_FOUND,
+        ),
+    }
+}
+
+fn current_status(state: &ServiceState) -> Arc<FullStatus> {
+    state.full_status.read().unwrap().clone()
+}
+
+/// The query endpoints below `/api`.
+pub fn routes(
+    service_state: Arc<ServiceState>,
+) -> impl Filter<Extract = impl warp::Reply, Error = Rejection> + Clone {
+    let state = warp::any().map(move || current_status(&service_state));
+    let api = warp::path("api");
+    let resources = api
+        .and(warp::path("resources"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(state.clone())
+        .map(|status: Arc<FullStatus>| warp::reply::json(&resource_summaries(&status)));
+    let resource = api
+        .and(warp::path("resources"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(state.clone())
+        .map(|name: String, status: Arc<FullStatus>| {
+            found_or_404(resource_detail(&status, &name), "resource")
+        });
+    let envs = api
+        .and(warp::path("envs"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(state.clone())
+        .map(|status: Arc<FullStatus>| warp::reply::json(&env_summaries(&status)));
+    let env = api
+        .and(warp::path("envs"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(state.clone())
+        .map(|name: String, status: Arc<FullStatus>| {
+            found_or_404(env_detail(&status, &name), "env")
+        });
+    let history = api
+        .and(warp::path("history"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(state.clone())
+        .and(warp::query())
+        .map(|status: Arc<FullStatus>, query: HistoryQuery| {
+            warp::reply::json(&history_page(&status, &query))
+        });
+    let approvals = api
+        .and(warp::path("approvals"))
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(state)
+        .map(|status: Arc<FullStatus>| warp::reply::json(&pending_approvals(&status)));
+    resources
+        .or(resource)
+        .or(envs)
+        .or(env)
+        .or(history)
+        .or(approvals)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::aggregator::{ResourceRepoChange, VersionsAnalysis};
+    use common::chrono::TimeZone;
+    use common::deployment::{AllDeployerStatus, RolloutStatusReason};
+
+    fn id(n: u8) -> Id {
+        format!("{:040x}", n).parse().unwrap()
+    }
+
+    fn commit(
+        n: u8,
+        author: &str,
+        day: u32,
+        changes: Vec<ResourceRepoChange>,
+    ) -> ResourceRepoCommit {
+        ResourceRepoCommit {
+            id: id(n),
+            message: format!("Commit {}", n),
+            long_message: String::new(),
+            time: Utc.ymd(2019, 10, day).and_hms(12, 0, 0),
+            author_name: author.to_string(),
+            author_email: format!("{}@example.com", author.to_lowercase()),
+            changes,
+        }
+    }
+
+    fn version(resource: &str, n: u8, commit: u8) -> ResourceRepoChange {
+        ResourceRepoChange::Version {
+            resource: ResourceId(resource.to_string()),
+            version: ResourceVersion {
+                version_id: id(n),
+                introduced_in: id(commit),
+                version: n.to_string(),
+                change_log: String::new(),
+            },
+        }
+    }
+
+    fn deployed(resource: &str, env: &str, n: u8) -> ResourceRepoChange {
+        ResourceRepoChange::VersionDeployed {
+            resource: ResourceId(resource.to_string()),
+            env: EnvName(env.to_string()),
+            previous_version_id: None,
+            version_id: id(n),
+        }
+    }
+
+    fn make_status() -> FullStatus {
+        let mut analysis = VersionsAnalysis::default();
+        analysis.add_commit(commit(
+            1,
+            "Alice",
+            1,
+            vec![version("foo", 10, 1), deployed("foo", "dev", 10)],
+        ));
+        analysis.add_commit(commit(
+            2,
+            "Bob",
+            2,
+            vec![version("bar", 20, 2), deployed("bar", "dev", 20)],
+        ));
+        analysis.add_commit(commit(3, "Alice", 3, vec![deployed("foo", "prod", 10)]));
+
+        let mut deployers = AllDeployerStatus::default();
+        deployers.deployers.insert(
+            "prod".to_string(),
+            DeployerStatus {
+                deployed_version: id(3),
+                last_successfully_deployed_version: Some(id(3)),
+                rollout_status: RolloutStatus::Clean,
+                status_by_resource: vec![(
+                    "foo".to_string(),
+                    ResourceState::Deployed {
+                        version: id(10),
+                        expected_version: id(10),
+                        status: RolloutStatusReason::Clean,
+                    },
+                )]
+                .into_iter()
+                .collect(),
+                clean_since: Default::default(),
+            },
+        );
+
+        FullStatus {
+            deployers,
+            analysis,
+            ..Default::default()
+        }
+    }
+
+    fn messages(page: &HistoryPage) -> Vec<&str> {
+        page.commits.iter().map(|c| c.message.as_str()).collect()
+    }
+
+    #[test]
+    fn resources() {
+        let status = make_status();
+        let summaries = resource_summaries(&status);
+        let names: Vec<_> = summaries.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["bar", "foo"]);
+        assert_eq!(
+            summaries[1].versions_by_env["prod"],
+            VersionRef {
+                version_id: id(10),
+                version: Some("10".to_string())
+            }
+        );
+
+        let detail = resource_detail(&status, "foo").unwrap();
+        assert_eq!(detail.versions.len(), 1);
+        let envs: Vec<_> = detail.envs.keys().map(String::as_str).collect();
+        assert_eq!(envs, vec!["dev", "prod"]);
+        assert!(detail.envs["dev"].deployer_state.is_none());
+        assert!(detail.envs["prod"].deployer_state.is_some());
+
+        assert!(resource_detail(&status, "baz").is_none());
+    }
+
+    #[test]
+    fn envs() {
+        let status = make_status();
+        let summaries = env_summaries(&status);
+        let names: Vec<_> = summaries.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec!["dev", "prod"]);
+        assert_eq!(summaries[0].rollout_status, None);
+        assert_eq!(summaries[1].rollout_status, Some(RolloutStatus::Clean));
+
+        let detail = env_detail(&status, "dev").unwrap();
+        let resources: Vec<_> = detail.resources.keys().map(String::as_str).collect();
+        assert_eq!(resources, vec!["bar", "foo"]);
+        assert!(detail.deployer.is_none());
+
+        assert!(env_detail(&status, "pp").is_none());
+    }
+
+    #[test]
+    fn approvals() {
+        use common::transitions::{SkipReason, TransitionRunInfo, TransitionStatusInfo};
+
+        let mut status = make_status();
+        let run = |result| {
+            let mut info = TransitionStatusInfo::new();
+            info.last_run = Some(TransitionRunInfo {
+                time: Some(Utc.ymd(2019, 10, 4).and_hms(14, 0, 0)),
+                result,
+            });
+            info
+        };
+        status.transitions.insert(
+            "prod".to_string(),
+            run(TransitionResult::AwaitingApproval {
+                change_set: id(30),
+                resources: vec!["foo".to_string()],
+                group: None,
+                since: Some(Utc.ymd(2019, 10, 4).and_hms(12, 0, 0)),
+            }),
+        );
+        status.transitions.insert(
+            "dev".to_string(),
+            run(TransitionResult::Skipped(SkipReason::NoChange)),
+        );
+
+        assert_eq!(
+            pending_approvals(&status),
+            vec![PendingApproval {
+                transition: "prod".to_string(),
+                change_set: id(30),
+                resources: vec!["foo".to_string()],
+                group: None,
+                since: Some(Utc.ymd(2019, 10, 4).and_hms(12, 0, 0)),
+            }]
+        );
+    }
+
+    #[test]
+    fn history_filters() {
+        let status = make_status();
+
+        let page = history_page(&status, &HistoryQuery::default());
+        assert_eq!(page.total, 3);
+        assert_eq!(messages(&page), vec!["Commit 3", "Commit 2", "Commit 1"]);
+
+        let query = HistoryQuery {
+            env: Some("dev".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            messages(&history_page(&status, &query)),
+            vec!["Commit 2", "Commit 1"]
+        );
+
+        let query = HistoryQuery {
+            resource: Some("foo".to_string()),
+            author: Some("alice@EXAMPLE.com".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            messages(&history_page(&status, &query)),
+            vec!["Commit 3", "Commit 1"]
+        );
+
+        let query = HistoryQuery {
+            since: Some(Utc.ymd(2019, 10, 2).and_hms(0, 0, 0)),
+            until: Some(Utc.ymd(2019, 10, 3).and_hms(0, 0, 0)),
+            ..Default::default()
+        };
+        assert_eq!(messages(&history_page(&status, &query)), vec!["Commit 2"]);
+    }
+
+    #[test]
+    fn history_pagination() {
+        let status = make_status();
+        let query = HistoryQuery {
+            offset: 1,
+            limit: Some(1),
+            ..Default::default()
+        };
+        let page = history_page(&status, &query);
+        assert_eq!(page.total, 3);
+        assert_eq!(page.offset, 1);
+        assert_eq!(messages(&page), vec!["Commit 2"]);
+    }
+}
@@ -0,0 +1,38 @@
+//! Follows the status event streams of the deployer and the transitioner.
+
+use std::io::BufReader;
+
+use failure::Error;
+use serde::de::DeserializeOwned;
+
+use common::event_stream;
+use common::shutdown::Shutdown;
+
+/// Follows the status events at `url` until shutdown, calling `on_status`
+/// for every status received.
+pub fn follow<T: DeserializeOwned>(url: &str, shutdown: &Shutdown, mut on_status: impl FnMut(T)) {
+    let client = reqwest::blocking::Client::new();
+    event_stream::follow(
+        url,
+        shutdown,
+        |last_event_id| connect(&client, url, last_event_id),
+        |event, data| {
+            if event == "status" {
+                on_status(serde_json::from_str(data)?);
+            }
+            Ok(())
+        },
+    );
+}
+
+fn connect(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    last_event_id: Option<&str>,
+) -> Result<BufReader<reqwest::blocking::Response>, Error> {
+    let mut request = client.get(url).header("Accept", "text/event-stream");
+    if let Some(id) = last_event_id {
+        request = request.header("Last-Event-ID", id);
+    }
+    Ok(BufReader::new(request.send()?.error_for_status()?))
+}
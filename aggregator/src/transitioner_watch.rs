@@ -1,88 +1,57 @@
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
 
-use failure::{bail, Error};
 use log::{error, trace};
-use reqwest;
 
 use common::aggregator::Message;
-use common::chrono::{self, Utc};
-use common::transitions::AllTransitionStatus;
+use common::shutdown::Shutdown;
+use common::transitions::TransitionerStatus;
 
+use super::status_stream;
 use super::ServiceState;
-use crate::Env;
 
-fn get_current_transitioner_status(env: &Env) -> Result<AllTransitionStatus, Error> {
-    if let Some(transitioner_url) = env.transitioner_url.as_ref() {
-        Ok(
-            reqwest::blocking::get(&format!("{}/status", transitioner_url))?
-                .error_for_status()?
-                .json()?,
-        )
-    } else {
-        bail!("no transitioner url configured");
-    }
-}
-
-pub fn start(service_state: Arc<ServiceState>) -> thread::JoinHandle<()> {
+pub fn start(service_state: Arc<ServiceState>, shutdown: Arc<Shutdown>) -> thread::JoinHandle<()> {
     thread::spawn(move || {
-        let mut last_status = Default::default();
-        loop {
-            trace!("Retrieve transitioner status...");
-            let mut status = match get_current_transitioner_status(&service_state.env) {
-                Ok(status) => status,
-                Err(e) => {
-                    error!(
-                        "Retrieving transitioner status failed: {}\n{}",
-                        e,
-                        e.backtrace()
-                    );
-                    for cause in e.iter_causes() {
-                        error!("caused by: {}", cause);
-                    }
-
-                    thread::sleep(Duration::from_secs(1));
-
-                    continue;
-                }
-            };
-
-            // If the last run for a transition happened within the last 10
-            // seconds, we remove the time. This way, we don't send an update
-            // every second.
-            for (_, transition_status) in &mut status {
-                if let Some(last_run) = &mut transition_status.last_run {
-                    let within_last_10_seconds = if let Some(time) = last_run.time {
-                        (time - Utc::now()) < chrono::Duration::seconds(10)
-                    } else {
-                        false
-                    };
-
-                    if within_last_10_seconds {
-                        last_run.time = None;
-                    }
-                }
+        let url = match service_state.env.transitioner_url.as_ref() {
+            Some(transitioner_url) => format!("{}/status/events", transitioner_url),
+            None => {
+                error!("No transitioner url configured");
+                return;
             }
-
-            if last_status != status {
-                trace!("Transitioner status changed: {:?}", status);
-
-                let counter = service_state.update_status(|full_status| {
-                    full_status.transitions = status.clone();
-                });
-
-                service_state.send_to_all_clients(Message::TransitionStatus {
+        };
+        let mut last_status = TransitionerStatus::default();
+        status_stream::follow(&url, &shutdown, |status: TransitionerStatus| {
+            trace!("Transitioner status changed: {:?}", status);
+
+            // a transition might have made a commit
+            service_state.versions_trigger.request_fetch();
+
+            let changed = status
+                .transitions
+                .iter()
+                .filter(|(name, s)| last_status.transitions.get(*name) != Some(s))
+                .map(|(name, s)| (name.clone(), s.clone()))
+                .collect();
+            let removed = last_status
+                .transitions
+                .keys()
+                .filter(|name| !status.transitions.contains_key(*name))
+                .cloned()
+                .collect();
+            service_state.publish(
+                |full_status| {
+                    full_status.transitions = status.transitions.clone();
+                    full_status.transitioner_config_error = status.config_error.clone();
+                },
+                |counter| Message::TransitionStatus {
                     counter,
-                    transitions: status.clone(),
-                });
-
-                last_status = status;
-            } else {
-                trace!("Transitioner status unchanged");
-            }
-
-            thread::sleep(Duration::from_secs(1));
-        }
+                    changed,
+                    removed,
+                    config_error: status.config_error.clone(),
+                },
+            );
+
+            last_status = status;
+        });
     })
 }
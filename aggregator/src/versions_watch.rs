@@ -1,7 +1,7 @@
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use failure::{format_err, Error};
 use git2::{Commit, Oid, Sort};
@@ -15,6 +15,8 @@ use common::aggregator::{
 };
 use common::chrono::{TimeZone, Utc};
 use common::repo::{self, GitResourceRepo, ResourceRepo};
+use common::shutdown::Shutdown;
+use common::watch;
 
 use super::ServiceState;
 
@@ -201,11 +203,19 @@ fn analyze_commits(
     Ok(())
 }
 
-pub fn start(service_state: Arc<ServiceState>) -> Result<thread::JoinHandle<()>, Error> {
+pub fn start(
+    service_state: Arc<ServiceState>,
+    shutdown: Arc<Shutdown>,
+) -> Result<thread::JoinHandle<()>, Error> {
     let mut repo = repo::GitResourceRepo::open(service_state.env.common.clone())?;
     // TODO move to the ResourceRepo abstraction
     let mut last_head = None;
     let mut last_analysis: VersionsAnalysis = Default::default();
+    let poll_interval = service_state
+        .env
+        .poll_interval_secs
+        .map_or(watch::DEFAULT_POLL_INTERVAL, Duration::from_secs);
+    let mut last_fetch = Instant::now();
     let handle = thread::spawn(move || loop {
         if last_head != Some(repo.head) {
             let mut new_analysis = last_analysis.clone();
@@ -213,23 +223,39 @@ pub fn start(service_state: Arc<ServiceState>) -> Result<thread::JoinHandle<()>,
                 error!("Error analyzing commits: {}", e);
             } else {
                 last_head = Some(repo.head);
+                let resources = new_analysis
+                    .resources
+                    .iter()
+                    .filter(|(id, r)| last_analysis.resources.get(*id) != Some(r))
+                    .map(|(id, r)| (id.clone(), r.clone()))
+                    .collect();
+                let new_commits = new_analysis.history[last_analysis.history.len()..].to_vec();
                 last_analysis = new_analysis;
 
-                let counter = service_state.update_status(|full_status| {
-                    full_status.analysis = last_analysis.clone();
-                });
-                service_state.send_to_all_clients(Message::Versions {
-                    counter,
-                    analysis: last_analysis.clone(),
-                });
+                service_state.publish(
+                    |full_status| full_status.analysis = last_analysis.clone(),
+                    |counter| Message::Versions {
+                        counter,
+                        resources,
+                        new_commits,
+                    },
+                );
             }
         }
 
-        thread::sleep(Duration::from_secs(1));
+        let timeout = poll_interval - last_fetch.elapsed().min(poll_interval);
+        service_state.versions_trigger.wait(timeout);
+        if shutdown.is_requested() {
+            break;
+        }
 
         if let Err(e) = repo.update() {
             error!("Error updating versions repo: {}", e);
-            thread::sleep(Duration::from_secs(1));
+            if shutdown.sleep(Duration::from_secs(1)) {
+                break;
+            }
+        } else {
+            last_fetch = Instant::now();
         }
     });
     Ok(handle)
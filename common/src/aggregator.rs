@@ -1,10 +1,10 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 use chrono::{DateTime, Utc};
 use indexmap::IndexMap;
 use serde_derive::{Deserialize, Serialize};
 
-use crate::deployment::AllDeployerStatus;
+use crate::deployment::{AllDeployerStatus, DeployerStatus};
 use crate::repo::Id;
 use crate::transitions::AllTransitionStatus;
 
@@ -58,6 +58,30 @@ pub enum ResourceRepoChange {
        // TODO schedule changes
 }
 
+impl ResourceRepoChange {
+    pub fn resource(&self) -> &ResourceId {
+        use self::ResourceRepoChange::*;
+        match self {
+            Version { resource, .. }
+            | Deployable { resource, .. }
+            | BaseData { resource, .. }
+            | VersionDeployed { resource, .. } => resource,
+        }
+    }
+
+    /// The env this change applies to, or `None` for new versions, which
+    /// aren't specific to an env.
+    pub fn env(&self) -> Option<&EnvName> {
+        use self::ResourceRepoChange::*;
+        match self {
+            Version { .. } => None,
+            Deployable { env, .. } | BaseData { env, .. } | VersionDeployed { env, .. } => {
+                Some(env)
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct ResourceRepoCommit {
     pub id: Id,
@@ -134,29 +158,79 @@ pub struct FullStatus {
     #[serde(flatten)]
     pub deployers: AllDeployerStatus,
     pub transitions: AllTransitionStatus,
+    #[serde(default)]
+    pub transitioner_config_error: Option<String>,
     #[serde(flatten)]
     pub analysis: VersionsAnalysis,
 }
 
+/// A message from the aggregator to the ui. Apart from `History`, every
+/// message is an update that increments the counter by one, so clients can
+/// notice missed updates and resume from the last counter they saw.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum Message {
-    FullStatus(FullStatus),
+    /// The complete status, except that `history` only contains the most
+    /// recent commits. Sent when a client connects without being able to
+    /// resume.
+    FullStatus {
+        #[serde(flatten)]
+        status: FullStatus,
+        /// The index of the first commit in `history` within the full
+        /// history.
+        history_offset: usize,
+    },
     DeployerStatus {
         counter: usize,
-        #[serde(flatten)]
-        content: AllDeployerStatus,
+        /// The deployers whose status changed.
+        changed: BTreeMap<String, DeployerStatus>,
+        removed: Vec<String>,
+        #[serde(default)]
+        config_error: Option<String>,
     },
     TransitionStatus {
         counter: usize,
-        transitions: AllTransitionStatus,
+        /// The transitions whose status changed.
+        changed: AllTransitionStatus,
+        removed: Vec<String>,
+        #[serde(default)]
+        config_error: Option<String>,
     },
-    // TODO only send analyzed versions repo commits, and reconstruct full data client-side
-    // -- except for the first message
-    // TODO don't send full repo history unless needed
     Versions {
         counter: usize,
-        #[serde(flatten)]
-        analysis: VersionsAnalysis,
+        /// The resources whose status changed.
+        resources: HashMap<ResourceId, ResourceStatus>,
+        /// The commits added to the end of the history.
+        new_commits: Vec<ResourceRepoCommit>,
+    },
+    /// A page of history, in reply to `ClientMessage::GetHistory`.
+    History {
+        /// The index of the first commit in `commits` within the full
+        /// history.
+        offset: usize,
+        commits: Vec<ResourceRepoCommit>,
     },
 }
+
+impl Message {
+    /// The counter of the aggregator status after this update, if this is
+    /// an update.
+    pub fn counter(&self) -> Option<usize> {
+        match self {
+            Message::FullStatus { status, .. } => Some(status.counter),
+            Message::DeployerStatus { counter, .. }
+            | Message::TransitionStatus { counter, .. }
+            | Message::Versions { counter, .. } => Some(*counter),
+            Message::History { .. } => None,
+        }
+    }
+}
+
+/// A message from the ui to the aggregator.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum ClientMessage {
+    /// Requests up to `limit` commits of the history before the index
+    /// `before`.
+    GetHistory { before: usize, limit: usize },
+}
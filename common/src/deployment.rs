@@ -1,6 +1,7 @@
 use std::collections::BTreeMap;
 use std::collections::HashMap;
 
+use chrono::{DateTime, Utc};
 use serde_derive::{Deserialize, Serialize};
 
 use crate::repo::Id;
@@ -38,11 +39,61 @@ pub struct DeployerStatus {
     pub last_successfully_deployed_version: Option<Id>,
     pub rollout_status: RolloutStatus,
     pub status_by_resource: HashMap<String, ResourceState>,
+    /// For each resource whose current version is cleanly rolled out, since
+    /// when it has been.
+    #[serde(default)]
+    pub clean_since: HashMap<String, CleanSince>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CleanSince {
+    pub version: Id,
+    pub since: DateTime<Utc>,
+}
+
+impl DeployerStatus {
+    /// Records when resources became clean, and forgets about the ones that
+    /// aren't clean anymore.
+    pub fn update_clean_since(&mut self, now: DateTime<Utc>) {
+        let status_by_resource = &self.status_by_resource;
+        self.clean_since
+            .retain(|resource, _| status_by_resource.contains_key(resource));
+        for (resource, state) in status_by_resource {
+            match state {
+                ResourceState::Deployed {
+                    version,
+                    expected_version,
+                    status: RolloutStatusReason::Clean,
+                } if version == expected_version => {
+                    let entry = self
+                        .clean_since
+                        .entry(resource.clone())
+                        .or_insert(CleanSince {
+                            version: *version,
+                            since: now,
+                        });
+                    if entry.version != *version {
+                        *entry = CleanSince {
+                            version: *version,
+                            since: now,
+                        };
+                    }
+                }
+                _ => {
+                    self.clean_since.remove(resource);
+                }
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
 pub struct AllDeployerStatus {
     pub deployers: BTreeMap<String, DeployerStatus>,
+    /// Set if the current deployer config is invalid, in which case the
+    /// deployer keeps using the previous one.
+    #[serde(default)]
+    pub config_error: Option<String>,
 }
 
 impl AllDeployerStatus {
@@ -87,4 +138,21 @@ pub enum ResourceState {
         #[serde(flatten)]
         status: RolloutStatusReason,
     },
+    /// The resource was not deployed because it violates policies.
+    PolicyViolation {
+        version: Id,
+        violations: Vec<PolicyViolation>,
+    },
+    /// The resource was not deployed because its version file has no base
+    /// file.
+    MissingBaseFile {
+        version: Id,
+        base_file: String,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PolicyViolation {
+    pub policy: String,
+    pub message: String,
 }
@@ -0,0 +1,139 @@
+//! Client for the status event streams of the deployer and the transitioner.
+
+use std::io::BufRead;
+use std::time::Duration;
+
+use failure::{bail, Error};
+use log::{debug, error};
+
+use crate::shutdown::Shutdown;
+
+/// How long to wait before reconnecting after the stream failed.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Follows the event stream at `url` until shutdown, calling `on_event` with
+/// the type and data of every event. `connect` opens the stream, passing on
+/// the last event id received, if any, so that the server only sends the
+/// status again if it changed in the meantime. Reconnects after failures.
+pub fn follow<R: BufRead>(
+    url: &str,
+    shutdown: &Shutdown,
+    mut connect: impl FnMut(Option<&str>) -> Result<R, Error>,
+    mut on_event: impl FnMut(&str, &str) -> Result<(), Error>,
+) {
+    let mut last_event_id = None;
+    while !shutdown.is_requested() {
+        let result = connect(last_event_id.as_deref()).and_then(|reader| {
+            debug!("Connected to {}", url);
+            parse_events(reader, shutdown, &mut last_event_id, &mut on_event)
+        });
+        if let Err(e) = result {
+            error!("Following {} failed: {}\n{}", url, e, e.backtrace());
+            for cause in e.iter_causes() {
+                error!("caused by: {}", cause);
+            }
+
+            if shutdown.sleep(RETRY_DELAY) {
+                break;
+            }
+        }
+    }
+}
+
+/// Reads events from one connection until it fails or shutdown is
+/// requested. The server sends keep-alive events regularly, so the shutdown
+/// check doesn't have to wait long.
+fn parse_events(
+    reader: impl BufRead,
+    shutdown: &Shutdown,
+    last_event_id: &mut Option<String>,
+    on_event: &mut impl FnMut(&str, &str) -> Result<(), Error>,
+) -> Result<(), Error> {
+    let mut event = String::new();
+    let mut data = String::new();
+    let mut id = None;
+    for line in reader.lines() {
+        if shutdown.is_requested() {
+            return Ok(());
+        }
+        let line = line?;
+        if line.is_empty() {
+            // a blank line dispatches the event
+            on_event(&event, &data)?;
+            if let Some(id) = id.take() {
+                *last_event_id = Some(id);
+            }
+            event.clear();
+            data.clear();
+            continue;
+        }
+        let (field, value) = match line.find(':') {
+            Some(i) if line[i + 1..].starts_with(' ') => (&line[..i], &line[i + 2..]),
+            Some(i) => (&line[..i], &line[i + 1..]),
+            None => (line.as_str(), ""),
+        };
+        match field {
+            "event" => event = value.to_string(),
+            "data" => {
+                if !data.is_empty() {
+                    data.push('\n');
+                }
+                data.push_str(value);
+            }
+            "id" => id = Some(value.to_string()),
+            // comments and unknown fields
+            _ => {}
+        }
+    }
+    bail!("the event stream ended");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_events() {
+        let stream = "\
+: comment
+id: 5-1
+event: status
+data: [1,
+data:2]
+
+id: 5-1
+event: keep-alive
+data:
+
+id: 5-2
+event: status
+data: [3]
+
+id: 5-3
+event: status
+data: [4]
+";
+        let mut last_event_id = None;
+        let mut events = Vec::new();
+        let result = parse_events(
+            stream.as_bytes(),
+            &Shutdown::new(),
+            &mut last_event_id,
+            &mut |event: &str, data: &str| {
+                events.push((event.to_string(), data.to_string()));
+                Ok(())
+            },
+        );
+        // the stream ended before the last event was dispatched
+        assert!(result.is_err());
+        assert_eq!(
+            events,
+            vec![
+                ("status".to_string(), "[1,\n2]".to_string()),
+                ("keep-alive".to_string(), "".to_string()),
+                ("status".to_string(), "[3]".to_string()),
+            ]
+        );
+        assert_eq!(last_event_id, Some("5-2".to_string()));
+    }
+}
@@ -1,9 +1,9 @@
 use std::ffi::OsStr;
 use std::ops::Range;
 use std::os::unix::ffi::OsStrExt;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 
-use failure::{format_err, Error, ResultExt};
+use failure::{bail, format_err, Error, ResultExt};
 use git2::{self, Blob, Commit, ObjectType, Repository, Tree, TreeBuilder, TreeEntry};
 
 use crate::config::Env;
@@ -188,6 +188,42 @@ impl<'repo> TreeZipper<'repo> {
         })?))
     }
 
+    /// Descends to the directory containing `path`, calls `f` with the file
+    /// name, and ascends back to where the zipper was.
+    pub fn update_file(
+        &mut self,
+        path: &Path,
+        f: impl FnOnce(&mut TreeZipper<'repo>, &str) -> Result<(), Error>,
+    ) -> Result<(), Error> {
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        let name = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .ok_or_else(|| format_err!("invalid file name {:?}", path))?;
+
+        let mut depth = 0;
+        for component in dir.components() {
+            match component {
+                Component::Normal(part) => {
+                    let part = part
+                        .to_str()
+                        .ok_or_else(|| format_err!("non-utf8 path: {:?}", path))?;
+                    self.descend(part)?;
+                    depth += 1;
+                }
+                _ => bail!("unexpected path component in file name: {:?}", component),
+            }
+        }
+
+        f(self, name)?;
+
+        for _ in 0..depth {
+            self.ascend()?;
+        }
+
+        Ok(())
+    }
+
     pub fn walk(
         &self,
         include_dirs: bool,
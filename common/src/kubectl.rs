@@ -0,0 +1,70 @@
+//! Runs kubectl, for what the services don't do via the Kubernetes API.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+use failure::{bail, Error, ResultExt};
+use log::debug;
+use serde_derive::Deserialize;
+
+/// Where to run kubectl. Unset fields are left to kubectl's defaults.
+#[derive(Debug, Default, Clone, PartialEq, Deserialize)]
+pub struct KubeTarget {
+    pub namespace: Option<String>,
+    pub context: Option<String>,
+    pub cluster: Option<String>,
+    pub user: Option<String>,
+}
+
+/// Runs kubectl with the arguments against the target, piping `input` to
+/// it, and returns what it printed.
+pub fn kubectl(target: &KubeTarget, args: &[&str], input: Option<&str>) -> Result<String, Error> {
+    let mut builder = Command::new("kubectl");
+    if let Some(namespace) = &target.namespace {
+        builder.arg("--namespace").arg(namespace);
+    }
+    if let Some(context) = &target.context {
+        builder.arg("--context").arg(context);
+    }
+    if let Some(cluster) = &target.cluster {
+        builder.arg("--cluster").arg(cluster);
+    }
+    if let Some(user) = &target.user {
+        builder.arg("--user").arg(user);
+    }
+    builder.args(args);
+    let mut process = builder
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("running kubectl failed")?;
+
+    if let Some(input) = input {
+        process
+            .stdin
+            .as_mut()
+            .unwrap()
+            .write_all(input.as_bytes())
+            .context("piping data to kubectl failed")?;
+    }
+
+    let output = process
+        .wait_with_output()
+        .context("kubectl wasn't running")?;
+
+    if !output.status.success() {
+        bail!(
+            "kubectl {} failed; stderr: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    debug!(
+        "kubectl stderr: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
@@ -1,9 +1,18 @@
 pub mod aggregator;
 mod config;
 pub mod deployment;
+pub mod event_stream;
 pub mod git;
+pub mod kubectl;
+#[cfg(feature = "policies")]
+pub mod merge;
+#[cfg(feature = "policies")]
+pub mod policy;
 pub mod repo;
+pub mod shutdown;
+pub mod transition_state;
 pub mod transitions;
+pub mod watch;
 
 pub use crate::config::{Config, Env};
 
@@ -0,0 +1,69 @@
+//! Merging version files into the base files of an env, which gives the
+//! resources the deployer deploys.
+
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::path::Path;
+
+use failure::{format_err, Error};
+pub use jsonnet::JsonnetVm;
+use serde_json::Value;
+
+/// Merges the content of a version file into its base file. Jsonnet base
+/// files get the version file as `std.extVar("version")`; in yaml base
+/// files, `$version` is replaced by the version.
+pub fn merge_version_file(
+    vm: &mut JsonnetVm,
+    path: &Path,
+    base_file_name: &Path,
+    base_file_content: &[u8],
+    content: &[u8],
+) -> Result<Value, Error> {
+    if base_file_name.extension() == Some(OsStr::new("jsonnet")) {
+        // FIXME implement import handler
+        let content: Value = serde_yaml::from_slice(content)?;
+        vm.ext_code("version", &serde_json::to_string(&content)?);
+        let result = vm
+            .evaluate_snippet(path, std::str::from_utf8(base_file_content)?)
+            .map_err(|e| format_err!("jsonnet error: {}", e.as_str()))?;
+        Ok(serde_json::from_str(&result)?)
+    } else {
+        let content = serde_yaml::from_slice(content)?;
+        let base_file_content = serde_yaml::from_slice(base_file_content)?;
+        Ok(merge_resource(base_file_content, &content))
+    }
+}
+
+fn merge_resource(
+    mut base: serde_json::Value,
+    version_content: &BTreeMap<String, String>,
+) -> serde_json::Value {
+    use serde_json::*;
+    // TODO rewrite everything about this
+    fn merge_mut(base: &mut Value, version_content: &BTreeMap<String, String>) {
+        match base {
+            Value::String(s) => {
+                let regex = regex::Regex::new("\\$version").unwrap();
+                let replaced = regex
+                    .replace_all(&s, move |_cap: &regex::Captures<'_>| {
+                        version_content.get("version").cloned().unwrap_or_default()
+                    })
+                    .into_owned();
+                *s = replaced;
+            }
+            Value::Array(s) => {
+                for element in s {
+                    merge_mut(element, version_content);
+                }
+            }
+            Value::Object(m) => {
+                for (_, value) in m {
+                    merge_mut(value, version_content);
+                }
+            }
+            Value::Null | Value::Bool(_) | Value::Number(_) => {}
+        }
+    }
+    merge_mut(&mut base, version_content);
+    base
+}
@@ -0,0 +1,593 @@
+//! Policies are rules that merged resources have to satisfy to be deployed.
+//!
+//! They live in the `policies` folder at the top level of the resource repo,
+//! one policy per file. A yaml policy is a list of rules in a small
+//! expression language, optionally restricted to some envs and resource
+//! kinds:
+//!
+//! ```yaml
+//! description: Images must come from our registry
+//! envs: [prod]
+//! kinds: [Deployment]
+//! rules:
+//!   - 'spec.template.spec.containers[*].image =~ "^registry.example.com/"'
+//!   - 'spec.template.spec.containers[*].image !~ ":latest$"'
+//! ```
+//!
+//! A rule is a path into the resource, where `[*]` matches all elements of an
+//! array, followed by one of these operators:
+//!  - `exists` / `absent`
+//!  - `== <json>` / `!= <json>`
+//!  - `=~ "<regex>"` / `!~ "<regex>"`
+//!
+//! Comparisons only apply to values that are present.
+//!
+//! A jsonnet policy gets the resource as `std.extVar("resource")` and the env
+//! as `std.extVar("env")`, and evaluates to a list of violation messages.
+
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::Path;
+use std::str::FromStr;
+
+use failure::{bail, format_err, Error, ResultExt};
+use git2::{Repository, Tree};
+use jsonnet::JsonnetVm;
+use log::warn;
+use regex::Regex;
+use serde_derive::Deserialize;
+use serde_json::Value;
+
+use crate::deployment::PolicyViolation;
+use crate::git::TreeZipper;
+use crate::repo::ResourceRepo;
+
+pub const POLICIES_DIR: &str = "policies";
+
+#[derive(Debug)]
+pub struct Policies(Vec<Policy>);
+
+#[derive(Debug)]
+struct Policy {
+    name: String,
+    kind: PolicyKind,
+}
+
+#[derive(Debug)]
+enum PolicyKind {
+    Rules {
+        description: Option<String>,
+        envs: Vec<String>,
+        kinds: Vec<String>,
+        rules: Vec<Rule>,
+    },
+    Jsonnet {
+        file_name: String,
+        source: String,
+    },
+    /// A policy file that can't be loaded. It counts as violated by every
+    /// resource it applies to, which is all of them if it's not clear which.
+    Invalid {
+        envs: Vec<String>,
+        kinds: Vec<String>,
+        error: String,
+    },
+}
+
+#[derive(Debug, Deserialize)]
+struct RulesPolicy {
+    description: Option<String>,
+    #[serde(default)]
+    envs: Vec<String>,
+    #[serde(default)]
+    kinds: Vec<String>,
+    rules: Vec<String>,
+}
+
+impl Policies {
+    /// Loads the policies of the resource repo. Files that aren't valid
+    /// policies are loaded as `Invalid`.
+    pub fn load(repo: &impl ResourceRepo) -> Result<Policies, Error> {
+        let mut policies = Vec::new();
+        repo.walk_contents(Path::new(POLICIES_DIR), |path, content| {
+            let name = path
+                .file_stem()
+                .map(|s| s.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let kind = load_policy(&path, content);
+            policies.push(Policy { name, kind });
+            Ok(())
+        })?;
+        Ok(Policies(policies))
+    }
+
+    /// Loads the policies of a tree of the resource repo, like `load`.
+    pub fn load_tree(repo: &Repository, tree: Tree<'_>) -> Result<Policies, Error> {
+        let mut zipper = TreeZipper::from(repo, tree);
+        zipper.descend(POLICIES_DIR)?;
+        let mut policies = Vec::new();
+        for (path, entry) in zipper.walk(false) {
+            let object = entry?.to_object(repo)?;
+            if let Some(blob) = object.as_blob() {
+                let name = path
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                let kind = load_policy(&path, blob.content().to_vec());
+                policies.push(Policy { name, kind });
+            }
+        }
+        Ok(Policies(policies))
+    }
+
+    /// Returns all violations of the merged resource in the env.
+    pub fn check(&self, env: &str, resource: &Value) -> Vec<PolicyViolation> {
+        let mut violations = Vec::new();
+        for policy in &self.0 {
+            let messages = match &policy.kind {
+                PolicyKind::Rules {
+                    description,
+                    envs,
+                    kinds,
+                    rules,
+                } => {
+                    if !applies_to(envs, kinds, env, resource) {
+                        continue;
+                    }
+                    rules
+                        .iter()
+                        .flat_map(|rule| rule.check(resource))
+                        .map(|detail| match description {
+                            Some(description) => format!("{}: {}", description, detail),
+                            None => detail,
+                        })
+                        .collect()
+                }
+                PolicyKind::Jsonnet { file_name, source } => {
+                    check_jsonnet(file_name, source, env, resource)
+                        .unwrap_or_else(|e| vec![format!("policy could not be evaluated: {}", e)])
+                }
+                PolicyKind::Invalid { envs, kinds, error } => {
+                    if !applies_to(envs, kinds, env, resource) {
+                        continue;
+                    }
+                    vec![format!("policy is invalid: {}", error)]
+                }
+            };
+            violations.extend(messages.into_iter().map(|message| PolicyViolation {
+                policy: policy.name.clone(),
+                message,
+            }));
+        }
+        violations
+    }
+}
+
+/// Parses a policy file. A file that isn't a valid policy becomes an
+/// `Invalid` one, for the envs and kinds it was meant for if they are known.
+fn load_policy(path: &Path, content: Vec<u8>) -> PolicyKind {
+    let invalid = |envs, kinds, error: Error| {
+        warn!("Invalid policy {:?}: {}", path, error);
+        PolicyKind::Invalid {
+            envs,
+            kinds,
+            error: error.to_string(),
+        }
+    };
+    if path.extension() == Some(OsStr::new("jsonnet")) {
+        return match String::from_utf8(content) {
+            Ok(source) => PolicyKind::Jsonnet {
+                file_name: path.to_string_lossy().into_owned(),
+                source,
+            },
+            Err(e) => invalid(vec![], vec![], e.into()),
+        };
+    }
+    let policy: RulesPolicy = match serde_yaml::from_slice(&content) {
+        Ok(policy) => policy,
+        Err(e) => return invalid(vec![], vec![], e.into()),
+    };
+    match policy
+        .rules
+        .iter()
+        .map(|r| r.parse())
+        .collect::<Result<Vec<Rule>, Error>>()
+    {
+        Ok(rules) => PolicyKind::Rules {
+            description: policy.description,
+            envs: policy.envs,
+            kinds: policy.kinds,
+            rules,
+        },
+        Err(e) => invalid(policy.envs, policy.kinds, e),
+    }
+}
+
+/// Whether a policy for these envs and resource kinds applies to the
+/// resource in the env. Empty lists mean all envs or kinds.
+fn applies_to(envs: &[String], kinds: &[String], env: &str, resource: &Value) -> bool {
+    let kind = resource.get("kind").and_then(Value::as_str);
+    (envs.is_empty() || envs.iter().any(|e| e == env))
+        && (kinds.is_empty() || kinds.iter().any(|k| Some(k.as_str()) == kind))
+}
+
+fn check_jsonnet(
+    file_name: &str,
+    source: &str,
+    env: &str,
+    resource: &Value,
+) -> Result<Vec<String>, Error> {
+    let mut vm = JsonnetVm::new();
+    vm.ext_code("resource", &serde_json::to_string(resource)?);
+    vm.ext_code("env", &serde_json::to_string(env)?);
+    let result = vm
+        .evaluate_snippet(file_name, source)
+        .map_err(|e| format_err!("jsonnet error: {}", e.as_str()))?;
+    let messages: Vec<String> = serde_json::from_str(&result)
+        .map_err(|_| format_err!("policy must evaluate to a list of messages"))?;
+    Ok(messages)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Step {
+    Field(String),
+    Index(usize),
+    All,
+}
+
+impl fmt::Display for Step {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Step::Field(name) => write!(f, ".{}", name),
+            Step::Index(i) => write!(f, "[{}]", i),
+            Step::All => write!(f, "[*]"),
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Condition {
+    Exists,
+    Absent,
+    Equals(Value),
+    NotEquals(Value),
+    Matches(Regex),
+    NotMatches(Regex),
+}
+
+#[derive(Debug)]
+struct Rule {
+    path: Vec<Step>,
+    condition: Condition,
+}
+
+impl FromStr for Rule {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Rule, Error> {
+        let mut parts = s.trim().splitn(2, char::is_whitespace);
+        let path = parse_path(parts.next().unwrap_or(""))?;
+        let mut parts = parts
+            .next()
+            .unwrap_or("")
+            .trim()
+            .splitn(2, char::is_whitespace);
+        let operator = parts.next().unwrap_or("");
+        let operand = parts.next().map(str::trim);
+
+        let value = || -> Result<Value, Error> {
+            let operand = operand.ok_or_else(|| format_err!("{} needs an operand", operator))?;
+            Ok(serde_json::from_str(operand)
+                .with_context(|_| format!("invalid operand {}", operand))?)
+        };
+        let regex = || -> Result<Regex, Error> {
+            match value()? {
+                Value::String(s) => Ok(Regex::new(&s)?),
+                _ => bail!("{} needs a string operand", operator),
+            }
+        };
+
+        let condition = match operator {
+            "exists" | "absent" if operand.is_some() => {
+                bail!("{} does not take an operand", operator)
+            }
+            "exists" => Condition::Exists,
+            "absent" => Condition::Absent,
+            "==" => Condition::Equals(value()?),
+            "!=" => Condition::NotEquals(value()?),
+            "=~" => Condition::Matches(regex()?),
+            "!~" => Condition::NotMatches(regex()?),
+            _ => bail!("invalid rule {:?}: unknown operator {:?}", s, operator),
+        };
+
+        Ok(Rule { path, condition })
+    }
+}
+
+fn parse_path(s: &str) -> Result<Vec<Step>, Error> {
+    let mut path = Vec::new();
+    for segment in s.split('.') {
+        let (field, mut rest) = match segment.find('[') {
+            Some(i) => segment.split_at(i),
+            None => (segment, ""),
+        };
+        if field.is_empty() {
+            bail!("invalid path {:?}: empty field name", s);
+        }
+        path.push(Step::Field(field.to_string()));
+        while !rest.is_empty() {
+            let end = rest
+                .find(']')
+                .ok_or_else(|| format_err!("invalid path {:?}: missing ]", s))?;
+            path.push(match &rest[1..end] {
+                "*" => Step::All,
+                index => Step::Index(
+                    index
+                        .parse()
+                        .map_err(|_| format_err!("invalid path {:?}: bad index {:?}", s, index))?,
+                ),
+            });
+            rest = &rest[end + 1..];
+            if !rest.is_empty() && !rest.starts_with('[') {
+                bail!("invalid path {:?}", s);
+            }
+        }
+    }
+    Ok(path)
+}
+
+/// Collects all values the path points to, with their concrete locations.
+/// Missing values are collected as `None`.
+fn select<'a>(
+    value: Option<&'a Value>,
+    path: &[Step],
+    location: String,
+    out: &mut Vec<(String, Option<&'a Value>)>,
+) {
+    let (step, rest) = match (value, path.split_first()) {
+        (Some(_), Some(split)) => split,
+        (value, _) => {
+            let location = path.iter().fold(location, |l, s| l + &s.to_string());
+            out.push((location.trim_start_matches('.').to_string(), value));
+            return;
+        }
+    };
+    let value = value.unwrap();
+    match step {
+        Step::Field(name) => select(value.get(name), rest, location + &step.to_string(), out),
+        Step::Index(i) => select(value.get(i), rest, location + &step.to_string(), out),
+        Step::All => match value.as_array() {
+            Some(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    select(Some(item), rest, format!("{}[{}]", location, i), out);
+                }
+            }
+            None => select(None, path, location, out),
+        },
+    }
+}
+
+impl Rule {
+    fn check(&self, resource: &Value) -> Vec<String> {
+        let mut values = Vec::new();
+        select(Some(resource), &self.path, String::new(), &mut values);
+        values
+            .into_iter()
+            .filter_map(|(location, value)| match (&self.condition, value) {
+                (Condition::Exists, None) => Some(format!("{} is missing", location)),
+                (Condition::Absent, Some(_)) => Some(format!("{} is set", location)),
+                (Condition::Equals(expected), Some(value)) if value != expected => Some(format!(
+                    "{} is {}, but should be {}",
+                    location, value, expected
+                )),
+                (Condition::NotEquals(expected), Some(value)) if value == expected => {
+                    Some(format!("{} must not be {}", location, value))
+                }
+                (Condition::Matches(regex), Some(value))
+                    if !value.as_str().map_or(false, |s| regex.is_match(s)) =>
+                {
+                    Some(format!(
+                        "{} is {}, which does not match {:?}",
+                        location,
+                        value,
+                        regex.as_str()
+                    ))
+                }
+                (Condition::NotMatches(regex), Some(value))
+                    if value.as_str().map_or(false, |s| regex.is_match(s)) =>
+                {
+                    Some(format!(
+                        "{} is {}, which matches {:?}",
+                        location,
+                        value,
+                        regex.as_str()
+                    ))
+                }
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn deployment() -> Value {
+        json!({
+            "apiVersion": "apps/v1",
+            "kind": "Deployment",
+            "metadata": { "name": "foo" },
+            "spec": {
+                "template": {
+                    "spec": {
+                        "containers": [
+                            { "name": "foo", "image": "registry.example.com/foo:1.0",
+                              "resources": { "limits": { "cpu": 1 } } },
+                            { "name": "sidecar", "image": "sidecar:latest" }
+                        ],
+                        "volumes": [{ "name": "data", "hostPath": { "path": "/data" } }]
+                    }
+                }
+            }
+        })
+    }
+
+    fn rules_policy(name: &str, envs: &[&str], rules: &[&str]) -> Policy {
+        Policy {
+            name: name.to_string(),
+            kind: PolicyKind::Rules {
+                description: None,
+                envs: envs.iter().map(|e| e.to_string()).collect(),
+                kinds: vec!["Deployment".to_string()],
+                rules: rules.iter().map(|r| r.parse().unwrap()).collect(),
+            },
+        }
+    }
+
+    fn messages(violations: Vec<PolicyViolation>) -> Vec<String> {
+        violations.into_iter().map(|v| v.message).collect()
+    }
+
+    #[test]
+    fn test_parse_rule() {
+        let rule: Rule = "spec.template.spec.containers[*].ports[0].name exists"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            rule.path,
+            vec![
+                Step::Field("spec".to_string()),
+                Step::Field("template".to_string()),
+                Step::Field("spec".to_string()),
+                Step::Field("containers".to_string()),
+                Step::All,
+                Step::Field("ports".to_string()),
+                Step::Index(0),
+                Step::Field("name".to_string()),
+            ]
+        );
+
+        assert!("spec.replicas == 3".parse::<Rule>().is_ok());
+        assert!("spec.replicas".parse::<Rule>().is_err());
+        assert!("spec.replicas exists 3".parse::<Rule>().is_err());
+        assert!("spec.replicas > 3".parse::<Rule>().is_err());
+        assert!("spec.replicas =~ 3".parse::<Rule>().is_err());
+        assert!("spec..replicas exists".parse::<Rule>().is_err());
+        assert!("spec.containers[x] exists".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn test_rules() {
+        let policies = Policies(vec![
+            rules_policy(
+                "images",
+                &[],
+                &[
+                    r#"spec.template.spec.containers[*].image =~ "^registry\\.example\\.com/""#,
+                    r#"spec.template.spec.containers[*].image !~ ":latest$""#,
+                ],
+            ),
+            rules_policy(
+                "limits",
+                &["prod"],
+                &["spec.template.spec.containers[*].resources.limits exists"],
+            ),
+            rules_policy(
+                "volumes",
+                &["prod"],
+                &[
+                    "spec.template.spec.volumes[*].hostPath absent",
+                    "spec.template.spec.hostNetwork != true",
+                ],
+            ),
+        ]);
+
+        assert_eq!(
+            messages(policies.check("dev", &deployment())),
+            vec![
+                "spec.template.spec.containers[1].image is \"sidecar:latest\", \
+                 which does not match \"^registry\\\\.example\\\\.com/\"",
+                "spec.template.spec.containers[1].image is \"sidecar:latest\", \
+                 which matches \":latest$\"",
+            ]
+        );
+        assert_eq!(
+            messages(policies.check("prod", &deployment())),
+            vec![
+                "spec.template.spec.containers[1].image is \"sidecar:latest\", \
+                 which does not match \"^registry\\\\.example\\\\.com/\"",
+                "spec.template.spec.containers[1].image is \"sidecar:latest\", \
+                 which matches \":latest$\"",
+                "spec.template.spec.containers[1].resources.limits is missing",
+                "spec.template.spec.volumes[0].hostPath is set",
+            ]
+        );
+
+        // policies are restricted to the given kinds
+        let service = json!({ "kind": "Service", "spec": {} });
+        assert_eq!(
+            messages(policies.check("prod", &service)),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn test_jsonnet() {
+        let policies = Policies(vec![Policy {
+            name: "replicas".to_string(),
+            kind: PolicyKind::Jsonnet {
+                file_name: "replicas.jsonnet".to_string(),
+                source: r#"
+                    local resource = std.extVar("resource");
+                    if std.extVar("env") == "prod" && resource.spec.replicas < 2
+                    then ["prod needs at least 2 replicas"]
+                    else []
+                "#
+                .to_string(),
+            },
+        }]);
+        let deployment = json!({ "kind": "Deployment", "spec": { "replicas": 1 } });
+
+        assert_eq!(
+            policies.check("prod", &deployment),
+            vec![PolicyViolation {
+                policy: "replicas".to_string(),
+                message: "prod needs at least 2 replicas".to_string(),
+            }]
+        );
+        assert_eq!(policies.check("dev", &deployment), vec![]);
+
+        // errors count as violations
+        assert_eq!(policies.check("prod", &json!({})).len(), 1);
+    }
+
+    #[test]
+    fn test_invalid_policies() {
+        let policies = Policies(vec![
+            Policy {
+                name: "replicas".to_string(),
+                kind: load_policy(
+                    Path::new("replicas.yaml"),
+                    b"envs: [prod]\nrules:\n  - 'spec.replicas > 3'\n".to_vec(),
+                ),
+            },
+            Policy {
+                name: "broken".to_string(),
+                kind: load_policy(Path::new("broken.yaml"), b"rules: [".to_vec()),
+            },
+        ]);
+        let violated = |env| {
+            policies
+                .check(env, &deployment())
+                .into_iter()
+                .map(|v| v.policy)
+                .collect::<Vec<_>>()
+        };
+
+        // the envs of a policy with an invalid rule are still known
+        assert_eq!(violated("dev"), vec!["broken"]);
+        assert_eq!(violated("prod"), vec!["replicas", "broken"]);
+    }
+}
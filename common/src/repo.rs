@@ -98,6 +98,13 @@ pub trait ResourceRepo {
     ) -> Result<(), Error> {
         self.walk_commit(path, self.version(), f)
     }
+    /// Like `walk`, but only with the path and content of each file, which
+    /// is much cheaper than finding their last changes.
+    fn walk_contents<F: FnMut(PathBuf, Vec<u8>) -> Result<(), Error>>(
+        &self,
+        path: &Path,
+        f: F,
+    ) -> Result<(), Error>;
     // fn version_info(&self, id: Id) -> Result<Version, Error>;
     // fn changed_files(&self, id: Id) -> Result<Vec<PathBuf>, Error>;
 }
@@ -123,6 +130,20 @@ impl GitResourceRepo {
     pub fn from_repo(repo: Repository, head: Oid, env: Env) -> GitResourceRepo {
         GitResourceRepo { repo, head, env }
     }
+
+    fn zipper_at(&self, commit: Oid, path: &Path) -> Result<git::TreeZipper<'_>, Error> {
+        let tree = self.repo.find_commit(commit)?.tree()?;
+
+        let mut zipper = git::TreeZipper::from(&self.repo, tree);
+        for component in path {
+            zipper.descend(
+                component
+                    .to_str()
+                    .ok_or_else(|| format_err!("invalid utf8 in path"))?,
+            )?;
+        }
+        Ok(zipper)
+    }
 }
 
 impl ResourceRepo for GitResourceRepo {
@@ -162,16 +183,7 @@ impl ResourceRepo for GitResourceRepo {
         commit: Id,
         mut f: F,
     ) -> Result<(), Error> {
-        let tree = self.repo.find_commit(id_to_oid(commit))?.tree()?;
-
-        let mut zipper = git::TreeZipper::from(&self.repo, tree);
-        for component in base_path {
-            zipper.descend(
-                component
-                    .to_str()
-                    .ok_or_else(|| format_err!("invalid utf8 in path"))?,
-            )?;
-        }
+        let zipper = self.zipper_at(id_to_oid(commit), base_path)?;
 
         for (path, entry) in zipper.walk(false) {
             let entry = entry?;
@@ -209,9 +221,28 @@ impl ResourceRepo for GitResourceRepo {
 
         Ok(())
     }
+
+    fn walk_contents<F: FnMut(PathBuf, Vec<u8>) -> Result<(), Error>>(
+        &self,
+        base_path: &Path,
+        mut f: F,
+    ) -> Result<(), Error> {
+        let zipper = self.zipper_at(self.head, base_path)?;
+
+        for (path, entry) in zipper.walk(false) {
+            let obj = entry?.to_object(&self.repo)?;
+            if let Some(blob) = obj.as_blob() {
+                f(path, blob.content().to_vec())?;
+            }
+        }
+
+        Ok(())
+    }
 }
 
-fn determine_last_change<'repo>(
+/// The oldest commit back from `commit` since which the file at `path` has
+/// its content in `commit`.
+pub fn determine_last_change<'repo>(
     repo: &'repo Repository,
     commit: Oid,
     path: &Path,
@@ -282,6 +313,13 @@ impl ResourceRepo for GitResourceRepoWithTempDir {
     ) -> Result<(), Error> {
         self.inner.walk_commit(path, commit, f)
     }
+    fn walk_contents<F: FnMut(PathBuf, Vec<u8>) -> Result<(), Error>>(
+        &self,
+        path: &Path,
+        f: F,
+    ) -> Result<(), Error> {
+        self.inner.walk_contents(path, f)
+    }
 }
 
 #[cfg(test)]
@@ -397,4 +435,28 @@ mod test {
         assert_eq!(found[0].path, Path::new("1"));
         assert_eq!(found[1].path, Path::new("2"));
     }
+
+    #[test]
+    fn test_walk_contents() {
+        let fixture =
+            git_fixture::RepoFixture::from_str(include_str!("./fixtures/test_repo.yaml")).unwrap();
+        let repo = make_resource_repo(fixture, "head");
+
+        let mut found = Vec::new();
+
+        repo.walk_contents(Path::new("a"), |path, content| {
+            found.push((path, content));
+            Ok(())
+        })
+        .unwrap();
+        found.sort();
+        assert_eq!(
+            found,
+            vec![
+                (PathBuf::from("b/1"), b"yy".to_vec()),
+                (PathBuf::from("b/2"), b"blubb".to_vec()),
+                (PathBuf::from("c"), b"c".to_vec()),
+            ]
+        );
+    }
 }
@@ -0,0 +1,157 @@
+//! Graceful shutdown handling shared by the services.
+//!
+//! The first SIGTERM or SIGINT requests a shutdown: the services finish their
+//! current step, stop their API servers and return from their main loops. If
+//! that takes longer than the configured deadline, or a second signal
+//! arrives, the process exits with status 1.
+
+use std::process;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::Error;
+use log::{error, info, warn};
+use signal_hook::iterator::Signals;
+
+pub const DEFAULT_DEADLINE: Duration = Duration::from_secs(20);
+
+type Callback = Box<dyn FnOnce() + Send>;
+
+#[derive(Default)]
+pub struct Shutdown {
+    requested: Mutex<bool>,
+    condvar: Condvar,
+    callbacks: Mutex<Vec<Callback>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Shutdown {
+        Default::default()
+    }
+
+    /// Installs handlers for SIGTERM and SIGINT that request a shutdown, and
+    /// terminate the process if it doesn't exit within `deadline` afterwards.
+    pub fn install(deadline: Duration) -> Result<Arc<Shutdown>, Error> {
+        let shutdown = Arc::new(Shutdown::new());
+        let signals = Signals::new(&[signal_hook::SIGTERM, signal_hook::SIGINT])?;
+        let shutdown_1 = shutdown.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                if shutdown_1.is_requested() {
+                    warn!("Received signal {} again, exiting immediately", signal);
+                    process::exit(1);
+                }
+                info!("Received signal {}, shutting down...", signal);
+                shutdown_1.request();
+                thread::spawn(move || {
+                    thread::sleep(deadline);
+                    error!("Shutdown did not finish within {:?}, exiting", deadline);
+                    process::exit(1);
+                });
+            }
+        });
+        Ok(shutdown)
+    }
+
+    /// Requests a shutdown, waking up everything waiting in `sleep` and
+    /// running the registered callbacks.
+    pub fn request(&self) {
+        {
+            let mut requested = self.requested.lock().expect("Mutex poisoned");
+            if *requested {
+                return;
+            }
+            *requested = true;
+        }
+        self.condvar.notify_all();
+        let callbacks = std::mem::replace(
+            &mut *self.callbacks.lock().expect("Mutex poisoned"),
+            Vec::new(),
+        );
+        for callback in callbacks {
+            callback();
+        }
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.requested.lock().expect("Mutex poisoned")
+    }
+
+    /// Sleeps for the given duration, or until a shutdown is requested.
+    /// Returns whether a shutdown was requested.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let end = Instant::now() + duration;
+        let mut requested = self.requested.lock().expect("Mutex poisoned");
+        while !*requested {
+            let now = Instant::now();
+            if now >= end {
+                break;
+            }
+            requested = self
+                .condvar
+                .wait_timeout(requested, end - now)
+                .expect("Mutex poisoned")
+                .0;
+        }
+        *requested
+    }
+
+    /// Registers a function to be called when a shutdown is requested. If one
+    /// was already requested, the function is called immediately.
+    pub fn on_shutdown(&self, callback: impl FnOnce() + Send + 'static) {
+        let requested = self.requested.lock().expect("Mutex poisoned");
+        if *requested {
+            drop(requested);
+            callback();
+        } else {
+            self.callbacks
+                .lock()
+                .expect("Mutex poisoned")
+                .push(Box::new(callback));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_sleep() {
+        let shutdown = Shutdown::new();
+        assert!(!shutdown.sleep(Duration::from_millis(10)));
+
+        let shutdown = Arc::new(shutdown);
+        let shutdown_1 = shutdown.clone();
+        let start = Instant::now();
+        let handle = thread::spawn(move || shutdown_1.sleep(Duration::from_secs(60)));
+        thread::sleep(Duration::from_millis(10));
+        shutdown.request();
+        assert!(handle.join().unwrap());
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert!(shutdown.sleep(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn test_on_shutdown() {
+        let shutdown = Shutdown::new();
+        let called = Arc::new(AtomicUsize::new(0));
+        let called_1 = called.clone();
+        shutdown.on_shutdown(move || {
+            called_1.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(called.load(Ordering::SeqCst), 0);
+
+        shutdown.request();
+        shutdown.request();
+        assert_eq!(called.load(Ordering::SeqCst), 1);
+
+        let called_1 = called.clone();
+        shutdown.on_shutdown(move || {
+            called_1.fetch_add(1, Ordering::SeqCst);
+        });
+        assert_eq!(called.load(Ordering::SeqCst), 2);
+    }
+}
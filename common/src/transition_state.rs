@@ -5,7 +5,7 @@ use failure::{Error, ResultExt};
 use git2::{Repository, TreeBuilder};
 use serde_derive::{Deserialize, Serialize};
 
-use common::git::{self, TreeZipper};
+use super::git::{self, TreeZipper};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TransitionStates(pub HashMap<String, TransitionState>);
@@ -69,19 +69,47 @@ impl TransitionStates {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TransitionState {
+    /// The transition doesn't run before this time.
     pub scheduled: Option<DateTime<Utc>>,
+    /// When the transition was triggered manually. It then runs at the next
+    /// opportunity, regardless of `scheduled`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub triggered: Option<DateTime<Utc>>,
+    /// The scheduled time whose run is skipped. As long as it is the same as
+    /// `scheduled`, the transition waits for the next scheduled time after
+    /// it instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub skipped: Option<DateTime<Utc>>,
+    /// The transition doesn't run at all while it is paused.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub paused: Option<Pause>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Pause {
+    pub reason: String,
+    pub author: String,
+    pub since: DateTime<Utc>,
 }
 
 impl TransitionState {
     fn is_empty(&self) -> bool {
         self.scheduled.is_none()
+            && self.triggered.is_none()
+            && self.skipped.is_none()
+            && self.paused.is_none()
     }
 }
 
 impl Default for TransitionState {
     fn default() -> TransitionState {
-        TransitionState { scheduled: None }
+        TransitionState {
+            scheduled: None,
+            triggered: None,
+            skipped: None,
+            paused: None,
+        }
     }
 }
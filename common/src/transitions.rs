@@ -1,16 +1,28 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 
 use chrono::{DateTime, Utc};
+use failure::{Error, ResultExt};
+use git2::Repository;
 use indexmap::IndexMap;
+use serde::de::IgnoredAny;
 use serde_derive::{Deserialize, Serialize};
 
+use super::git::TreeZipper;
 use super::repo::Id;
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(tag = "result")]
 pub enum TransitionResult {
     /// The transition was performed successfully.
-    Success { committed_version: Id },
+    Success {
+        committed_version: Id,
+        /// The resources whose versions were mirrored.
+        #[serde(default)]
+        transitioned: Vec<String>,
+        /// The resources that preconditions held back, with the reason.
+        #[serde(default)]
+        held: BTreeMap<String, String>,
+    },
     /// The transition was not applicable for some reason, or there was no change.
     Skipped(SkipReason),
     /// The transition might be applicable soon, and the source env should not
@@ -18,15 +30,42 @@ pub enum TransitionResult {
     Blocked { message: String },
     /// A precondition check was negative.
     CheckFailed { message: String },
+    /// The transition waits for someone to approve the change set.
+    AwaitingApproval {
+        change_set: Id,
+        /// The resources the transition would change.
+        resources: Vec<String>,
+        /// The approver group the approval has to be for.
+        #[serde(default)]
+        group: Option<String>,
+        /// When the transition started waiting for this change set.
+        #[serde(default)]
+        since: Option<DateTime<Utc>>,
+    },
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(tag = "reason")]
 pub enum SkipReason {
-    Scheduled { time: DateTime<Utc> },
+    Scheduled {
+        time: DateTime<Utc>,
+    },
     TargetLocked,
     SourceMissing,
     NoChange,
+    /// A `TimeWindow` precondition doesn't allow the transition right now.
+    OutsideTimeWindow {
+        next_start: Option<DateTime<Utc>>,
+    },
+    /// The target is frozen by the freeze calendar.
+    Frozen {
+        message: String,
+        until: DateTime<Utc>,
+    },
+    /// The transition is paused.
+    Paused {
+        message: String,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
@@ -60,24 +99,174 @@ pub struct TransitionRunInfo {
 
 pub type AllTransitionStatus = IndexMap<String, TransitionStatusInfo>;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// The status the transitioner reports in its status events. `GET /status`
+/// only returns the transitions.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Default)]
+pub struct TransitionerStatus {
+    pub transitions: AllTransitionStatus,
+    /// Set if the current transition config is invalid, in which case the
+    /// transitioner keeps using the previous one.
+    #[serde(default)]
+    pub config_error: Option<String>,
+}
+
+/// Why something is locked. In the locks file, a reason can also be just a
+/// string.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(from = "LockReasonEntry")]
+pub struct LockReason {
+    pub reason: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    /// When the lock was taken.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<DateTime<Utc>>,
+    /// After this time, the reason doesn't lock anything anymore.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub expires: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "LockKind::is_permanent")]
+    pub kind: LockKind,
+}
+
+/// What a lock reason lets through, and how transitions change it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type")]
+pub enum LockKind {
+    /// Holds everything until the reason is removed or expires.
+    Permanent,
+    /// Holds everything, but the next transition that would have changed the
+    /// locked resource (or env) removes the reason.
+    ReleaseAfterNextTransition,
+    /// Lets transitions change the resource to this version, and nothing else.
+    /// Once the resource has the version, the reason becomes permanent. On the
+    /// env lock, this is the same as a permanent lock.
+    PinToVersion { version: Id },
+}
+
+impl LockKind {
+    pub fn is_permanent(&self) -> bool {
+        *self == LockKind::Permanent
+    }
+}
+
+impl Default for LockKind {
+    fn default() -> LockKind {
+        LockKind::Permanent
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LockReasonEntry {
+    Plain(String),
+    Full {
+        reason: String,
+        #[serde(default)]
+        author: Option<String>,
+        #[serde(default)]
+        since: Option<DateTime<Utc>>,
+        #[serde(default)]
+        expires: Option<DateTime<Utc>>,
+        #[serde(default)]
+        kind: LockKind,
+    },
+}
+
+impl From<LockReasonEntry> for LockReason {
+    fn from(entry: LockReasonEntry) -> LockReason {
+        match entry {
+            LockReasonEntry::Plain(reason) => LockReason::new(&reason),
+            LockReasonEntry::Full {
+                reason,
+                author,
+                since,
+                expires,
+                kind,
+            } => LockReason {
+                reason,
+                author,
+                since,
+                expires,
+                kind,
+            },
+        }
+    }
+}
+
+impl LockReason {
+    pub fn new(reason: &str) -> LockReason {
+        LockReason {
+            reason: reason.to_owned(),
+            author: None,
+            since: None,
+            expires: None,
+            kind: LockKind::Permanent,
+        }
+    }
+
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.expires.map_or(true, |expires| now < expires)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Lock {
-    pub reasons: Vec<String>,
+    pub reasons: Vec<LockReason>,
 }
 
 impl Lock {
-    pub fn is_locked(&self) -> bool {
-        !self.reasons.is_empty()
+    pub fn is_locked(&self, now: DateTime<Utc>) -> bool {
+        self.reasons.iter().any(|r| r.is_active(now))
     }
 
     pub fn add_reason(&mut self, reason: &str) {
-        if !self.reasons.iter().any(|r| r == reason) {
-            self.reasons.push(reason.to_owned());
+        if !self.reasons.iter().any(|r| r.reason == reason) {
+            self.reasons.push(LockReason::new(reason));
         }
     }
 
+    /// Adds a reason, replacing an existing one with the same text.
+    pub fn add(&mut self, reason: LockReason) {
+        self.remove_reason(&reason.reason);
+        self.reasons.push(reason);
+    }
+
     pub fn remove_reason(&mut self, reason: &str) {
-        self.reasons.retain(|r| r != reason);
+        self.reasons.retain(|r| r.reason != reason);
+    }
+
+    /// Whether the lock keeps out all transitions, not just some changes.
+    pub fn blocks_transitions(&self, now: DateTime<Utc>) -> bool {
+        self.reasons
+            .iter()
+            .any(|r| r.is_active(now) && r.kind != LockKind::ReleaseAfterNextTransition)
+    }
+
+    /// Whether a transition may change the locked resource to `version`.
+    pub fn allows(&self, version: Id, now: DateTime<Utc>) -> bool {
+        self.reasons
+            .iter()
+            .filter(|r| r.is_active(now))
+            .all(|r| r.kind == LockKind::PinToVersion { version })
+    }
+
+    /// Updates the reasons after a transition that would have changed the
+    /// locked resource or env. `version` is the version the resource has
+    /// after the transition. Returns whether anything changed.
+    pub fn after_transition(&mut self, version: Option<Id>, now: DateTime<Utc>) -> bool {
+        let count = self.reasons.len();
+        self.reasons
+            .retain(|r| !(r.is_active(now) && r.kind == LockKind::ReleaseAfterNextTransition));
+        let mut changed = self.reasons.len() != count;
+        for reason in &mut self.reasons {
+            if let LockKind::PinToVersion { version: pinned } = reason.kind {
+                if Some(pinned) == version {
+                    reason.kind = LockKind::Permanent;
+                    changed = true;
+                }
+            }
+        }
+        changed
     }
 }
 
@@ -89,7 +278,7 @@ impl Default for Lock {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Locks {
     #[serde(default)]
     pub env_lock: Lock,
@@ -97,11 +286,52 @@ pub struct Locks {
     pub resource_locks: HashMap<String, Lock>,
 }
 
+pub const LOCKS_FILE: &str = "locks.yaml";
+
 impl Locks {
-    pub fn resource_is_locked(&self, resource: &str) -> bool {
+    pub fn resource_is_locked(&self, resource: &str, now: DateTime<Utc>) -> bool {
         self.resource_locks
             .get(resource)
-            .map_or(false, |l| l.is_locked())
+            .map_or(false, |l| l.is_locked(now))
+    }
+
+    /// Removes resource locks without reasons.
+    pub fn remove_empty(&mut self) {
+        self.resource_locks.retain(|_, l| !l.reasons.is_empty());
+    }
+
+    /// Loads the locks file from the env directory the zipper currently
+    /// points to.
+    pub fn load(zipper: &TreeZipper<'_>) -> Result<Locks, Error> {
+        let blob = if let Some(blob) = zipper.get_blob(LOCKS_FILE)? {
+            blob
+        } else {
+            return Ok(Locks::default());
+        };
+
+        let locks = serde_yaml::from_slice(blob.content()).context("deserializing locks failed")?;
+
+        Ok(locks)
+    }
+
+    /// Writes the locks file into the env directory the zipper currently
+    /// points to.
+    pub fn save<'repo>(
+        &self,
+        repo: &'repo Repository,
+        zipper: &mut TreeZipper<'repo>,
+    ) -> Result<(), Error> {
+        let mut serialized = serde_yaml::to_vec(self).context("serializing locks file failed")?;
+        serialized.extend("\n".as_bytes());
+
+        let blob = repo.blob(&serialized).context("writing blob failed")?;
+
+        zipper.rebuild(|builder| {
+            builder
+                .insert(LOCKS_FILE, blob, 0o100644)
+                .context("updating locks file failed")?;
+            Ok(())
+        })
     }
 }
 
@@ -113,3 +343,292 @@ impl Default for Locks {
         }
     }
 }
+
+/// An approval of a transition's change set, for the `ManualApproval`
+/// precondition.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Approval {
+    /// Identifies the approved change set.
+    pub change_set: Id,
+    pub author: String,
+    #[serde(default)]
+    pub comment: String,
+    /// The approver group the author approved for.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    pub time: DateTime<Utc>,
+}
+
+/// The latest approval of each transition, by transition name.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Approvals(pub BTreeMap<String, Approval>);
+
+pub const APPROVALS_FILE: &str = "approvals.yaml";
+
+impl Approvals {
+    /// Loads the approvals file from the root directory the zipper points
+    /// to.
+    pub fn load(zipper: &TreeZipper<'_>) -> Result<Approvals, Error> {
+        let blob = if let Some(blob) = zipper.get_blob(APPROVALS_FILE)? {
+            blob
+        } else {
+            return Ok(Approvals::default());
+        };
+
+        let approvals =
+            serde_yaml::from_slice(blob.content()).context("deserializing approvals failed")?;
+
+        Ok(approvals)
+    }
+
+    /// Writes the approvals file into the root directory the zipper points
+    /// to, or removes it if there are no approvals.
+    pub fn save<'repo>(
+        &self,
+        repo: &'repo Repository,
+        zipper: &mut TreeZipper<'repo>,
+    ) -> Result<(), Error> {
+        if self.0.is_empty() {
+            return zipper.rebuild(|builder| {
+                if builder.get(APPROVALS_FILE)?.is_some() {
+                    builder.remove(APPROVALS_FILE)?;
+                }
+                Ok(())
+            });
+        }
+
+        let mut serialized =
+            serde_yaml::to_vec(self).context("serializing approvals file failed")?;
+        serialized.extend("\n".as_bytes());
+
+        let blob = repo.blob(&serialized).context("writing blob failed")?;
+
+        zipper.rebuild(|builder| {
+            builder
+                .insert(APPROVALS_FILE, blob, 0o100644)
+                .context("updating approvals file failed")?;
+            Ok(())
+        })
+    }
+}
+
+/// A period in which transitions into some envs are not allowed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Freeze {
+    pub reason: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// The envs that transitions may not change. All envs if empty.
+    #[serde(default)]
+    pub targets: Vec<String>,
+}
+
+/// The freeze calendar of the resource repo.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Freezes {
+    #[serde(default)]
+    pub freezes: Vec<Freeze>,
+}
+
+pub const FREEZES_FILE: &str = "freezes.yaml";
+
+impl Freezes {
+    /// Loads the freeze calendar from the root directory the zipper points
+    /// to.
+    pub fn load(zipper: &TreeZipper<'_>) -> Result<Freezes, Error> {
+        let blob = if let Some(blob) = zipper.get_blob(FREEZES_FILE)? {
+            blob
+        } else {
+            return Ok(Freezes::default());
+        };
+
+        let freezes =
+            serde_yaml::from_slice(blob.content()).context("deserializing freezes failed")?;
+
+        Ok(freezes)
+    }
+
+    /// The freeze that applies to the target env at the given time, if any.
+    /// Of overlapping freezes, the one that lasts longest is returned.
+    pub fn active(&self, target: &str, now: DateTime<Utc>) -> Option<&Freeze> {
+        self.freezes
+            .iter()
+            .filter(|f| f.start <= now && now < f.end)
+            .filter(|f| f.targets.is_empty() || f.targets.iter().any(|t| t == target))
+            .max_by_key(|f| f.end)
+    }
+}
+
+pub const TRANSITIONS_FILE: &str = "transitions.yaml";
+
+/// The names of the transitions configured in the transitions file, for
+/// services that don't need the rest of the transitioner's config.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TransitionNames(pub Vec<String>);
+
+impl TransitionNames {
+    /// Loads the transition names from the root directory the zipper points
+    /// to.
+    pub fn load(zipper: &TreeZipper<'_>) -> Result<TransitionNames, Error> {
+        #[derive(Deserialize)]
+        struct Config {
+            #[serde(default)]
+            transitions: IndexMap<String, IgnoredAny>,
+        }
+
+        let blob = if let Some(blob) = zipper.get_blob(TRANSITIONS_FILE)? {
+            blob
+        } else {
+            return Ok(TransitionNames::default());
+        };
+
+        let config: Config = serde_yaml::from_slice(blob.content())
+            .context("deserializing transitions config failed")?;
+
+        Ok(TransitionNames(
+            config.transitions.keys().cloned().collect(),
+        ))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|n| n == name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn lock_reasons() {
+        let locks: Locks = serde_yaml::from_str(
+            r#"
+env_lock:
+  reasons:
+    - old style reason
+    - reason: maintenance
+      author: alice
+      expires: 2019-11-01T12:00:00Z
+"#,
+        )
+        .unwrap();
+        let reasons = &locks.env_lock.reasons;
+        assert_eq!(reasons[0], LockReason::new("old style reason"));
+        assert_eq!(reasons[1].author.as_ref().unwrap(), "alice");
+
+        let mut lock = Lock {
+            reasons: vec![reasons[1].clone()],
+        };
+        assert!(lock.is_locked(Utc.ymd(2019, 11, 1).and_hms(11, 59, 0)));
+        assert!(!lock.is_locked(Utc.ymd(2019, 11, 1).and_hms(12, 0, 0)));
+
+        lock.add(LockReason::new("maintenance"));
+        assert_eq!(lock.reasons, vec![LockReason::new("maintenance")]);
+        assert!(lock.is_locked(Utc.ymd(2019, 11, 1).and_hms(12, 0, 0)));
+    }
+
+    #[test]
+    fn lock_kinds() {
+        let v1: Id = "22817d2a9c7fc1f62d5670ca1e44948446543973".parse().unwrap();
+        let v2: Id = "8f3a5b4c2d1e0f9a8b7c6d5e4f3a2b1c0d9e8f7a".parse().unwrap();
+        let now = Utc::now();
+        let mut lock: Lock = serde_yaml::from_str(
+            r#"
+reasons:
+  - reason: let v1 through
+    kind:
+      type: PinToVersion
+      version: 22817d2a9c7fc1f62d5670ca1e44948446543973
+  - reason: rolled back
+    kind:
+      type: ReleaseAfterNextTransition
+"#,
+        )
+        .unwrap();
+        let release_only = Lock {
+            reasons: vec![lock.reasons[1].clone()],
+        };
+        assert!(!release_only.blocks_transitions(now));
+        assert!(lock.blocks_transitions(now));
+        assert!(!lock.allows(v1, now));
+
+        assert!(lock.after_transition(Some(v2), now));
+        assert_eq!(lock.reasons.len(), 1);
+        assert!(lock.allows(v1, now));
+        assert!(!lock.allows(v2, now));
+        assert!(lock.blocks_transitions(now));
+
+        assert!(lock.after_transition(Some(v1), now));
+        assert_eq!(lock.reasons[0].kind, LockKind::Permanent);
+        assert!(!lock.allows(v1, now));
+        assert!(!lock.after_transition(Some(v1), now));
+    }
+
+    #[test]
+    fn active_freezes() {
+        let freezes: Freezes = serde_yaml::from_str(
+            r#"
+freezes:
+  - reason: Christmas
+    start: 2019-12-20T00:00:00+01:00
+    end: 2020-01-03T00:00:00+01:00
+    targets: [prod]
+  - reason: Data center move
+    start: 2019-12-27T00:00:00Z
+    end: 2019-12-28T00:00:00Z
+"#,
+        )
+        .unwrap();
+        let christmas = Utc.ymd(2019, 12, 24).and_hms(12, 0, 0);
+        assert_eq!(
+            freezes.active("prod", christmas).unwrap().reason,
+            "Christmas"
+        );
+        assert_eq!(freezes.active("dev", christmas), None);
+        let move_day = Utc.ymd(2019, 12, 27).and_hms(12, 0, 0);
+        assert_eq!(
+            freezes.active("dev", move_day).unwrap().reason,
+            "Data center move"
+        );
+        assert_eq!(
+            freezes.active("prod", move_day).unwrap().reason,
+            "Christmas"
+        );
+        assert_eq!(
+            freezes.active("prod", Utc.ymd(2020, 1, 2).and_hms(23, 0, 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn transition_names() {
+        let fixture = git_fixture::RepoFixture::from_str(
+            r#"
+commits:
+  - files:
+      transitions.yaml: |
+        transitions:
+          prod:
+            source: available
+            target: prod
+          staging:
+            source: available
+            target: staging
+    name: head
+"#,
+        )
+        .unwrap();
+        let head = fixture
+            .repo
+            .find_commit(fixture.get_commit("head").unwrap());
+        let zipper = TreeZipper::from(&fixture.repo, head.unwrap().tree().unwrap());
+        let names = TransitionNames::load(&zipper).unwrap();
+        assert_eq!(names.0, vec!["prod".to_string(), "staging".to_string()]);
+        assert!(names.contains("staging"));
+        assert!(!names.contains("dev"));
+
+        let empty = TransitionNames::load(&TreeZipper::new(&fixture.repo)).unwrap();
+        assert_eq!(empty, TransitionNames::default());
+    }
+}
@@ -0,0 +1,423 @@
+//! Primitives for event-driven service loops.
+//!
+//! A `Watch` holds a value together with a counter that increases with every
+//! change, so that other threads can wait for changes, and a `Broadcast`
+//! sends them to the event streams of the API. A `Trigger` wakes up a
+//! service loop early, e.g. because a webhook reported a push to the
+//! versions repo.
+
+use std::fmt;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use failure::{format_err, Error};
+
+pub const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A value together with the counter of the change that produced it.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Versioned<T> {
+    pub counter: u64,
+    pub value: T,
+}
+
+/// Identifies a value of a watch in event streams. Counters start over when
+/// the service restarts, so the id includes the epoch of the watch.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventId {
+    pub epoch: u64,
+    pub counter: u64,
+}
+
+impl fmt::Display for EventId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.epoch, self.counter)
+    }
+}
+
+impl FromStr for EventId {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<EventId, Error> {
+        let mut parts = s.splitn(2, '-');
+        match (parts.next(), parts.next()) {
+            (Some(epoch), Some(counter)) => Ok(EventId {
+                epoch: epoch.parse()?,
+                counter: counter.parse()?,
+            }),
+            _ => Err(format_err!("invalid event id {}", s)),
+        }
+    }
+}
+
+pub struct Watch<T> {
+    /// Identifies this watch, so that counters of an earlier instance of the
+    /// service aren't mistaken for its own. Only ever compared for equality.
+    epoch: u64,
+    state: Mutex<Versioned<T>>,
+    closed: AtomicBool,
+    condvar: Condvar,
+}
+
+impl<T: Clone + PartialEq + Default> Default for Watch<T> {
+    fn default() -> Watch<T> {
+        Watch::new(T::default())
+    }
+}
+
+impl<T: Clone + PartialEq> Watch<T> {
+    pub fn new(value: T) -> Watch<T> {
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Watch {
+            epoch,
+            state: Mutex::new(Versioned { counter: 0, value }),
+            closed: AtomicBool::new(false),
+            condvar: Condvar::new(),
+        }
+    }
+
+    pub fn event_id(&self, counter: u64) -> EventId {
+        EventId {
+            epoch: self.epoch,
+            counter,
+        }
+    }
+
+    pub fn get(&self) -> T {
+        self.get_versioned().value
+    }
+
+    pub fn get_versioned(&self) -> Versioned<T> {
+        self.state.lock().expect("Mutex poisoned").clone()
+    }
+
+    pub fn set(&self, value: T) {
+        self.update(|v| *v = value);
+    }
+
+    /// Updates the value in place. Waiters are only woken up if the value
+    /// actually changed.
+    pub fn update(&self, update_fn: impl FnOnce(&mut T)) {
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        let old_value = state.value.clone();
+        update_fn(&mut state.value);
+        if state.value != old_value {
+            state.counter += 1;
+            self.condvar.notify_all();
+        }
+    }
+
+    /// Wakes up all waiters and makes all future waits return immediately,
+    /// because the service is shutting down.
+    pub fn close(&self) {
+        let _state = self.state.lock().expect("Mutex poisoned");
+        self.closed.store(true, Ordering::SeqCst);
+        self.condvar.notify_all();
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Waits until the counter differs from `since`, the timeout elapses or
+    /// the watch is closed, and returns the current value. Without `since`,
+    /// returns immediately.
+    pub fn wait_for_change(&self, since: Option<u64>, timeout: Duration) -> Versioned<T> {
+        let end = Instant::now() + timeout;
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        while since == Some(state.counter) && !self.is_closed() {
+            let now = Instant::now();
+            if now >= end {
+                break;
+            }
+            state = self
+                .condvar
+                .wait_timeout(state, end - now)
+                .expect("Mutex poisoned")
+                .0;
+        }
+        state.clone()
+    }
+}
+
+/// What a subscriber of a `Broadcast` is sent.
+#[derive(Debug, PartialEq)]
+pub enum Update<'a, T> {
+    /// The value changed since the subscriber was last sent something.
+    Changed(EventId, &'a T),
+    /// Nothing changed for a while.
+    KeepAlive(EventId),
+}
+
+type SendFn<T> = Box<dyn FnMut(Update<'_, T>) -> bool + Send>;
+
+struct Subscriber<T> {
+    counter: u64,
+    send: SendFn<T>,
+}
+
+/// Sends the changes of a watch to any number of subscribers, e.g. event
+/// streams, from a single thread.
+pub struct Broadcast<T> {
+    subscribers: Mutex<Vec<Subscriber<T>>>,
+}
+
+impl<T> Default for Broadcast<T> {
+    fn default() -> Broadcast<T> {
+        Broadcast {
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl<T: Clone + PartialEq> Broadcast<T> {
+    pub fn new() -> Broadcast<T> {
+        Default::default()
+    }
+
+    /// Adds a subscriber and sends it the current value, unless it already
+    /// has it according to `last_event_id`. Subscribers are dropped once
+    /// `send` returns false, e.g. because the client disconnected, or the
+    /// watch is closed.
+    pub fn subscribe(
+        &self,
+        watch: &Watch<T>,
+        last_event_id: Option<EventId>,
+        mut send: impl FnMut(Update<'_, T>) -> bool + Send + 'static,
+    ) {
+        let mut subscribers = self.subscribers.lock().expect("Mutex poisoned");
+        if watch.is_closed() {
+            return;
+        }
+        let current = watch.get_versioned();
+        let id = watch.event_id(current.counter);
+        if last_event_id != Some(id) && !send(Update::Changed(id, &current.value)) {
+            return;
+        }
+        subscribers.push(Subscriber {
+            counter: current.counter,
+            send: Box::new(send),
+        });
+    }
+
+    /// Sends every change of the watch to the subscribers until the watch is
+    /// closed. If nothing changes for `keep_alive`, they get a keep-alive
+    /// instead.
+    pub fn run(&self, watch: &Watch<T>, keep_alive: Duration) {
+        let mut since = Some(watch.get_versioned().counter);
+        while !watch.is_closed() {
+            let current = watch.wait_for_change(since, keep_alive);
+            since = Some(current.counter);
+            let mut subscribers = self.subscribers.lock().expect("Mutex poisoned");
+            *subscribers = subscribers
+                .drain(..)
+                .filter_map(|mut subscriber| {
+                    // subscribers added in the meantime might be ahead
+                    let update = if current.counter > subscriber.counter {
+                        subscriber.counter = current.counter;
+                        Update::Changed(watch.event_id(current.counter), &current.value)
+                    } else {
+                        Update::KeepAlive(watch.event_id(subscriber.counter))
+                    };
+                    if (subscriber.send)(update) {
+                        Some(subscriber)
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+        }
+        self.subscribers.lock().expect("Mutex poisoned").clear();
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct TriggerState {
+    triggered: bool,
+    fetch: bool,
+}
+
+/// Wakes up a service loop that is waiting for something to do.
+#[derive(Default)]
+pub struct Trigger {
+    state: Mutex<TriggerState>,
+    condvar: Condvar,
+}
+
+impl Trigger {
+    pub fn new() -> Trigger {
+        Default::default()
+    }
+
+    /// Wakes up the loop, e.g. because the state of deployed resources
+    /// changed.
+    pub fn notify(&self) {
+        self.state.lock().expect("Mutex poisoned").triggered = true;
+        self.condvar.notify_all();
+    }
+
+    /// Wakes up the loop and asks it to fetch the versions repo.
+    pub fn request_fetch(&self) {
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        state.triggered = true;
+        state.fetch = true;
+        self.condvar.notify_all();
+    }
+
+    /// Waits until the trigger is notified or the timeout elapses. Returns
+    /// whether a fetch was requested in the meantime.
+    pub fn wait(&self, timeout: Duration) -> bool {
+        let end = Instant::now() + timeout;
+        let mut state = self.state.lock().expect("Mutex poisoned");
+        while !state.triggered {
+            let now = Instant::now();
+            if now >= end {
+                break;
+            }
+            state = self
+                .condvar
+                .wait_timeout(state, end - now)
+                .expect("Mutex poisoned")
+                .0;
+        }
+        let fetch = state.fetch;
+        *state = TriggerState::default();
+        fetch
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::{mpsc, Arc};
+    use std::thread;
+
+    #[test]
+    fn test_watch_wait_for_change() {
+        let watch = Arc::new(Watch::new(1));
+        let start = watch.get_versioned().counter;
+        assert_eq!(
+            watch.wait_for_change(None, Duration::from_secs(60)),
+            Versioned {
+                counter: start,
+                value: 1
+            }
+        );
+        assert_eq!(
+            watch.wait_for_change(Some(start), Duration::from_millis(10)),
+            Versioned {
+                counter: start,
+                value: 1
+            }
+        );
+
+        watch.set(1);
+        assert_eq!(watch.get_versioned().counter, start);
+
+        let watch_1 = watch.clone();
+        let handle =
+            thread::spawn(move || watch_1.wait_for_change(Some(start), Duration::from_secs(60)));
+        thread::sleep(Duration::from_millis(10));
+        watch.update(|v| *v += 1);
+        assert_eq!(
+            handle.join().unwrap(),
+            Versioned {
+                counter: start + 1,
+                value: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_watch_close() {
+        let watch = Arc::new(Watch::new(1));
+        let start = watch.get_versioned().counter;
+        let watch_1 = watch.clone();
+        let handle =
+            thread::spawn(move || watch_1.wait_for_change(Some(start), Duration::from_secs(60)));
+        thread::sleep(Duration::from_millis(10));
+        watch.close();
+        assert_eq!(handle.join().unwrap().counter, start);
+        assert!(watch.is_closed());
+    }
+
+    #[test]
+    fn test_event_id() {
+        let id = EventId {
+            epoch: 1_571_000_000,
+            counter: 12,
+        };
+        assert_eq!(id.to_string(), "1571000000-12");
+        assert_eq!("1571000000-12".parse::<EventId>().unwrap(), id);
+        assert!("1571000000".parse::<EventId>().is_err());
+        assert!("a-12".parse::<EventId>().is_err());
+    }
+
+    #[test]
+    fn test_broadcast() {
+        let watch = Arc::new(Watch::new(1));
+        let broadcast = Arc::new(Broadcast::new());
+        let (tx, rx) = mpsc::channel();
+        let tx_1 = tx.clone();
+        broadcast.subscribe(&watch, None, move |update| {
+            tx_1.send(format!("a {:?}", update)).is_ok()
+        });
+        // already has the current value
+        broadcast.subscribe(&watch, Some(watch.event_id(0)), move |update| {
+            tx.send(format!("b {:?}", update)).is_ok()
+        });
+        let id = |counter| watch.event_id(counter);
+        assert_eq!(
+            rx.recv().unwrap(),
+            format!("a {:?}", Update::Changed(id(0), &1))
+        );
+
+        let (watch_1, broadcast_1) = (watch.clone(), broadcast.clone());
+        let handle = thread::spawn(move || broadcast_1.run(&watch_1, Duration::from_millis(100)));
+        assert_eq!(
+            rx.recv().unwrap(),
+            format!("a {:?}", Update::<i32>::KeepAlive(id(0)))
+        );
+        assert_eq!(
+            rx.recv().unwrap(),
+            format!("b {:?}", Update::<i32>::KeepAlive(id(0)))
+        );
+
+        watch.set(2);
+        assert_eq!(
+            rx.recv().unwrap(),
+            format!("a {:?}", Update::Changed(id(1), &2))
+        );
+        assert_eq!(
+            rx.recv().unwrap(),
+            format!("b {:?}", Update::Changed(id(1), &2))
+        );
+
+        watch.close();
+        handle.join().unwrap();
+        // at most keep-alives until all subscribers are dropped, which
+        // disconnects the channel
+        assert!(rx.iter().all(|update| update.contains("KeepAlive")));
+    }
+
+    #[test]
+    fn test_trigger() {
+        let trigger = Arc::new(Trigger::new());
+        assert!(!trigger.wait(Duration::from_millis(10)));
+
+        trigger.notify();
+        assert!(!trigger.wait(Duration::from_secs(60)));
+
+        let trigger_1 = trigger.clone();
+        let handle = thread::spawn(move || trigger_1.wait(Duration::from_secs(60)));
+        thread::sleep(Duration::from_millis(10));
+        trigger.request_fetch();
+        assert!(handle.join().unwrap());
+        assert!(!trigger.wait(Duration::from_millis(10)));
+    }
+}
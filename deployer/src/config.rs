@@ -5,15 +5,16 @@ use serde_derive::{Deserialize, Serialize};
 use serde_yaml;
 
 use crate::{
-    deployment::{kubernetes, mock, Deployer},
+    deployment::{kubernetes, mock, validation, Deployer},
     Env,
 };
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type")]
 pub enum DeployerConfig {
     Kubernetes(kubernetes::Config),
     Mock(mock::Config),
+    Validate(validation::Config),
 }
 
 impl DeployerConfig {
@@ -23,13 +24,24 @@ impl DeployerConfig {
                 c.create(env).map(|d| Box::new(d) as Box<dyn Deployer>)
             }
             DeployerConfig::Mock(c) => c.create().map(|d| Box::new(d) as Box<dyn Deployer>),
+            DeployerConfig::Validate(c) => c.create().map(|d| Box::new(d) as Box<dyn Deployer>),
         }
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EnvConfig {
+    #[serde(flatten)]
+    pub deployer: DeployerConfig,
+    /// Whether to automatically revert resources whose rollout failed to the
+    /// last version that was deployed cleanly, and lock the env afterwards.
+    #[serde(default)]
+    pub auto_rollback: bool,
+}
+
 #[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
-    pub deployers: BTreeMap<String, DeployerConfig>,
+    pub deployers: BTreeMap<String, EnvConfig>,
 }
 
 impl Config {
@@ -1,27 +1,34 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
-use failure::{bail, format_err, Error, ResultExt};
+use failure::{bail, format_err, Error};
 use k8s_openapi::{api, apimachinery::pkg::apis::meta::v1::ObjectMeta};
-use kubernetes::config::Configuration as KubeConfig;
 use log::{debug, info, warn};
 use serde_derive::{Deserialize, Serialize};
 use serde_json::json;
 
 use common::deployment::{ResourceState, RolloutStatusReason};
+use common::kubectl::{kubectl, KubeTarget};
 use common::repo::Id;
+use common::watch::Trigger;
 
+use super::object_cache::ObjectCache;
+use super::validation::{self, Validator};
 use super::{Deployer, Resource};
 use crate::Env;
 
 const VERSION_ANNOTATION: &str = "new-dm/version";
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {
     namespace: String,
     context: Option<String>,
     cluster: Option<String>,
     user: Option<String>,
     glob: Option<String>,
+    /// If set, resources are validated before being applied, and invalid
+    /// ones are not deployed.
+    validation: Option<validation::Config>,
 }
 
 impl Config {
@@ -31,11 +38,9 @@ impl Config {
 }
 
 pub struct KubernetesDeployer {
-    namespace: String,
-    context: Option<String>,
-    cluster: Option<String>,
-    user: Option<String>,
-    client: KubeConfig,
+    kubectl_target: KubeTarget,
+    cache: ObjectCache,
+    validator: Option<Validator>,
 }
 
 impl KubernetesDeployer {
@@ -48,65 +53,28 @@ impl KubernetesDeployer {
         let configuration = kubernetes::config::incluster_config()
             .or_else(|_| kubernetes::config::load_kube_config_with(options))?;
         Ok(KubernetesDeployer {
-            client: configuration,
-            context: config.context.clone(),
-            cluster: config.cluster.clone(),
-            user: config.user.clone(),
-            namespace: config.namespace.clone(),
+            cache: ObjectCache::new(
+                configuration.base_path,
+                configuration.client,
+                config.namespace.clone(),
+            ),
+            kubectl_target: KubeTarget {
+                namespace: Some(config.namespace.clone()),
+                context: config.context.clone(),
+                cluster: config.cluster.clone(),
+                user: config.user.clone(),
+            },
+            validator: config
+                .validation
+                .as_ref()
+                .map(|v| Validator::new(&v.kubernetes_version))
+                .transpose()?,
         })
     }
     fn kubectl_apply(&self, data: &str) -> Result<(), Error> {
         // TODO: use kube API instead
-        use std::io::Write;
-        use std::process::{Command, Stdio};
-
-        let mut builder = Command::new("kubectl");
-        builder.args(&["apply", "--namespace", &self.namespace]);
-        if let Some(context) = &self.context {
-            builder.args(&["--context", &context]);
-        }
-        if let Some(cluster) = &self.cluster {
-            builder.args(&["--cluster", &cluster]);
-        }
-        if let Some(user) = &self.user {
-            builder.args(&["--user", &user]);
-        }
-        builder.args(&["-f", "-"]);
-        let mut process = builder
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .stderr(Stdio::piped())
-            .spawn()
-            .context("running kubectl failed")?;
-
-        process
-            .stdin
-            .as_mut()
-            .unwrap()
-            .write_all(data.as_bytes())
-            .context("piping data to kubectl failed")?;
-
-        let output = process
-            .wait_with_output()
-            .context("kubectl wasn't running")?;
-
-        if !output.status.success() {
-            bail!(
-                "kubectl failed; stderr: {}",
-                String::from_utf8_lossy(&output.stderr)
-            );
-        }
-
-        debug!(
-            "kubectl stdout: {}",
-            String::from_utf8_lossy(&output.stdout)
-        );
-
-        debug!(
-            "kubectl stderr: {}",
-            String::from_utf8_lossy(&output.stderr)
-        );
-
+        let output = kubectl(&self.kubectl_target, &["apply", "-f", "-"], Some(data))?;
+        debug!("kubectl stdout: {}", output);
         Ok(())
     }
 }
@@ -122,26 +90,20 @@ impl Deployer for KubernetesDeployer {
             let kind = determine_kind(&d.merged_content)?;
 
             let state = match kind {
-                Kind::Deployment => get_deployment_state(&self.client, &self.namespace, d)?,
+                Kind::Deployment => get_deployment_state(&mut self.cache, d)?,
                 // TODO these should work
                 Kind::DaemonSet => bail!("Unsupported resource type: {:?}", kind),
                 Kind::Pod => bail!("Unsupported resource type: {:?}", kind),
 
-                Kind::Service => get_simple_resource_state::<api::core::v1::Service>(
-                    &self.client,
-                    &self.namespace,
-                    d,
-                )?,
-                Kind::ConfigMap => get_simple_resource_state::<api::core::v1::ConfigMap>(
-                    &self.client,
-                    &self.namespace,
-                    d,
-                )?,
-                Kind::Secret => get_simple_resource_state::<api::core::v1::Secret>(
-                    &self.client,
-                    &self.namespace,
-                    d,
-                )?,
+                Kind::Service => {
+                    get_simple_resource_state::<api::core::v1::Service>(&mut self.cache, d)?
+                }
+                Kind::ConfigMap => {
+                    get_simple_resource_state::<api::core::v1::ConfigMap>(&mut self.cache, d)?
+                }
+                Kind::Secret => {
+                    get_simple_resource_state::<api::core::v1::Secret>(&mut self.cache, d)?
+                }
                 Kind::NetworkPolicy | Kind::Node => {
                     bail!("Unsupported resource type: {:?}", kind);
                 }
@@ -160,6 +122,11 @@ impl Deployer for KubernetesDeployer {
 
     fn deploy(&mut self, resource: &Resource) -> Result<(), Error> {
         use serde_json::Value;
+        if let Some(validator) = &self.validator {
+            if let Err(errors) = validator.validate(&resource.merged_content) {
+                bail!("validation failed: {}", errors.join("; "));
+            }
+        }
         let mut data: Value = resource.merged_content.clone(); // TODO
         {
             let metadata = data
@@ -182,8 +149,21 @@ impl Deployer for KubernetesDeployer {
 
         self.kubectl_apply(&data)?;
 
+        if let Some(kind) = resource.merged_content["kind"].as_str() {
+            self.cache.expect_annotation(
+                kind,
+                &resource.name,
+                VERSION_ANNOTATION,
+                &resource.version.to_string(),
+            );
+        }
+
         Ok(())
     }
+
+    fn watch(&mut self, trigger: Arc<Trigger>) {
+        self.cache.set_trigger(trigger);
+    }
 }
 
 fn determine_rollout_status(name: &str, dep: &api::apps::v1::Deployment) -> RolloutStatusReason {
@@ -259,12 +239,10 @@ fn determine_rollout_status(name: &str, dep: &api::apps::v1::Deployment) -> Roll
 }
 
 fn get_deployment_state(
-    config: &KubeConfig,
-    namespace: &str,
+    cache: &mut ObjectCache,
     d: &Resource,
 ) -> Result<Option<ResourceState>, Error> {
-    let kube_deployment =
-        get_kubernetes_resource::<api::apps::v1::Deployment>(config, namespace, &d.name)?;
+    let kube_deployment = cache.get::<api::apps::v1::Deployment>(&d.name)?;
 
     let kube_deployment = if let Some(k) = kube_deployment {
         k
@@ -284,11 +262,10 @@ fn get_simple_resource_state<
         + k8s_openapi::Metadata<Ty = ObjectMeta>
         + for<'de> serde::Deserialize<'de>,
 >(
-    config: &KubeConfig,
-    namespace: &str,
+    cache: &mut ObjectCache,
     r: &Resource,
 ) -> Result<Option<ResourceState>, Error> {
-    let resource = get_kubernetes_resource::<T>(&config, namespace, &r.name)?;
+    let resource = cache.get::<T>(&r.name)?;
 
     let resource = if let Some(k) = resource {
         k
@@ -321,38 +298,6 @@ fn to_resource_state<T: k8s_openapi::Metadata<Ty = ObjectMeta>>(
     }
 }
 
-fn get_kubernetes_resource<
-    T: k8s_openapi::Resource
-        + k8s_openapi::Metadata<Ty = ObjectMeta>
-        + for<'de> serde::Deserialize<'de>,
->(
-    config: &KubeConfig,
-    namespace: &str,
-    name: &str,
-) -> Result<Option<T>, Error> {
-    let url = format!(
-        "{}/{}/{}/namespaces/{}/{}s/{}",
-        config.base_path,
-        if T::group() == "" { "api" } else { "apis" },
-        T::api_version(),
-        namespace,
-        T::kind().to_ascii_lowercase(),
-        name
-    );
-    let response = config.client.get(&url).send()?;
-    debug!("GET {} => {}", url, response.status());
-    let result = match response.error_for_status() {
-        Ok(mut r) => r.json()?,
-        Err(e) => {
-            if e.status() == Some(reqwest::StatusCode::NOT_FOUND) {
-                return Ok(None);
-            }
-            return Err(e.into());
-        }
-    };
-    Ok(Some(result))
-}
-
 #[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Kind {
     DaemonSet,
@@ -1,14 +1,16 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
 use failure::Error;
 use serde_derive::{Deserialize, Serialize};
 
 use common::deployment::{ResourceState, RolloutStatusReason};
 use common::repo::Id;
+use common::watch::Trigger;
 
 use super::{Deployer, Resource};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Config {}
 
 impl Config {
@@ -25,12 +27,15 @@ struct MockResource {
 
 pub struct MockDeployer {
     resources: HashMap<String, MockResource>,
+    /// Notified whenever a resource is deployed.
+    trigger: Option<Arc<Trigger>>,
 }
 
 impl MockDeployer {
     fn new(_config: &Config) -> Result<MockDeployer, Error> {
         Ok(MockDeployer {
             resources: HashMap::new(),
+            trigger: None,
         })
     }
 }
@@ -67,6 +72,15 @@ impl Deployer for MockDeployer {
                 content: resource.merged_content.clone(),
             },
         );
+        if let Some(trigger) = &self.trigger {
+            trigger.notify();
+        }
         Ok(())
     }
+
+    /// Mock resources are clean as soon as they are deployed, so deploying
+    /// is the only change to report.
+    fn watch(&mut self, trigger: Arc<Trigger>) {
+        self.trigger = Some(trigger);
+    }
 }
@@ -1,18 +1,22 @@
 use std::path::Path;
-use std::{
-    collections::{BTreeMap, HashMap},
-    ffi::OsStr,
-};
+use std::sync::Arc;
+use std::{collections::HashMap, ffi::OsStr};
 
 use failure::{bail, format_err, Error};
 use log::{debug, error, info, warn};
 
+use common::chrono::Utc;
 use common::deployment::{DeployerStatus, ResourceState, RolloutStatus};
+use common::merge;
+use common::policy::Policies;
 use common::repo::{Id, ResourceRepo};
+use common::watch::Trigger;
 use jsonnet::JsonnetVm;
 
 pub mod kubernetes;
 pub mod mock;
+mod object_cache;
+pub mod validation;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Resource {
@@ -25,6 +29,9 @@ pub struct Resource {
 #[derive(Debug, PartialEq, Clone)]
 pub struct ResourcesInfo {
     pub resources: Vec<Resource>,
+    /// The states of the version files that can't be deployed because their
+    /// base file is missing.
+    pub missing_base_files: HashMap<String, ResourceState>,
 }
 
 pub trait Deployer {
@@ -34,6 +41,12 @@ pub trait Deployer {
     ) -> Result<HashMap<String, ResourceState>, Error>;
 
     fn deploy(&mut self, resource: &Resource) -> Result<(), Error>;
+
+    /// Asks the deployer to notify `trigger` whenever the state of the
+    /// deployed resources changes, so that rollouts in progress are checked
+    /// again without polling. Without a watch, they are only checked again
+    /// whenever the resource repo is fetched.
+    fn watch(&mut self, _trigger: Arc<Trigger>) {}
 }
 
 impl Deployer for Box<dyn Deployer> {
@@ -47,6 +60,10 @@ impl Deployer for Box<dyn Deployer> {
     fn deploy(&mut self, resource: &Resource) -> Result<(), Error> {
         (**self).deploy(resource)
     }
+
+    fn watch(&mut self, trigger: Arc<Trigger>) {
+        (**self).watch(trigger)
+    }
 }
 
 pub fn get_resources(
@@ -55,6 +72,7 @@ pub fn get_resources(
     last_version: Option<Id>,
 ) -> Result<Option<ResourcesInfo>, Error> {
     let mut resources = HashMap::<String, Resource>::new();
+    let mut missing_base_files = HashMap::new();
 
     // collect current versions of all resources
     let current_version = repo.version();
@@ -116,25 +134,25 @@ pub fn get_resources(
         let base_file_content = match repo.get(&base_file_name) {
             Ok(Some(content)) => content,
             Ok(None) => {
-                // FIXME report error (base file not found)
-                eprintln!("error: base file {:?} not found", base_file_name);
+                error!("Base file {:?} for {} not found", base_file_name, name);
+                missing_base_files.insert(
+                    name,
+                    ResourceState::MissingBaseFile {
+                        version: entry.last_change,
+                        base_file: base_file_name.display().to_string(),
+                    },
+                );
                 return Ok(());
             }
             Err(e) => bail!(e),
         };
-        let merged_content = if base_file_name.extension() == Some(OsStr::new("jsonnet")) {
-            // FIXME implement import handler
-            let content: serde_json::Value = serde_yaml::from_slice(&entry.content)?;
-            vm.ext_code("version", &serde_json::to_string(&content)?);
-            let result = vm
-                .evaluate_snippet(entry.path, std::str::from_utf8(&base_file_content)?)
-                .map_err(|e| format_err!("jsonnet error: {}", e.as_str()))?;
-            serde_json::from_str(&result)?
-        } else {
-            let content = serde_yaml::from_slice(&entry.content)?;
-            let base_file_content = serde_yaml::from_slice(&base_file_content)?;
-            merge_resource(base_file_content, &content)
-        };
+        let merged_content = merge::merge_version_file(
+            &mut vm,
+            &entry.path,
+            &base_file_name,
+            &base_file_content,
+            &entry.content,
+        )?;
         let resource = Resource {
             name: name.clone(),
             merged_content,
@@ -148,45 +166,12 @@ pub fn get_resources(
 
     let result = ResourcesInfo {
         resources: resources.into_iter().map(|(_, v)| v).collect(),
+        missing_base_files,
     };
 
     Ok(Some(result))
 }
 
-fn merge_resource(
-    mut base: serde_json::Value,
-    version_content: &BTreeMap<String, String>,
-) -> serde_json::Value {
-    use serde_json::*;
-    // TODO rewrite everything about this
-    fn merge_mut(base: &mut Value, version_content: &BTreeMap<String, String>) {
-        match base {
-            Value::String(s) => {
-                let regex = regex::Regex::new("\\$version").unwrap();
-                let replaced = regex
-                    .replace_all(&s, move |_cap: &regex::Captures<'_>| {
-                        version_content.get("version").cloned().unwrap_or_default()
-                    })
-                    .into_owned();
-                *s = replaced;
-            }
-            Value::Array(s) => {
-                for element in s {
-                    merge_mut(element, version_content);
-                }
-            }
-            Value::Object(m) => {
-                for (_, value) in m {
-                    merge_mut(value, version_content);
-                }
-            }
-            Value::Null | Value::Bool(_) | Value::Number(_) => {}
-        }
-    }
-    merge_mut(&mut base, version_content);
-    base
-}
-
 pub fn deploy(deployer: &mut impl Deployer, resources: &[Resource]) -> Result<(), Error> {
     let current_state = deployer.retrieve_current_state(resources)?;
 
@@ -248,6 +233,9 @@ pub fn check_rollout_status(
             ResourceState::Deployed { status, .. } => {
                 RolloutStatus::Outdated.combine(status.clone().into())
             }
+            ResourceState::PolicyViolation { .. } | ResourceState::MissingBaseFile { .. } => {
+                RolloutStatus::Failed
+            }
         })
         .fold(RolloutStatus::Clean, RolloutStatus::combine);
 
@@ -260,12 +248,40 @@ pub fn new_deployer_status(version: Id) -> DeployerStatus {
         last_successfully_deployed_version: None,
         rollout_status: RolloutStatus::InProgress,
         status_by_resource: HashMap::new(),
+        clean_since: HashMap::new(),
+    }
+}
+
+/// Splits the resources into the ones that satisfy all policies for the env,
+/// and the states of the ones that don't.
+fn partition(
+    policies: &Policies,
+    env: &str,
+    resources: Vec<Resource>,
+) -> (Vec<Resource>, HashMap<String, ResourceState>) {
+    let mut allowed = Vec::with_capacity(resources.len());
+    let mut rejected = HashMap::new();
+    for resource in resources {
+        let violations = policies.check(env, &resource.merged_content);
+        if violations.is_empty() {
+            allowed.push(resource);
+        } else {
+            rejected.insert(
+                resource.name,
+                ResourceState::PolicyViolation {
+                    version: resource.version,
+                    violations,
+                },
+            );
+        }
     }
+    (allowed, rejected)
 }
 
 pub fn deploy_env(
     deployer: &mut impl Deployer,
     repo: &impl ResourceRepo,
+    policies: &Policies,
     env: &str,
     last_version: Option<Id>,
     last_status: Option<DeployerStatus>,
@@ -277,7 +293,18 @@ pub fn deploy_env(
             "Got a change for {} to version {:?}, now deploying...",
             env, version
         );
-        deploy(deployer, &resources.resources)?;
+        let (resources, rejected) = partition(policies, env, resources.resources);
+        for (name, state) in &rejected {
+            if let ResourceState::PolicyViolation { violations, .. } = state {
+                for violation in violations {
+                    warn!(
+                        "Not deploying {} to {}: violates policy {}: {}",
+                        name, env, violation.policy, violation.message
+                    );
+                }
+            }
+        }
+        deploy(deployer, &resources)?;
 
         env_status.deployed_version = version;
         env_status.rollout_status = RolloutStatus::InProgress;
@@ -286,15 +313,23 @@ pub fn deploy_env(
     }
 
     if env_status.rollout_status == RolloutStatus::InProgress {
-        if let Some(resources) =
-            get_resources(repo, env, env_status.last_successfully_deployed_version)?
+        if let Some(ResourcesInfo {
+            resources,
+            missing_base_files,
+        }) = get_resources(repo, env, env_status.last_successfully_deployed_version)?
         {
-            let (new_rollout_status, new_status_by_resource) =
-                check_rollout_status(deployer, &resources.resources)?;
+            let (resources, mut rejected) = partition(policies, env, resources);
+            rejected.extend(missing_base_files);
+            let (mut new_rollout_status, new_status_by_resource) =
+                check_rollout_status(deployer, &resources)?;
+            if !rejected.is_empty() {
+                new_rollout_status = new_rollout_status.combine(RolloutStatus::Failed);
+            }
             env_status.rollout_status = new_rollout_status;
             env_status
                 .status_by_resource
-                .extend(new_status_by_resource.into_iter());
+                .extend(new_status_by_resource.into_iter().chain(rejected));
+            env_status.update_clean_since(Utc::now());
         }
     }
 
@@ -500,4 +535,69 @@ mod test {
         assert_eq!(info.resources[0].merged_content, json!({ "bar": 3 }));
         assert_eq!(info.resources[0].version, head)
     }
+
+    #[test]
+    fn test_get_resources_missing_base_file() {
+        let fixture = git_fixture::RepoFixture::from_str(include_str!(
+            "./fixtures/get_resources_missing_base_file.yaml"
+        ))
+        .unwrap();
+        let head = repo::oid_to_id(fixture.get_commit("head").unwrap());
+        let info = get_resources(&make_resource_repo(fixture, "head"), "available", None)
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.resources.len(), 1);
+        assert_eq!(info.resources[0].name, "simple");
+        assert_eq!(info.missing_base_files.len(), 1);
+        assert_eq!(
+            info.missing_base_files["orphan"],
+            ResourceState::MissingBaseFile {
+                version: head,
+                base_file: "available/base/orphan".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_deploy_env_policy_violation() {
+        use common::deployment::{PolicyViolation, RolloutStatusReason};
+
+        let fixture =
+            git_fixture::RepoFixture::from_str(include_str!("./fixtures/deploy_env_policy.yaml"))
+                .unwrap();
+        let head = repo::oid_to_id(fixture.get_commit("head").unwrap());
+        let repo = make_resource_repo(fixture, "head");
+        let policies = Policies::load(&repo).unwrap();
+
+        let mut deployer = mock::Config {}.create().unwrap();
+        let status = deploy_env(&mut deployer, &repo, &policies, "prod", None, None).unwrap();
+        assert_eq!(status.rollout_status, RolloutStatus::Failed);
+        assert_eq!(status.last_successfully_deployed_version, None);
+        assert_eq!(
+            status.status_by_resource["foo"],
+            ResourceState::Deployed {
+                version: head,
+                expected_version: head,
+                status: RolloutStatusReason::Clean,
+            }
+        );
+        assert_eq!(
+            status.status_by_resource["bar"],
+            ResourceState::PolicyViolation {
+                version: head,
+                violations: vec![PolicyViolation {
+                    policy: "no-latest".to_string(),
+                    message: "No latest tags: image is \"bar:latest\", which matches \":latest$\""
+                        .to_string(),
+                }],
+            }
+        );
+        assert_eq!(status.clean_since["foo"].version, head);
+        assert!(!status.clean_since.contains_key("bar"));
+
+        // the policy only applies to prod
+        let mut deployer = mock::Config {}.create().unwrap();
+        let status = deploy_env(&mut deployer, &repo, &policies, "dev", None, None).unwrap();
+        assert_eq!(status.rollout_status, RolloutStatus::Clean);
+    }
 }
@@ -0,0 +1,283 @@
+//! A cache of the Kubernetes objects in a namespace, kept up to date by
+//! watches, so that checking rollouts doesn't need a GET per resource.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::Duration;
+
+use failure::Error;
+use log::{debug, warn};
+use serde::de::DeserializeOwned;
+use serde_derive::Deserialize;
+use serde_json::Value;
+
+use common::watch::Trigger;
+
+/// How long to wait before restarting a failed watch.
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Watch requests are ended by the server after this time, which needs to be
+/// shorter than the client's request timeout.
+const WATCH_TIMEOUT_SECS: u32 = 20;
+
+#[derive(Default)]
+struct KindState {
+    /// The objects of this kind by name, or `None` if they are not known
+    /// because the watch hasn't started yet or failed.
+    objects: Option<HashMap<String, Value>>,
+    /// Annotations that objects are expected to get because they were just
+    /// applied. Until the watch sees them, these objects are fetched
+    /// directly.
+    pending: HashMap<String, (String, String)>,
+}
+
+impl KindState {
+    fn upsert(&mut self, object: Value) {
+        let name = match object_name(&object) {
+            Some(name) => name.to_string(),
+            None => return,
+        };
+        if let Some((key, value)) = self.pending.get(&name) {
+            let annotation = object
+                .pointer(&format!("/metadata/annotations/{}", key.replace('/', "~1")))
+                .and_then(|v| v.as_str());
+            if annotation == Some(value.as_str()) {
+                self.pending.remove(&name);
+            }
+        }
+        if let Some(objects) = &mut self.objects {
+            objects.insert(name, object);
+        }
+    }
+}
+
+pub struct ObjectCache {
+    base_path: String,
+    client: reqwest::Client,
+    namespace: String,
+    trigger: Option<Arc<Trigger>>,
+    kinds: HashMap<String, Arc<Mutex<KindState>>>,
+}
+
+impl ObjectCache {
+    pub fn new(base_path: String, client: reqwest::Client, namespace: String) -> ObjectCache {
+        ObjectCache {
+            base_path,
+            client,
+            namespace,
+            trigger: None,
+            kinds: HashMap::new(),
+        }
+    }
+
+    /// Sets the trigger to notify when watched objects change. Only affects
+    /// watches that are started afterwards.
+    pub fn set_trigger(&mut self, trigger: Arc<Trigger>) {
+        self.trigger = Some(trigger);
+    }
+
+    /// Gets an object, or `None` if it doesn't exist. The first request for
+    /// a kind of object starts watching all objects of that kind.
+    pub fn get<T: k8s_openapi::Resource + DeserializeOwned>(
+        &mut self,
+        name: &str,
+    ) -> Result<Option<T>, Error> {
+        let url = format!(
+            "{}/{}/{}/namespaces/{}/{}s",
+            self.base_path,
+            if T::group() == "" { "api" } else { "apis" },
+            T::api_version(),
+            self.namespace,
+            T::kind().to_ascii_lowercase(),
+        );
+        let state = self.kind_state(T::kind(), &url);
+        let cached = {
+            let state = state.lock().expect("Mutex poisoned");
+            match &state.objects {
+                Some(objects) if !state.pending.contains_key(name) => {
+                    Some(objects.get(name).cloned())
+                }
+                _ => None,
+            }
+        };
+        match cached {
+            Some(Some(object)) => Ok(Some(serde_json::from_value(object)?)),
+            Some(None) => Ok(None),
+            None => get_object(&self.client, &format!("{}/{}", url, name)),
+        }
+    }
+
+    /// Records that an object was just applied with the given annotation, so
+    /// that it isn't taken from the cache until the watch has seen the
+    /// change.
+    pub fn expect_annotation(&mut self, kind: &str, name: &str, key: &str, value: &str) {
+        if let Some(state) = self.kinds.get(kind) {
+            state
+                .lock()
+                .expect("Mutex poisoned")
+                .pending
+                .insert(name.to_string(), (key.to_string(), value.to_string()));
+        }
+    }
+
+    fn kind_state(&mut self, kind: &str, url: &str) -> Arc<Mutex<KindState>> {
+        if let Some(state) = self.kinds.get(kind) {
+            return state.clone();
+        }
+        let state = Arc::new(Mutex::new(KindState::default()));
+        let client = self.client.clone();
+        let url = url.to_string();
+        let weak_state = Arc::downgrade(&state);
+        let trigger = self.trigger.clone();
+        thread::spawn(move || watch_kind(&client, &url, &weak_state, trigger.as_ref()));
+        self.kinds.insert(kind.to_string(), state.clone());
+        state
+    }
+}
+
+fn object_name(object: &Value) -> Option<&str> {
+    object.pointer("/metadata/name").and_then(|n| n.as_str())
+}
+
+fn get_object<T: DeserializeOwned>(
+    client: &reqwest::Client,
+    url: &str,
+) -> Result<Option<T>, Error> {
+    let response = client.get(url).send()?;
+    debug!("GET {} => {}", url, response.status());
+    let result = match response.error_for_status() {
+        Ok(mut r) => r.json()?,
+        Err(e) => {
+            if e.status() == Some(reqwest::StatusCode::NOT_FOUND) {
+                return Ok(None);
+            }
+            return Err(e.into());
+        }
+    };
+    Ok(Some(result))
+}
+
+/// Keeps the objects of one kind up to date until the cache is dropped.
+fn watch_kind(
+    client: &reqwest::Client,
+    url: &str,
+    weak_state: &Weak<Mutex<KindState>>,
+    trigger: Option<&Arc<Trigger>>,
+) {
+    let mut resource_version = None;
+    loop {
+        let state = match weak_state.upgrade() {
+            Some(state) => state,
+            None => return,
+        };
+        let result = match &resource_version {
+            None => list(client, url, &state),
+            Some(version) => watch(client, url, version, &state, trigger),
+        };
+        match result {
+            Ok(version) => resource_version = version,
+            Err(e) => {
+                warn!("Watching {} failed: {}", url, e);
+                state.lock().expect("Mutex poisoned").objects = None;
+                resource_version = None;
+                drop(state);
+                thread::sleep(RETRY_DELAY);
+                // the objects need to be fetched directly until the watch
+                // works again
+                if let Some(trigger) = trigger {
+                    trigger.notify();
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListMeta {
+    resource_version: String,
+}
+
+#[derive(Deserialize)]
+struct ObjectList {
+    metadata: ListMeta,
+    items: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+struct WatchEvent {
+    #[serde(rename = "type")]
+    type_: String,
+    object: Value,
+}
+
+fn list(
+    client: &reqwest::Client,
+    url: &str,
+    state: &Mutex<KindState>,
+) -> Result<Option<String>, Error> {
+    let list: ObjectList = client.get(url).send()?.error_for_status()?.json()?;
+    let mut state = state.lock().expect("Mutex poisoned");
+    state.objects = Some(HashMap::with_capacity(list.items.len()));
+    for object in list.items {
+        state.upsert(object);
+    }
+    Ok(Some(list.metadata.resource_version))
+}
+
+/// Applies the changes reported by one watch request. Returns the resource
+/// version to continue from, or `None` if the objects need to be listed
+/// again.
+fn watch(
+    client: &reqwest::Client,
+    url: &str,
+    resource_version: &str,
+    state: &Mutex<KindState>,
+    trigger: Option<&Arc<Trigger>>,
+) -> Result<Option<String>, Error> {
+    let response = client
+        .get(url)
+        .query(&[
+            ("watch", "true"),
+            ("resourceVersion", resource_version),
+            ("timeoutSeconds", &WATCH_TIMEOUT_SECS.to_string()),
+        ])
+        .send()?
+        .error_for_status()?;
+    let mut resource_version = resource_version.to_string();
+    for line in BufReader::new(response).lines() {
+        let event: WatchEvent = serde_json::from_str(&line?)?;
+        match event.type_.as_str() {
+            "ERROR" => {
+                // usually means our resource version is too old
+                debug!("Watch on {} ended with error: {}", url, event.object);
+                return Ok(None);
+            }
+            "BOOKMARK" => {}
+            type_ => {
+                let mut state = state.lock().expect("Mutex poisoned");
+                if type_ == "DELETED" {
+                    if let (Some(objects), Some(name)) =
+                        (&mut state.objects, object_name(&event.object))
+                    {
+                        objects.remove(name);
+                    }
+                } else {
+                    state.upsert(event.object.clone());
+                }
+                if let Some(trigger) = trigger {
+                    trigger.notify();
+                }
+            }
+        }
+        if let Some(version) = event
+            .object
+            .pointer("/metadata/resourceVersion")
+            .and_then(|v| v.as_str())
+        {
+            resource_version = version.to_string();
+        }
+    }
+    Ok(Some(resource_version))
+}
@@ -0,0 +1,514 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use failure::{bail, format_err, Error};
+use log::warn;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use common::deployment::{ResourceState, RolloutStatusReason};
+use common::watch::Trigger;
+
+use super::{Deployer, Resource};
+
+/// Resources with this annotation set to `"true"` are not validated.
+pub const SKIP_VALIDATION_ANNOTATION: &str = "new-dm/skip-validation";
+
+const DEFAULT_KUBERNETES_VERSION: &str = "1.10";
+
+/// The bundled schemas, by Kubernetes version, together with the built-in
+/// kinds the version serves by apiVersion. The schemas are hand-written
+/// subsets of the Kubernetes API definitions that only cover Deployment,
+/// Service, ConfigMap and Secret.
+const SCHEMAS: &[(&str, &str, &str)] = &[
+    (
+        "1.10",
+        include_str!("./schemas/v1_10.json"),
+        include_str!("./schemas/v1_10_served.yaml"),
+    ),
+    (
+        "1.16",
+        include_str!("./schemas/v1_16.json"),
+        include_str!("./schemas/v1_16_served.yaml"),
+    ),
+];
+
+fn default_kubernetes_version() -> String {
+    DEFAULT_KUBERNETES_VERSION.to_string()
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Config {
+    #[serde(default = "default_kubernetes_version")]
+    pub kubernetes_version: String,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            kubernetes_version: default_kubernetes_version(),
+        }
+    }
+}
+
+impl Config {
+    pub fn create(&self) -> Result<ValidationDeployer, Error> {
+        Ok(ValidationDeployer {
+            validator: Validator::new(&self.kubernetes_version)?,
+        })
+    }
+}
+
+/// Checks resources against the OpenAPI schema of a Kubernetes version.
+///
+/// This rejects built-in kinds under an apiVersion the Kubernetes version
+/// doesn't serve, and checks types, required fields and enum values of the
+/// kinds the schema covers. Unknown fields are not reported, since the
+/// bundled schemas don't contain every field. Custom resources are only
+/// checked for apiVersion and kind.
+pub struct Validator {
+    kubernetes_version: String,
+    definitions: Map<String, Value>,
+    /// Maps (apiVersion, kind) to the name of the definition.
+    kinds: HashMap<(String, String), String>,
+    /// The built-in (apiVersion, kind) pairs the version serves.
+    served: HashSet<(String, String)>,
+    /// The API groups of any supported version, to tell built-in kinds from
+    /// custom resources.
+    builtin_groups: HashSet<String>,
+    /// The kinds without a schema that were already logged.
+    unchecked: Mutex<HashSet<(String, String)>>,
+}
+
+impl Validator {
+    pub fn new(kubernetes_version: &str) -> Result<Validator, Error> {
+        let (schema, served) = SCHEMAS
+            .iter()
+            .find(|(version, _, _)| *version == kubernetes_version)
+            .map(|(_, schema, served)| (*schema, *served))
+            .ok_or_else(|| {
+                format_err!(
+                    "no schema for Kubernetes version {}; supported versions are {}",
+                    kubernetes_version,
+                    SCHEMAS
+                        .iter()
+                        .map(|(v, _, _)| *v)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                )
+            })?;
+
+        let definitions = match serde_json::from_str(schema)? {
+            Value::Object(mut schema) => match schema.remove("definitions") {
+                Some(Value::Object(definitions)) => definitions,
+                _ => bail!("bad schema: no definitions"),
+            },
+            _ => bail!("bad schema: not an object"),
+        };
+
+        let mut kinds = HashMap::new();
+        for (name, definition) in &definitions {
+            let gvks = definition
+                .get("x-kubernetes-group-version-kind")
+                .and_then(Value::as_array)
+                .map(|a| a.as_slice())
+                .unwrap_or(&[]);
+            for gvk in gvks {
+                let field = |f: &str| gvk.get(f).and_then(Value::as_str).unwrap_or("");
+                let api_version = match field("group") {
+                    "" => field("version").to_string(),
+                    group => format!("{}/{}", group, field("version")),
+                };
+                kinds.insert((api_version, field("kind").to_string()), name.clone());
+            }
+        }
+
+        let mut builtin_groups = HashSet::new();
+        for (_, _, served) in SCHEMAS {
+            for api_version in parse_served(served)?.keys() {
+                builtin_groups.insert(group(api_version).to_string());
+            }
+        }
+        let served = parse_served(served)?
+            .into_iter()
+            .flat_map(|(api_version, kinds)| {
+                kinds
+                    .into_iter()
+                    .map(move |kind| (api_version.clone(), kind))
+            })
+            .collect();
+
+        Ok(Validator {
+            kubernetes_version: kubernetes_version.to_string(),
+            definitions,
+            kinds,
+            served,
+            builtin_groups,
+            unchecked: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /// Validates the resource, returning a list of errors if there were any.
+    pub fn validate(&self, resource: &Value) -> Result<(), Vec<String>> {
+        if skip_validation(resource) {
+            return Ok(());
+        }
+
+        let api_version = resource.get("apiVersion").and_then(Value::as_str);
+        let kind = resource.get("kind").and_then(Value::as_str);
+        let (api_version, kind) = match (api_version, kind) {
+            (Some(api_version), Some(kind)) => (api_version, kind),
+            _ => return Err(vec!["apiVersion and kind are required".to_string()]),
+        };
+
+        let key = (api_version.to_string(), kind.to_string());
+        if self.builtin_groups.contains(group(api_version)) && !self.served.contains(&key) {
+            return Err(vec![format!(
+                "Kubernetes {} doesn't serve {} {}",
+                self.kubernetes_version, kind, api_version
+            )]);
+        }
+
+        let definition = match self.kinds.get(&key) {
+            Some(definition) => definition,
+            None => {
+                if self.unchecked.lock().expect("Mutex poisoned").insert(key) {
+                    warn!(
+                        "Only checking apiVersion of {} {}: no schema for it",
+                        api_version, kind
+                    );
+                }
+                return Ok(());
+            }
+        };
+
+        let mut errors = Vec::new();
+        self.validate_definition(definition, resource, "", &mut errors);
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn validate_definition(&self, name: &str, value: &Value, path: &str, errors: &mut Vec<String>) {
+        match self.definitions.get(name) {
+            Some(schema) => self.validate_value(schema, value, path, errors),
+            None => errors.push(format!("{}: unknown definition {}", display(path), name)),
+        }
+    }
+
+    fn validate_value(&self, schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) {
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            let name = reference.trim_start_matches("#/definitions/");
+            return self.validate_definition(name, value, path, errors);
+        }
+
+        let format = schema.get("format").and_then(Value::as_str);
+        let type_ok = match schema.get("type").and_then(Value::as_str) {
+            // int-or-string and quantities are declared as strings, but
+            // Kubernetes accepts numbers for them as well
+            Some("string") if format == Some("int-or-string") || format == Some("quantity") => {
+                value.is_string() || value.is_number()
+            }
+            Some("string") => value.is_string(),
+            Some("integer") => value.is_i64() || value.is_u64(),
+            Some("number") => value.is_number(),
+            Some("boolean") => value.is_boolean(),
+            Some("array") => value.is_array(),
+            Some("object") => value.is_object(),
+            _ => true,
+        };
+        if !type_ok {
+            errors.push(format!(
+                "{}: expected {}, got {}",
+                display(path),
+                schema.get("type").and_then(Value::as_str).unwrap_or(""),
+                type_name(value)
+            ));
+            return;
+        }
+
+        if let Some(allowed) = schema.get("enum").and_then(Value::as_array) {
+            if !allowed.contains(value) {
+                errors.push(format!(
+                    "{}: {} is not one of {}",
+                    display(path),
+                    value,
+                    allowed
+                        .iter()
+                        .map(Value::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ));
+            }
+        }
+
+        match value {
+            Value::Object(object) => {
+                let required = schema
+                    .get("required")
+                    .and_then(Value::as_array)
+                    .map(|a| a.as_slice())
+                    .unwrap_or(&[]);
+                for field in required.iter().filter_map(Value::as_str) {
+                    if !object.contains_key(field) {
+                        errors.push(format!(
+                            "{}: missing required field",
+                            display(&join(path, field))
+                        ));
+                    }
+                }
+
+                let properties = schema.get("properties").and_then(Value::as_object);
+                let additional = schema.get("additionalProperties");
+                for (field, field_value) in object {
+                    if let Some(field_schema) = properties.and_then(|p| p.get(field)) {
+                        self.validate_value(field_schema, field_value, &join(path, field), errors);
+                    } else if let Some(field_schema) = additional {
+                        self.validate_value(field_schema, field_value, &join(path, field), errors);
+                    }
+                }
+            }
+            Value::Array(items) => {
+                if let Some(item_schema) = schema.get("items") {
+                    for (i, item) in items.iter().enumerate() {
+                        self.validate_value(item_schema, item, &format!("{}[{}]", path, i), errors);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn parse_served(served: &str) -> Result<BTreeMap<String, Vec<String>>, Error> {
+    Ok(serde_yaml::from_str(served)?)
+}
+
+/// The API group of an apiVersion, which is empty for the core group.
+fn group(api_version: &str) -> &str {
+    match api_version.rfind('/') {
+        Some(i) => &api_version[..i],
+        None => "",
+    }
+}
+
+fn skip_validation(resource: &Value) -> bool {
+    resource
+        .get("metadata")
+        .and_then(|m| m.get("annotations"))
+        .and_then(|a| a.get(SKIP_VALIDATION_ANNOTATION))
+        .and_then(Value::as_str)
+        == Some("true")
+}
+
+fn join(path: &str, field: &str) -> String {
+    if path.is_empty() {
+        field.to_string()
+    } else {
+        format!("{}.{}", path, field)
+    }
+}
+
+fn display(path: &str) -> &str {
+    if path.is_empty() {
+        "<root>"
+    } else {
+        path
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(n) if n.is_f64() => "number",
+        Value::Number(_) => "integer",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// A deployer that doesn't deploy anywhere, but only validates the resources.
+/// This is useful for envs like `latest` that aren't backed by a cluster.
+/// Valid resources are reported as cleanly deployed, invalid ones as failed.
+pub struct ValidationDeployer {
+    validator: Validator,
+}
+
+impl Deployer for ValidationDeployer {
+    fn retrieve_current_state(
+        &mut self,
+        resources: &[Resource],
+    ) -> Result<HashMap<String, ResourceState>, Error> {
+        let mut result = HashMap::with_capacity(resources.len());
+        for resource in resources {
+            let status = match self.validator.validate(&resource.merged_content) {
+                Ok(()) => RolloutStatusReason::Clean,
+                Err(errors) => RolloutStatusReason::Failed {
+                    message: format!("validation failed: {}", errors.join("; ")),
+                },
+            };
+            result.insert(
+                resource.name.clone(),
+                ResourceState::Deployed {
+                    version: resource.version,
+                    expected_version: resource.version,
+                    status,
+                },
+            );
+        }
+        Ok(result)
+    }
+
+    fn deploy(&mut self, _resource: &Resource) -> Result<(), Error> {
+        // nothing to deploy; the resources are validated when retrieving
+        // their state
+        Ok(())
+    }
+
+    /// The state of a resource only depends on its content, which only
+    /// changes with the resource repo, so there is nothing to watch.
+    fn watch(&mut self, _trigger: Arc<Trigger>) {}
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn deployment() -> Value {
+        json!({
+            "apiVersion": "apps/v1",
+            "kind": "Deployment",
+            "metadata": { "name": "foo", "labels": { "app": "foo" } },
+            "spec": {
+                "replicas": 2,
+                "selector": { "matchLabels": { "app": "foo" } },
+                "template": {
+                    "metadata": { "labels": { "app": "foo" } },
+                    "spec": {
+                        "containers": [{
+                            "name": "foo",
+                            "image": "foo:1.0",
+                            "ports": [{ "containerPort": 8080 }],
+                            "resources": { "limits": { "cpu": 1, "memory": "128Mi" } },
+                            "readinessProbe": { "httpGet": { "port": "http" } }
+                        }]
+                    }
+                }
+            }
+        })
+    }
+
+    #[test]
+    fn test_valid() {
+        let validator = Validator::new("1.10").unwrap();
+        assert_eq!(validator.validate(&deployment()), Ok(()));
+
+        let service = json!({
+            "apiVersion": "v1",
+            "kind": "Service",
+            "metadata": { "name": "foo" },
+            "spec": {
+                "type": "ClusterIP",
+                "ports": [{ "port": 80, "targetPort": 8080 }]
+            }
+        });
+        assert_eq!(validator.validate(&service), Ok(()));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let validator = Validator::new("1.10").unwrap();
+
+        let mut resource = deployment();
+        resource["spec"]["replicas"] = json!("two");
+        resource["spec"]["template"]["spec"]["containers"][0]
+            .as_object_mut()
+            .unwrap()
+            .remove("name");
+        resource["metadata"]["labels"]["version"] = json!(3);
+        assert_eq!(
+            validator.validate(&resource),
+            Err(vec![
+                "metadata.labels.version: expected string, got integer".to_string(),
+                "spec.replicas: expected integer, got string".to_string(),
+                "spec.template.spec.containers[0].name: missing required field".to_string(),
+            ])
+        );
+
+        let service = json!({
+            "apiVersion": "v1",
+            "kind": "Service",
+            "metadata": { "name": "foo" },
+            "spec": { "type": "Internal" }
+        });
+        assert_eq!(
+            validator.validate(&service),
+            Err(vec![
+                "spec.type: \"Internal\" is not one of \"ExternalName\", \"ClusterIP\", \
+                 \"NodePort\", \"LoadBalancer\""
+                    .to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_kubernetes_version() {
+        let mut resource = deployment();
+        resource["apiVersion"] = json!("extensions/v1beta1");
+
+        let kind = |validator: &Validator| {
+            let key = ("extensions/v1beta1".to_string(), "Deployment".to_string());
+            validator.kinds.contains_key(&key)
+        };
+        assert!(kind(&Validator::new("1.10").unwrap()));
+        assert!(!kind(&Validator::new("1.16").unwrap()));
+        assert!(Validator::new("0.1").is_err());
+
+        assert_eq!(Validator::new("1.10").unwrap().validate(&resource), Ok(()));
+        assert_eq!(
+            Validator::new("1.16").unwrap().validate(&resource),
+            Err(vec![
+                "Kubernetes 1.16 doesn't serve Deployment extensions/v1beta1".to_string()
+            ])
+        );
+    }
+
+    #[test]
+    fn test_kinds_without_schema() {
+        let validator = Validator::new("1.10").unwrap();
+
+        // served, but only the apiVersion is checked
+        let job = json!({ "apiVersion": "batch/v1", "kind": "Job", "spec": 1 });
+        assert_eq!(validator.validate(&job), Ok(()));
+
+        let ingress = json!({ "apiVersion": "networking.k8s.io/v1beta1", "kind": "Ingress" });
+        assert_eq!(
+            validator.validate(&ingress),
+            Err(vec![
+                "Kubernetes 1.10 doesn't serve Ingress networking.k8s.io/v1beta1".to_string()
+            ])
+        );
+        assert_eq!(Validator::new("1.16").unwrap().validate(&ingress), Ok(()));
+
+        // custom resources
+        let monitor = json!({ "apiVersion": "monitoring.coreos.com/v1", "kind": "ServiceMonitor" });
+        assert_eq!(validator.validate(&monitor), Ok(()));
+    }
+
+    #[test]
+    fn test_skip_validation() {
+        let validator = Validator::new("1.10").unwrap();
+        let mut resource = deployment();
+        resource["spec"]["replicas"] = json!("two");
+        resource["metadata"]["annotations"] = json!({ SKIP_VALIDATION_ANNOTATION: "true" });
+
+        assert_eq!(validator.validate(&resource), Ok(()));
+    }
+}
@@ -3,67 +3,200 @@ use std::collections::HashMap;
 use std::path::Path;
 use std::process;
 use std::sync::Arc;
-use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use crossbeam::atomic::ArcCell;
-use failure::Error;
-use log::error;
+use failure::{Error, ResultExt};
+use log::{error, info};
 use serde_derive::Deserialize;
 
-use common::deployment::AllDeployerStatus;
-use common::repo::{self, ResourceRepo};
+use common::deployment::{AllDeployerStatus, RolloutStatus};
+use common::policy::Policies;
+use common::repo::{self, Id, ResourceRepo};
+use common::shutdown::{self, Shutdown};
+use common::watch::{self, Trigger, Watch};
 
 mod api;
 mod config;
 mod deployment;
+mod rollback;
 
 use crate::config::Config;
+use crate::deployment::Deployer;
+
+/// How soon to retry after deploying an env failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Deserialize, Clone)]
 struct Env {
     #[serde(flatten)]
     common: common::Env,
     api_port: Option<u16>,
+    shutdown_deadline_secs: Option<u64>,
+    /// How often to fetch the versions repo if no webhook triggers a fetch.
+    poll_interval_secs: Option<u64>,
 }
 
 pub struct ServiceState {
-    latest_status: ArcCell<AllDeployerStatus>,
+    latest_status: Watch<Arc<AllDeployerStatus>>,
+    /// Wakes up the main loop, either to fetch the versions repo or because
+    /// deployed resources changed.
+    trigger: Arc<Trigger>,
     env: Env,
 }
 
-fn serve(env: Env) -> Result<(), Error> {
+fn load_config(repo: &impl ResourceRepo) -> Result<Config, Error> {
+    repo.get(Path::new("deployers.yaml"))?
+        .map_or(Ok(Config::default()), |data| Config::load(&data))
+}
+
+/// Switches to the new config, creating deployers for all envs whose deployer
+/// config changed and tearing down the ones for envs that were removed. If any
+/// deployer can't be created, nothing is changed.
+///
+/// Returns the envs whose deployer was recreated or removed.
+fn update_deployers(
+    env: &Env,
+    trigger: &Arc<Trigger>,
+    config: &mut Config,
+    deployers: &mut BTreeMap<String, Box<dyn Deployer>>,
+    new_config: Config,
+) -> Result<Vec<String>, Error> {
+    let mut created = BTreeMap::new();
+    for (env_name, env_config) in &new_config.deployers {
+        let old_config = config.deployers.get(env_name).map(|c| &c.deployer);
+        if old_config != Some(&env_config.deployer) {
+            let mut deployer = env_config
+                .deployer
+                .create(env)
+                .with_context(|_| format!("creating deployer for {} failed", env_name))?;
+            deployer.watch(trigger.clone());
+            created.insert(env_name.clone(), deployer);
+        }
+    }
+
+    let mut changed = created.keys().cloned().collect::<Vec<_>>();
+    deployers.retain(|env_name, _| {
+        let keep = new_config.deployers.contains_key(env_name);
+        if !keep {
+            changed.push(env_name.clone());
+        }
+        keep
+    });
+    deployers.extend(created);
+    *config = new_config;
+
+    Ok(changed)
+}
+
+fn serve(env: Env, shutdown: &Shutdown) -> Result<(), Error> {
     let mut repo = repo::GitResourceRepo::open(env.common.clone())?;
 
-    let config = repo
-        .get(Path::new("deployers.yaml"))?
-        .map_or(Ok(Config::default()), |data| Config::load(&data))?;
+    let mut config = Config::default();
+    let mut config_version = None;
+    let mut deployers = BTreeMap::new();
 
-    let mut deployers = config
-        .deployers
-        .iter()
-        .map(|(env_name, deployer_config)| {
-            deployer_config
-                .create(&env)
-                .map(|d| (env_name.to_owned(), d))
-        })
-        .collect::<Result<BTreeMap<_, _>, Error>>()?;
+    let poll_interval = env
+        .poll_interval_secs
+        .map_or(watch::DEFAULT_POLL_INTERVAL, Duration::from_secs);
 
     let service_state = Arc::new(ServiceState {
-        latest_status: ArcCell::new(Arc::new(AllDeployerStatus::empty())),
+        latest_status: Watch::new(Arc::new(AllDeployerStatus::empty())),
+        trigger: Arc::new(Trigger::new()),
         env,
     });
 
-    api::start(service_state.clone());
+    let trigger = service_state.trigger.clone();
+    shutdown.on_shutdown(move || trigger.notify());
+
+    let api = api::start(service_state.clone(), shutdown);
 
     let mut last_version = HashMap::new();
+    let mut policies: Option<(Id, Policies)> = None;
+    let mut last_fetch: Option<Instant> = None;
+    let mut fetch_requested = false;
 
     loop {
-        repo.update()?;
+        let mut failed = false;
+
+        if fetch_requested || last_fetch.map_or(true, |t| t.elapsed() >= poll_interval) {
+            repo.update()?;
+            last_fetch = Some(Instant::now());
+        }
 
         let version = repo.version();
 
+        if config_version != Some(version) {
+            let mut latest_status = service_state.latest_status.get();
+            let latest_status_mut = Arc::make_mut(&mut latest_status);
+            let result = load_config(&repo).and_then(|new_config| {
+                update_deployers(
+                    &service_state.env,
+                    &service_state.trigger,
+                    &mut config,
+                    &mut deployers,
+                    new_config,
+                )
+            });
+            match result {
+                Ok(changed) => {
+                    for env in changed {
+                        info!("Deployer for {} changed", env);
+                        // the new deployer deploys everything again, but
+                        // keeps the status, e.g. the last successfully
+                        // deployed version
+                        last_version.remove(&env);
+                        if !deployers.contains_key(&env) {
+                            latest_status_mut.deployers.remove(&env);
+                        }
+                    }
+                    latest_status_mut.config_error = None;
+                }
+                Err(e) => {
+                    error!(
+                        "Loading deployer config failed, keeping the previous one: {}\n{}",
+                        e,
+                        e.backtrace()
+                    );
+                    for cause in e.iter_causes() {
+                        error!("caused by: {}", cause);
+                    }
+                    latest_status_mut.config_error = Some(
+                        e.iter_chain()
+                            .map(|c| c.to_string())
+                            .collect::<Vec<_>>()
+                            .join(": "),
+                    );
+                }
+            }
+            service_state.latest_status.set(latest_status);
+            config_version = Some(version);
+        }
+
+        if policies.as_ref().map(|(v, _)| *v) != Some(version) {
+            match Policies::load(&repo) {
+                Ok(p) => policies = Some((version, p)),
+                Err(e) => {
+                    error!("Loading policies failed: {}\n{}", e, e.backtrace());
+                    for cause in e.iter_causes() {
+                        error!("caused by: {}", cause);
+                    }
+
+                    policies = None;
+                    failed = true;
+                }
+            }
+        }
+
         for (env, deployer) in &mut deployers {
+            if shutdown.is_requested() {
+                break;
+            }
+            // nothing is deployed without knowing the policies
+            let policies = match &policies {
+                Some((_, p)) => p,
+                None => break,
+            };
+
             let mut latest_status = service_state.latest_status.get();
 
             let env_status = latest_status.deployers.get(&*env).cloned();
@@ -71,6 +204,7 @@ fn serve(env: Env) -> Result<(), Error> {
             let env_status = match deployment::deploy_env(
                 deployer,
                 &repo,
+                policies,
                 env,
                 last_version.get(env).cloned(),
                 env_status,
@@ -82,10 +216,33 @@ fn serve(env: Env) -> Result<(), Error> {
                         error!("caused by: {}", cause);
                     }
 
+                    failed = true;
                     continue;
                 }
             };
 
+            let auto_rollback = config
+                .deployers
+                .get(&*env)
+                .map_or(false, |c| c.auto_rollback);
+            if auto_rollback && env_status.rollout_status == RolloutStatus::Failed {
+                match rollback::rollback_failed(
+                    &repo.repo,
+                    &service_state.env.common.versions_url,
+                    env,
+                    &env_status,
+                ) {
+                    Ok(Some(_)) => service_state.trigger.request_fetch(),
+                    Ok(None) => {}
+                    Err(e) => {
+                        error!("Rollback failed: {}\n{}", e, e.backtrace());
+                        for cause in e.iter_causes() {
+                            error!("caused by: {}", cause);
+                        }
+                    }
+                }
+            }
+
             Arc::make_mut(&mut latest_status)
                 .deployers
                 .insert(env.to_string(), env_status);
@@ -93,15 +250,34 @@ fn serve(env: Env) -> Result<(), Error> {
             last_version.insert(env.clone(), version);
         }
 
-        thread::sleep(Duration::from_millis(1000));
+        let mut timeout = last_fetch.map_or(Duration::from_secs(0), |t| {
+            poll_interval - t.elapsed().min(poll_interval)
+        });
+        if failed {
+            timeout = timeout.min(RETRY_INTERVAL);
+        }
+        fetch_requested = service_state.trigger.wait(timeout);
+        if shutdown.is_requested() {
+            break;
+        }
     }
+
+    api.join().expect("API thread panicked");
+
+    info!("Deployer stopped.");
+
+    Ok(())
 }
 
 fn run() -> Result<(), Error> {
     env_logger::init();
-    let env = envy::from_env()?;
+    let env: Env = envy::from_env()?;
+    let deadline = env
+        .shutdown_deadline_secs
+        .map_or(shutdown::DEFAULT_DEADLINE, Duration::from_secs);
+    let shutdown = Shutdown::install(deadline)?;
 
-    serve(env)
+    serve(env, &shutdown)
 }
 
 fn main() {
@@ -0,0 +1,270 @@
+use std::fmt::Write;
+use std::path::Path;
+
+use failure::{bail, Error};
+use git2::{ErrorCode, Repository, Signature};
+use log::{info, warn};
+
+use common::deployment::{DeployerStatus, ResourceState, RolloutStatusReason};
+use common::git::{self, TreeZipper};
+use common::repo::{id_to_oid, oid_to_id, Id};
+use common::transitions::Locks;
+
+/// Returns the resources of the env whose current version failed to roll out,
+/// together with the failure message.
+pub fn failed_resources(status: &DeployerStatus) -> Vec<(String, String)> {
+    let mut result = status
+        .status_by_resource
+        .iter()
+        .filter_map(|(name, state)| match state {
+            ResourceState::Deployed {
+                version,
+                expected_version,
+                status: RolloutStatusReason::Failed { message },
+            } if version == expected_version => Some((name.clone(), message.clone())),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    result.sort();
+    result
+}
+
+/// Reverts the version files of all resources that failed to roll out in the
+/// env to the last version that was deployed cleanly, and locks the env so
+/// the failed versions don't get transitioned there again right away.
+///
+/// Returns the ID of the rollback commit, or `None` if there was nothing to
+/// roll back.
+pub fn rollback_failed(
+    repo: &Repository,
+    versions_url: &str,
+    env: &str,
+    status: &DeployerStatus,
+) -> Result<Option<Id>, Error> {
+    let last_clean = match status.last_successfully_deployed_version {
+        Some(version) if version != status.deployed_version => version,
+        _ => return Ok(None),
+    };
+
+    let failed = failed_resources(status);
+    if failed.is_empty() {
+        return Ok(None);
+    }
+
+    let head_commit = git::get_head_commit(repo)?;
+    let tree = head_commit.tree()?;
+    let clean_tree = repo.find_commit(id_to_oid(last_clean))?.tree()?;
+
+    let env_version_path = Path::new(env).join("version");
+    let mut version_files = TreeZipper::from(repo, tree.clone());
+    version_files.descend(env)?;
+    version_files.descend("version")?;
+    let version_files = version_files
+        .walk(false)
+        .map(|(path, entry)| Ok((path, entry?)))
+        .collect::<Result<Vec<_>, git2::Error>>()?;
+
+    let mut zipper = TreeZipper::from(repo, tree.clone());
+    let mut rolled_back = Vec::with_capacity(failed.len());
+    for (resource, message) in &failed {
+        let resource = resource.as_str();
+        let version_file = version_files
+            .iter()
+            .find(|(path, _)| path.file_stem().and_then(|s| s.to_str()) == Some(resource));
+        let (path, entry) = if let Some(f) = version_file {
+            f
+        } else {
+            warn!(
+                "Not rolling back {} in {}: it has no version file",
+                resource, env
+            );
+            continue;
+        };
+
+        let clean_entry = match clean_tree.get_path(&env_version_path.join(path)) {
+            Ok(entry) => entry,
+            Err(ref e) if e.code() == ErrorCode::NotFound => {
+                warn!(
+                    "Not rolling back {} in {}: it did not exist in {}",
+                    resource, env, last_clean
+                );
+                continue;
+            }
+            Err(e) => bail!(e),
+        };
+
+        if clean_entry.id() == entry.id() {
+            continue;
+        }
+
+        zipper.update_file(&env_version_path.join(path), |zipper, name| {
+            zipper.rebuild(|b| {
+                b.insert(name, clean_entry.id(), clean_entry.filemode())?;
+                Ok(())
+            })
+        })?;
+
+        rolled_back.push((resource, message));
+    }
+
+    if rolled_back.is_empty() {
+        return Ok(None);
+    }
+
+    let resource_list = rolled_back
+        .iter()
+        .map(|(r, _)| *r)
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    zipper.descend(env)?;
+    let mut locks = Locks::load(&zipper)?;
+    locks
+        .env_lock
+        .add_reason(&format!("automatic rollback of {}", resource_list));
+    locks.save(repo, &mut zipper)?;
+    zipper.ascend()?;
+
+    let new_tree = zipper.into_inner().expect("new tree should not be None");
+
+    let mut message = format!("Roll back {} in {}\n\n", resource_list, env);
+    write!(&mut message, "The rollout of these resources failed:\n").unwrap();
+    for (resource, failure) in &rolled_back {
+        write!(&mut message, " - {}: {}\n", resource, failure).unwrap();
+    }
+    write!(
+        &mut message,
+        "\nTheir versions were reverted to the ones deployed in {}, and {} was locked.\n\n",
+        last_clean, env
+    )
+    .unwrap();
+    write!(&mut message, "DM-Type: Rollback\n").unwrap();
+    write!(&mut message, "DM-Env: {}\n", env).unwrap();
+    write!(&mut message, "DM-Resources: {}\n", resource_list).unwrap();
+    write!(
+        &mut message,
+        "DM-Failed-Version: {}\n",
+        status.deployed_version
+    )
+    .unwrap();
+    write!(&mut message, "DM-Rollback-Version: {}\n", last_clean).unwrap();
+
+    let signature = Signature::now("DM Deployer", "n/a")?;
+
+    let commit = repo.commit(
+        Some("refs/dm_head"),
+        &signature,
+        &signature,
+        &message,
+        &new_tree,
+        &[&head_commit],
+    )?;
+
+    info!(
+        "Made commit {} rolling back {} in {}. Pushing...",
+        commit, resource_list, env
+    );
+
+    git::push(repo, versions_url)?;
+
+    info!("Pushed.");
+
+    Ok(Some(oid_to_id(commit)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    use common::chrono::Utc;
+    use common::deployment::RolloutStatus;
+    use git_fixture::RepoFixture;
+
+    fn failed_status(fixture: &RepoFixture) -> DeployerStatus {
+        let head = oid_to_id(fixture.get_commit("head").unwrap());
+        let clean = oid_to_id(fixture.get_commit("clean").unwrap());
+        let mut status_by_resource = HashMap::new();
+        status_by_resource.insert(
+            "foo".to_string(),
+            ResourceState::Deployed {
+                version: head,
+                expected_version: head,
+                status: RolloutStatusReason::Failed {
+                    message: "Deployment foo exceeded its progress deadline".to_string(),
+                },
+            },
+        );
+        status_by_resource.insert(
+            "bar".to_string(),
+            ResourceState::Deployed {
+                version: clean,
+                expected_version: clean,
+                status: RolloutStatusReason::Clean,
+            },
+        );
+        DeployerStatus {
+            deployed_version: head,
+            last_successfully_deployed_version: Some(clean),
+            rollout_status: RolloutStatus::Failed,
+            status_by_resource,
+            clean_since: HashMap::new(),
+        }
+    }
+
+    fn file_at_head(fixture: &RepoFixture, path: &str) -> String {
+        let head = git::get_head_commit(&fixture.repo).unwrap();
+        let entry = head.tree().unwrap().get_path(Path::new(path)).unwrap();
+        let blob = fixture.repo.find_blob(entry.id()).unwrap();
+        String::from_utf8(blob.content().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_failed_resources() {
+        let fixture = RepoFixture::from_str(include_str!("./fixtures/rollback.yaml")).unwrap();
+        let status = failed_status(&fixture);
+
+        assert_eq!(
+            failed_resources(&status),
+            vec![(
+                "foo".to_string(),
+                "Deployment foo exceeded its progress deadline".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn test_rollback_failed() {
+        let fixture = RepoFixture::from_str(include_str!("./fixtures/rollback.yaml")).unwrap();
+        fixture.set_ref("refs/dm_head", "head").unwrap();
+        let status = failed_status(&fixture);
+        let url = fixture.repo.path().to_string_lossy().into_owned();
+
+        let result = rollback_failed(&fixture.repo, &url, "prod", &status).unwrap();
+
+        assert!(result.is_some());
+        assert_eq!(
+            file_at_head(&fixture, "prod/version/foo.yaml"),
+            "version: 1\n"
+        );
+        assert_eq!(
+            file_at_head(&fixture, "prod/version/bar.yaml"),
+            "version: 2\n"
+        );
+
+        let head = git::get_head_commit(&fixture.repo).unwrap();
+        let mut zipper = TreeZipper::from(&fixture.repo, head.tree().unwrap());
+        zipper.descend("prod").unwrap();
+        let locks = Locks::load(&zipper).unwrap();
+        assert!(locks.env_lock.is_locked(Utc::now()));
+        assert!(head.message().unwrap().contains("DM-Type: Rollback\n"));
+
+        // rolling back again does nothing
+        let status = DeployerStatus {
+            deployed_version: oid_to_id(head.id()),
+            ..status
+        };
+        let result = rollback_failed(&fixture.repo, &url, "prod", &status).unwrap();
+        assert_eq!(result, None);
+    }
+}
@@ -226,7 +226,6 @@ impl RepoFixture {
     }
 
     pub fn assert_ref_matches(&self, ref_name: &str, commit_name: &str) {
-        use std::process::Command;
         let commit_id = self.repo.refname_to_id(ref_name).unwrap();
         let actual_commit = self.repo.find_commit(commit_id).unwrap();
         let expected_commit = self
@@ -241,6 +240,20 @@ impl RepoFixture {
             "parent ids did not match"
         );
 
+        self.assert_tree_matches(ref_name, commit_name);
+    }
+
+    /// Like `assert_ref_matches`, but only compares the trees, e.g. if the
+    /// ref is several commits ahead.
+    pub fn assert_tree_matches(&self, ref_name: &str, commit_name: &str) {
+        use std::process::Command;
+        let commit_id = self.repo.refname_to_id(ref_name).unwrap();
+        let actual_commit = self.repo.find_commit(commit_id).unwrap();
+        let expected_commit = self
+            .repo
+            .find_commit(self.get_commit(commit_name).unwrap())
+            .unwrap();
+
         let actual_tree = actual_commit.tree_id();
         let expected_tree = expected_commit.tree_id();
         if actual_tree != expected_tree {
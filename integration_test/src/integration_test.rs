@@ -4,14 +4,12 @@ use std::env;
 use std::error::Error as _;
 use std::fs::{self, File};
 use std::io::Write;
-use std::os::unix::process::ExitStatusExt;
 use std::path::PathBuf;
 use std::process::{Child, Command, Stdio};
 
 use failure::Error;
 use git2;
 use hyper;
-use indexmap::IndexMap;
 use nix;
 use rand::{self, Rng};
 use reqwest;
@@ -22,7 +20,7 @@ use git_fixture;
 
 use common::deployment::{AllDeployerStatus, RolloutStatus};
 use common::repo::oid_to_id;
-use common::transitions::TransitionStatusInfo;
+use common::transitions::AllTransitionStatus;
 
 pub struct IntegrationTest {
     executable_root: PathBuf,
@@ -319,6 +317,25 @@ impl IntegrationTest {
         panic!("wait_ready timed out");
     }
 
+    /// Calls the webhook endpoints of all services, like the git server
+    /// would after a push to the versions repo.
+    pub fn notify_push(&mut self) -> &mut Self {
+        let client = reqwest::Client::new();
+        for (service, _) in &self.processes {
+            let path = match service {
+                TestService::Aggregator => "api/trigger",
+                _ => "trigger",
+            };
+            let url = format!("http://127.0.0.1:{}/{}", self.get_port(*service), path);
+            client
+                .post(&url)
+                .send()
+                .and_then(|r| r.error_for_status())
+                .unwrap();
+        }
+        self
+    }
+
     pub fn get_service_url(&self, namespace: &str, svc: &str) -> String {
         let mut namespace = namespace.to_owned();
         namespace.push_str(&self.suffix);
@@ -493,9 +510,7 @@ impl IntegrationTest {
         for (name, mut child) in self.processes.drain(..) {
             terminate_child(&child).unwrap();
             let status = child.wait().unwrap();
-            // TODO implement proper signal handling in the services, then
-            // remove the handling for signal 15 here
-            if !status.success() && status.signal() != Some(15) {
+            if !status.success() {
                 panic!("Process {:?} exited with code {}", name, status)
             }
         }
@@ -517,7 +532,7 @@ fn get_deployer_status(url: &str) -> Result<AllDeployerStatus, Error> {
     Ok(reqwest::get(url)?.error_for_status()?.json()?)
 }
 
-fn get_transitioner_status(url: &str) -> Result<IndexMap<String, TransitionStatusInfo>, Error> {
+fn get_transitioner_status(url: &str) -> Result<AllTransitionStatus, Error> {
     Ok(reqwest::get(url)?.error_for_status()?.json()?)
 }
 
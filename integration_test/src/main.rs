@@ -22,9 +22,10 @@ fn main() -> Result<(), Error> {
         .connect_to_aggregator_socket()
         .wait_transition("prod", 1);
     fixture.apply("refs/heads/master", "c1");
-    test.wait_transition("prod", 2);
+    test.notify_push().wait_transition("prod", 2);
     fixture.apply("refs/heads/master", "c2");
-    test.wait_transition("prod", 3)
+    test.notify_push()
+        .wait_transition("prod", 3)
         .wait_env_rollout_done("prod");
 
     eprintln!("Playground running");
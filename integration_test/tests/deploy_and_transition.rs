@@ -27,7 +27,8 @@ fn minikube_deploy_and_transition() {
 
     // update service
     fixture.apply("refs/heads/master", "head2").unwrap();
-    test.wait_env_rollout_done("dev")
+    test.notify_push()
+        .wait_env_rollout_done("dev")
         .wait_transition("prod", 2)
         .wait_env_rollout_done("prod");
     let url = format!("{}/answer", test.get_service_url("prod-", "s1-service"));
@@ -16,7 +16,8 @@ fn deploy_and_transition_mock() {
 
     // update service
     fixture.apply("refs/heads/master", "head2").unwrap();
-    test.wait_env_rollout_done("dev")
+    test.notify_push()
+        .wait_env_rollout_done("dev")
         .wait_transition("prod", 2)
         .wait_env_rollout_done("prod");
 
@@ -24,7 +24,7 @@ fn minikube_deploy_k8s() {
 
     // update service
     fixture.set_ref("refs/heads/master", "head2").unwrap();
-    test.wait_env_rollout_done("dev");
+    test.notify_push().wait_env_rollout_done("dev");
     let url = format!("{}/answer", test.get_service_url("dev-", "s1-service"));
     eprintln!("Requesting {}...", url);
     let response = retrying_request(|| reqwest::get(&url))
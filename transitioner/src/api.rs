@@ -1,24 +1,114 @@
+use std::io;
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
+use chrono::Utc;
+use futures::{
+    sync::{mpsc, oneshot},
+    Future, Stream,
+};
+use log::error;
 use serde_json::json;
 use warp::{self, Filter};
 
+use common::shutdown::Shutdown;
+use common::transitions::TransitionerStatus;
+use common::watch::{Broadcast, Update};
+
+use super::explain;
 use super::ServiceState;
 
+/// Idle event streams send a keep-alive event after this time, so that
+/// clients notice broken connections and can check for shutdown.
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(10);
+
 fn health(_state: Arc<ServiceState>) -> impl warp::Reply {
     warp::reply::json(&json!({}))
 }
 
+/// Returns the status of all transitions. The config error is only part of
+/// the status events, to keep the shape of this response.
 fn status(state: Arc<ServiceState>) -> impl warp::Reply {
-    let latest_status = state.transition_status.lock().unwrap().clone();
-    warp::reply::json(&latest_status)
+    warp::reply::json(&state.status.get().transitions)
+}
+
+/// Streams the status as server-sent events: first the current status,
+/// unless the client already has it according to `Last-Event-ID`, and then
+/// every change.
+fn status_events(
+    state: Arc<ServiceState>,
+    broadcast: Arc<Broadcast<TransitionerStatus>>,
+    last_event_id: Option<String>,
+    sse: warp::sse::Sse,
+) -> impl warp::Reply {
+    let (tx, rx) = mpsc::unbounded();
+    // ids of an earlier instance or unparseable ones get the current status
+    let last_event_id = last_event_id.and_then(|id| id.parse().ok());
+    broadcast.subscribe(&state.status, last_event_id, move |update| {
+        let (id, event, data) = match update {
+            Update::Changed(id, status) => match serde_json::to_string(status) {
+                Ok(data) => (id, "status", data),
+                Err(e) => {
+                    error!("Serializing the status failed: {}", e);
+                    return false;
+                }
+            },
+            Update::KeepAlive(id) => (id, "keep-alive", String::new()),
+        };
+        let event = (
+            warp::sse::id(id.to_string()),
+            warp::sse::event(event),
+            warp::sse::data(data),
+        );
+        // fails once the client has disconnected
+        tx.unbounded_send(event).is_ok()
+    });
+    sse.reply(rx.map_err(|()| io::Error::new(io::ErrorKind::Other, "status stream failed")))
+}
+
+/// Explains what the transition would do if it ran now.
+fn explain(
+    name: String,
+    state: Arc<ServiceState>,
+) -> impl Future<Item = impl warp::Reply, Error = warp::Rejection> {
+    let (tx, rx) = oneshot::channel();
+    thread::spawn(move || {
+        let _ = tx.send(explain::explain_configured(&state, &name, Utc::now()));
+    });
+    rx.map_err(warp::reject::custom)
+        .and_then(|result| match result {
+            Ok(Some(explanation)) => Ok(warp::reply::json(&explanation)),
+            Ok(None) => Err(warp::reject::not_found()),
+            Err(e) => {
+                error!("Explaining transition failed: {}", e);
+                Err(warp::reject::custom(e.compat()))
+            }
+        })
 }
 
-pub fn start(service_state: Arc<ServiceState>) {
+/// Called by webhooks when the versions repo changed.
+fn trigger(state: Arc<ServiceState>) -> impl warp::Reply {
+    state.trigger.request_fetch();
+    warp::reply()
+}
+
+pub fn start(service_state: Arc<ServiceState>, shutdown: &Shutdown) -> thread::JoinHandle<()> {
+    let (shutdown_tx, shutdown_rx) = oneshot::channel();
+    let service_state_1 = service_state.clone();
+    shutdown.on_shutdown(move || {
+        // end the event streams so the server can shut down
+        service_state_1.status.close();
+        let _ = shutdown_tx.send(());
+    });
+    let broadcast = Arc::new(Broadcast::new());
+    let service_state_2 = service_state.clone();
+    let broadcast_1 = broadcast.clone();
+    thread::spawn(move || broadcast_1.run(&service_state_2.status, KEEP_ALIVE_INTERVAL));
     thread::spawn(move || {
         let port = service_state.env.api_port.unwrap_or(9001);
         let state = warp::any().map(move || service_state.clone());
+        let broadcast = warp::any().map(move || broadcast.clone());
         let health = warp::path("health")
             .and(warp::path::end())
             .and(warp::get2())
@@ -27,9 +117,32 @@ pub fn start(service_state: Arc<ServiceState>) {
         let status = warp::path("status")
             .and(warp::path::end())
             .and(warp::get2())
-            .and(state)
+            .and(state.clone())
             .map(status);
-        let routes = health.or(status);
-        warp::serve(routes).run(([0, 0, 0, 0], port));
-    });
+        let status_events = warp::path("status")
+            .and(warp::path("events"))
+            .and(warp::path::end())
+            .and(warp::get2())
+            .and(state.clone())
+            .and(broadcast)
+            .and(warp::sse::last_event_id::<String>())
+            .and(warp::sse())
+            .map(status_events);
+        let explain = warp::path("transitions")
+            .and(warp::path::param())
+            .and(warp::path("explain"))
+            .and(warp::path::end())
+            .and(warp::get2())
+            .and(state.clone())
+            .and_then(explain);
+        let trigger = warp::path("trigger")
+            .and(warp::path::end())
+            .and(warp::post2())
+            .and(state)
+            .map(trigger);
+        let routes = health.or(status).or(status_events).or(explain).or(trigger);
+        let (_, server) = warp::serve(routes)
+            .bind_with_graceful_shutdown(([0, 0, 0, 0], port), shutdown_rx.map_err(|_| ()));
+        tokio::run(server);
+    })
 }
@@ -0,0 +1,214 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write;
+use std::path::Path;
+
+use failure::Error;
+use git2::{Commit, Oid, Repository, Sort, Tree, TreeWalkMode, TreeWalkResult};
+
+/// A resource whose version file a transition changes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResourceChange {
+    pub resource: String,
+    pub old_version: Option<Oid>,
+    pub new_version: Oid,
+}
+
+/// Describes the changes of a transition for its commit message: one line
+/// with the old and new version per resource, then the change logs of the
+/// commits that introduced the new versions.
+pub fn describe_changes(
+    repo: &Repository,
+    head: &Commit<'_>,
+    changes: &[ResourceChange],
+) -> Result<String, Error> {
+    let mut description = String::new();
+    for change in changes {
+        let old_version = match change.old_version {
+            Some(oid) => version_name(repo, oid)?,
+            None => "(none)".to_string(),
+        };
+        writeln!(
+            &mut description,
+            "- {}: {} -> {}",
+            change.resource,
+            old_version,
+            version_name(repo, change.new_version)?
+        )
+        .unwrap();
+    }
+
+    let new_versions = changes.iter().map(|c| c.new_version).collect();
+    let introduced_in = find_introducing_commits(repo, head, &new_versions)?;
+    let mut seen_commits = HashSet::new();
+    for change in changes {
+        let commit_id = match introduced_in.get(&change.new_version) {
+            Some(id) => *id,
+            None => continue,
+        };
+        if !seen_commits.insert(commit_id) {
+            continue;
+        }
+        let commit = repo.find_commit(commit_id)?;
+        if let Some(change_log) = commit.message().and_then(change_log) {
+            write!(
+                &mut description,
+                "\n{} {}:\n{}\n",
+                change.resource,
+                version_name(repo, change.new_version)?,
+                change_log
+            )
+            .unwrap();
+        }
+    }
+
+    Ok(description)
+}
+
+/// The `version` field of a version file, or the abbreviated blob id if
+/// there is none.
+pub fn version_name(repo: &Repository, oid: Oid) -> Result<String, Error> {
+    let blob = repo.find_blob(oid)?;
+    let content: Option<HashMap<String, String>> = serde_yaml::from_slice(blob.content()).ok();
+    Ok(content
+        .and_then(|mut c| c.remove("version"))
+        .unwrap_or_else(|| oid.to_string()[..8].to_string()))
+}
+
+/// Finds, for each of the given version file blobs, the commit reachable
+/// from `head` that put it into an env where no env had it before. The walk
+/// stops once all of them are found, which is usually a few commits back.
+fn find_introducing_commits(
+    repo: &Repository,
+    head: &Commit<'_>,
+    versions: &HashSet<Oid>,
+) -> Result<HashMap<Oid, Oid>, Error> {
+    let mut result = HashMap::new();
+    let mut remaining = versions.clone();
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL);
+    revwalk.push(head.id())?;
+    for oid in revwalk {
+        if remaining.is_empty() {
+            break;
+        }
+        let commit = repo.find_commit(oid?)?;
+        let parent_tree = match commit.parents().next() {
+            Some(parent) => Some(parent.tree()?),
+            None => None,
+        };
+        let diff = repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+        for delta in diff.deltas() {
+            let new_file = delta.new_file();
+            let id = new_file.id();
+            if !remaining.contains(&id) || !new_file.path().map_or(false, is_version_file) {
+                continue;
+            }
+            // older commits come later and win, e.g. the commit that added
+            // the version over a transition that mirrored it
+            result.insert(id, commit.id());
+            let known_before = match &parent_tree {
+                Some(tree) => has_version_file(tree, id)?,
+                None => false,
+            };
+            if !known_before {
+                remaining.remove(&id);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Whether the blob is a version file of some env in the tree.
+fn has_version_file(tree: &Tree<'_>, version: Oid) -> Result<bool, Error> {
+    let mut found = false;
+    tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+        let depth = Path::new(root).components().count();
+        if found || (depth == 1 && entry.name() != Some("version")) {
+            TreeWalkResult::Skip
+        } else {
+            found = depth > 1 && entry.id() == version;
+            TreeWalkResult::Ok
+        }
+    })?;
+    Ok(found)
+}
+
+fn is_version_file(path: &Path) -> bool {
+    path.components()
+        .nth(1)
+        .map_or(false, |c| c.as_os_str() == "version")
+}
+
+/// The body of a commit message, without the header and trailers.
+fn change_log(message: &str) -> Option<String> {
+    let mut paragraphs: Vec<&str> = message
+        .split("\n\n")
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .skip(1)
+        .collect();
+    if let Some(last) = paragraphs.last() {
+        if last.lines().all(is_trailer) {
+            paragraphs.pop();
+        }
+    }
+    if paragraphs.is_empty() {
+        None
+    } else {
+        Some(paragraphs.join("\n\n"))
+    }
+}
+
+fn is_trailer(line: &str) -> bool {
+    match line.find(':') {
+        Some(pos) => {
+            pos > 0
+                && line[..pos]
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-')
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use git_fixture::RepoFixture;
+
+    #[test]
+    fn test_change_log() {
+        assert_eq!(change_log("Header only"), None);
+        assert_eq!(
+            change_log("New version 2 of foo\n\nFixed things.\n\nMore details.\n\nDM-Resource: foo\nDM-Version: 2\n"),
+            Some("Fixed things.\n\nMore details.".to_string())
+        );
+        assert_eq!(
+            change_log("Mirroring dev to prod\n\nDM-Transition: prod\n"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_find_introducing_commits() {
+        let fixture =
+            RepoFixture::from_str(include_str!("./fixtures/change_log_mirrored.yaml")).unwrap();
+        let repo = &fixture.repo;
+        let head = repo
+            .find_commit(fixture.get_commit("head").unwrap())
+            .unwrap();
+        let version = head
+            .tree()
+            .unwrap()
+            .get_path(Path::new("dev/version/foo.yaml"))
+            .unwrap()
+            .id();
+        let versions = vec![version].into_iter().collect();
+
+        let introduced_in = find_introducing_commits(repo, &head, &versions).unwrap();
+        assert_eq!(
+            introduced_in[&version],
+            fixture.get_commit("introduced").unwrap()
+        );
+    }
+}
@@ -1,7 +1,12 @@
-use failure::Error;
+use std::str::FromStr;
+
+use cron::Schedule;
+use failure::{format_err, Error};
 use indexmap::IndexMap;
 use serde_derive::Deserialize;
 
+use crate::precondition::Precondition;
+
 #[derive(Debug, Default, Clone, Deserialize)]
 pub struct Config {
     pub transitions: IndexMap<String, super::Transition>,
@@ -9,6 +14,21 @@ pub struct Config {
 
 impl Config {
     pub fn load(data: &[u8]) -> Result<Config, Error> {
-        Ok(serde_yaml::from_slice(data)?)
+        let config: Config = serde_yaml::from_slice(data)?;
+        for (name, transition) in &config.transitions {
+            if let Some(schedule) = &transition.schedule {
+                Schedule::from_str(schedule).map_err(|e| {
+                    format_err!("invalid schedule {:?} for {}: {}", schedule, name, e)
+                })?;
+            }
+            for precondition in &transition.preconditions {
+                if let Precondition::TimeWindow(window) = precondition {
+                    window
+                        .parse()
+                        .map_err(|e| format_err!("invalid time window for {}: {}", name, e))?;
+                }
+            }
+        }
+        Ok(config)
     }
 }
@@ -0,0 +1,68 @@
+//! Communication with the deployer: preconditions depend on its status, so
+//! the transitioner is woken up whenever it changes, and the deployer is asked
+//! to fetch new commits right after a transition.
+
+use std::io::BufReader;
+use std::sync::Arc;
+use std::thread;
+
+use failure::Error;
+use log::{debug, warn};
+
+use common::event_stream;
+use common::shutdown::Shutdown;
+
+use super::ServiceState;
+
+fn connect(
+    service_state: &ServiceState,
+    url: &str,
+    last_event_id: Option<&str>,
+) -> Result<BufReader<reqwest::Response>, Error> {
+    let mut request = service_state
+        .client
+        .get(url)
+        .header("Accept", "text/event-stream");
+    if let Some(id) = last_event_id {
+        request = request.header("Last-Event-ID", id);
+    }
+    Ok(BufReader::new(request.send()?.error_for_status()?))
+}
+
+/// Starts a thread that triggers the main loop whenever the deployer status
+/// changes, if a deployer url is configured.
+pub fn start(
+    service_state: Arc<ServiceState>,
+    shutdown: Arc<Shutdown>,
+) -> Option<thread::JoinHandle<()>> {
+    let url = format!("{}/status/events", service_state.env.deployer_url.as_ref()?);
+    Some(thread::spawn(move || {
+        event_stream::follow(
+            &url,
+            &shutdown,
+            |last_event_id| connect(&service_state, &url, last_event_id),
+            |event, _data| {
+                if event == "status" {
+                    debug!("Deployer status changed");
+                    service_state.trigger.notify();
+                }
+                Ok(())
+            },
+        );
+    }))
+}
+
+/// Asks the deployer to fetch the versions repo, so that it doesn't need to
+/// wait for its poll interval to pick up a new commit.
+pub fn request_fetch(service_state: &ServiceState) {
+    if let Some(deployer_url) = &service_state.env.deployer_url {
+        let result = service_state
+            .client
+            .post(&format!("{}/trigger", deployer_url))
+            .send()
+            .and_then(|r| r.error_for_status());
+        if let Err(e) = result {
+            warn!("Asking the deployer to fetch failed: {}", e);
+        }
+    }
+}
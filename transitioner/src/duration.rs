@@ -0,0 +1,103 @@
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::Duration;
+use failure::{bail, format_err, Error};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// A duration in the config, written like `90s`, `30m`, `2h` or `1d12h`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ConfigDuration(pub Duration);
+
+impl FromStr for ConfigDuration {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ConfigDuration, Error> {
+        if s.trim().is_empty() {
+            bail!("invalid duration {:?}: empty", s);
+        }
+        let mut total = Duration::zero();
+        let mut number = String::new();
+        for c in s.trim().chars() {
+            if c.is_ascii_digit() {
+                number.push(c);
+                continue;
+            }
+            if number.is_empty() {
+                bail!("invalid duration {:?}: expected a number before {:?}", s, c);
+            }
+            let n: i64 = number
+                .parse()
+                .map_err(|_| format_err!("invalid duration {:?}: number too large", s))?;
+            number.clear();
+            total = total
+                + match c {
+                    'd' => Duration::days(n),
+                    'h' => Duration::hours(n),
+                    'm' => Duration::minutes(n),
+                    's' => Duration::seconds(n),
+                    _ => bail!("invalid duration {:?}: unknown unit {:?}", s, c),
+                };
+        }
+        if !number.is_empty() {
+            bail!("invalid duration {:?}: missing unit after {}", s, number);
+        }
+        Ok(ConfigDuration(total))
+    }
+}
+
+impl<'de> Deserialize<'de> for ConfigDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<ConfigDuration, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+impl Serialize for ConfigDuration {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl fmt::Display for ConfigDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut seconds = self.0.num_seconds();
+        if seconds <= 0 {
+            return write!(f, "0s");
+        }
+        for (unit, unit_seconds) in &[("d", 86400), ("h", 3600), ("m", 60), ("s", 1)] {
+            if seconds >= *unit_seconds {
+                write!(f, "{}{}", seconds / unit_seconds, unit)?;
+                seconds %= unit_seconds;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(s: &str) -> Duration {
+        s.parse::<ConfigDuration>().unwrap().0
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse("90s"), Duration::seconds(90));
+        assert_eq!(parse("30m"), Duration::minutes(30));
+        assert_eq!(parse("1d12h"), Duration::hours(36));
+        assert!("".parse::<ConfigDuration>().is_err());
+        assert!("10".parse::<ConfigDuration>().is_err());
+        assert!("h".parse::<ConfigDuration>().is_err());
+        assert!("3w".parse::<ConfigDuration>().is_err());
+    }
+
+    #[test]
+    fn test_display_duration() {
+        assert_eq!(ConfigDuration(Duration::seconds(90)).to_string(), "1m30s");
+        assert_eq!(ConfigDuration(Duration::hours(36)).to_string(), "1d12h");
+        assert_eq!(ConfigDuration(Duration::zero()).to_string(), "0s");
+    }
+}
@@ -0,0 +1,112 @@
+//! Explains what a transition would do if it ran now, without committing
+//! anything.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use failure::Error;
+use git2::Repository;
+use serde_derive::Serialize;
+
+use common::transitions::TransitionResult;
+
+use super::change_log::{version_name, ResourceChange};
+use super::precondition::{Precondition, PreconditionResult};
+use super::{plan_transition, Plan, ServiceState, Transition};
+
+#[derive(Debug, Default, Serialize)]
+pub struct TransitionExplanation {
+    /// When the transition is scheduled to run, if it is.
+    pub scheduled: Option<DateTime<Utc>>,
+    /// When it would be scheduled to run next after running now.
+    pub next_scheduled: Option<DateTime<Utc>>,
+    /// The resources it would change.
+    pub changes: Vec<PlannedChange>,
+    /// The resources it would change if they weren't locked in the target
+    /// env.
+    pub locked: Vec<String>,
+    /// The resources that preconditions would hold back, with the reason.
+    pub held: BTreeMap<String, String>,
+    /// The preconditions that were checked, in order. The ones after a
+    /// precondition that stops the transition aren't checked.
+    pub preconditions: Vec<PreconditionReport>,
+    /// The result of the transition, unless it would commit its changes (if
+    /// the preconditions that weren't evaluated let it).
+    pub result: Option<TransitionResult>,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+pub struct PlannedChange {
+    pub resource: String,
+    /// The version in the target env, if there is one.
+    pub old_version: Option<String>,
+    pub new_version: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PreconditionReport {
+    pub precondition: Precondition,
+    /// `None` if the precondition has side effects, and so wasn't evaluated.
+    #[serde(flatten)]
+    pub result: Option<PreconditionResult>,
+}
+
+/// The changes with the version names.
+pub fn planned_changes(
+    repo: &Repository,
+    changes: &[ResourceChange],
+) -> Result<Vec<PlannedChange>, Error> {
+    changes
+        .iter()
+        .map(|c| {
+            Ok(PlannedChange {
+                resource: c.resource.clone(),
+                old_version: c.old_version.map(|v| version_name(repo, v)).transpose()?,
+                new_version: version_name(repo, c.new_version)?,
+            })
+        })
+        .collect()
+}
+
+/// Runs the transition up to the commit. Preconditions are checked just like
+/// when the transition runs, except for the ones with side effects, like
+/// starting a job, which are assumed to pass.
+pub fn explain_transition(
+    name: &str,
+    transition: &Transition,
+    repo: &Repository,
+    service_state: &ServiceState,
+    now: DateTime<Utc>,
+) -> Result<TransitionExplanation, Error> {
+    let mut explanation = TransitionExplanation::default();
+    if let Plan::Done(result) = plan_transition(
+        name,
+        transition,
+        repo,
+        service_state,
+        now,
+        true,
+        &mut explanation,
+    )? {
+        explanation.result = Some(result);
+    }
+    Ok(explanation)
+}
+
+/// Explains the configured transition with the given name, if there is one,
+/// on the checked out resource repo.
+pub fn explain_configured(
+    service_state: &ServiceState,
+    name: &str,
+    now: DateTime<Utc>,
+) -> Result<Option<TransitionExplanation>, Error> {
+    let transition = {
+        let config = service_state.config.read().expect("RwLock poisoned");
+        match config.transitions.get(name) {
+            Some(transition) => transition.clone(),
+            None => return Ok(None),
+        }
+    };
+    let repo = Repository::open(&service_state.env.common.versions_checkout_path)?;
+    explain_transition(name, &transition, &repo, service_state, now).map(Some)
+}
@@ -0,0 +1,166 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use failure::{Error, ResultExt};
+use git2::{Repository, Tree};
+use serde_derive::Deserialize;
+
+/// Selects version files for a transition. A filter matches if all of its
+/// conditions match.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResourceFilter {
+    /// A glob pattern for the path of the version file below `version/`.
+    /// `*` matches within one directory, `**` across directories.
+    pub path: Option<String>,
+    /// A glob pattern for the resource name.
+    pub resource: Option<String>,
+    /// Labels that the resource's base manifest needs to have.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+/// What a filter can look at.
+pub struct Candidate<'a> {
+    pub path: &'a str,
+    pub resource: &'a str,
+    pub labels: &'a BTreeMap<String, String>,
+}
+
+impl ResourceFilter {
+    pub fn matches(&self, candidate: &Candidate<'_>) -> bool {
+        self.path
+            .as_ref()
+            .map_or(true, |p| glob_matches(p, candidate.path))
+            && self
+                .resource
+                .as_ref()
+                .map_or(true, |r| glob_matches(r, candidate.resource))
+            && self
+                .labels
+                .iter()
+                .all(|(k, v)| candidate.labels.get(k) == Some(v))
+    }
+}
+
+/// Whether a transition with these filters applies to the candidate: it has
+/// to match one of the includes (if there are any), and none of the excludes.
+pub fn selects(
+    include: &[ResourceFilter],
+    exclude: &[ResourceFilter],
+    candidate: &Candidate<'_>,
+) -> bool {
+    (include.is_empty() || include.iter().any(|f| f.matches(candidate)))
+        && !exclude.iter().any(|f| f.matches(candidate))
+}
+
+pub fn needs_labels(filters: &[ResourceFilter]) -> bool {
+    filters.iter().any(|f| !f.labels.is_empty())
+}
+
+/// Reads the labels from the base manifest of a version file in the given
+/// env. Returns no labels if there is no base file or it isn't yaml.
+pub fn base_labels(
+    repo: &Repository,
+    tree: &Tree<'_>,
+    env: &str,
+    path: &Path,
+) -> Result<BTreeMap<String, String>, Error> {
+    let entry = match tree.get_path(&Path::new(env).join("base").join(path)) {
+        Ok(entry) => entry,
+        Err(_) => return Ok(BTreeMap::new()),
+    };
+    let blob = match entry.to_object(repo)?.into_blob() {
+        Ok(blob) => blob,
+        Err(_) => return Ok(BTreeMap::new()),
+    };
+    if !path.to_string_lossy().ends_with(".yaml") {
+        return Ok(BTreeMap::new());
+    }
+    let manifest: serde_yaml::Value = serde_yaml::from_slice(blob.content())
+        .with_context(|_| format!("parsing base file for {:?} in {} failed", path, env))?;
+    let labels = manifest
+        .get("metadata")
+        .and_then(|m| m.get("labels"))
+        .and_then(|l| l.as_mapping());
+    Ok(labels
+        .into_iter()
+        .flat_map(|l| l.iter())
+        .filter_map(|(k, v)| Some((k.as_str()?.to_string(), v.as_str()?.to_string())))
+        .collect())
+}
+
+pub fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    glob_matches_chars(&pattern, &text)
+}
+
+fn glob_matches_chars(pattern: &[char], text: &[char]) -> bool {
+    if pattern.is_empty() {
+        return text.is_empty();
+    }
+    match pattern[0] {
+        '*' if pattern.get(1) == Some(&'*') => {
+            let rest = &pattern[2..];
+            // `**/` can also match no directories at all
+            (rest.first() == Some(&'/') && glob_matches_chars(&rest[1..], text))
+                || (0..=text.len()).any(|i| glob_matches_chars(rest, &text[i..]))
+        }
+        '*' => {
+            let segment_len = text.iter().position(|c| *c == '/').unwrap_or(text.len());
+            (0..=segment_len).any(|i| glob_matches_chars(&pattern[1..], &text[i..]))
+        }
+        '?' => !text.is_empty() && text[0] != '/' && glob_matches_chars(&pattern[1..], &text[1..]),
+        c => text.first() == Some(&c) && glob_matches_chars(&pattern[1..], &text[1..]),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("payments/**", "payments/api.yaml"));
+        assert!(glob_matches("payments/**", "payments/sub/api.yaml"));
+        assert!(!glob_matches("payments/**", "frontend/api.yaml"));
+        assert!(glob_matches("payments/*.yaml", "payments/api.yaml"));
+        assert!(!glob_matches("payments/*.yaml", "payments/sub/api.yaml"));
+        assert!(glob_matches("**/api.yaml", "api.yaml"));
+        assert!(glob_matches("**/api.yaml", "payments/sub/api.yaml"));
+        assert!(glob_matches("pay?ents-*", "payments-api"));
+        assert!(!glob_matches("payments", "payments-api"));
+    }
+
+    #[test]
+    fn test_selects() {
+        let filter = |s: &str| -> ResourceFilter { serde_yaml::from_str(s).unwrap() };
+        let mut labels = BTreeMap::new();
+        labels.insert("team".to_string(), "payments".to_string());
+        let candidate = Candidate {
+            path: "payments/api.yaml",
+            resource: "api",
+            labels: &labels,
+        };
+
+        assert!(selects(&[], &[], &candidate));
+        assert!(selects(&[filter("path: payments/**")], &[], &candidate));
+        assert!(!selects(&[filter("resource: web")], &[], &candidate));
+        assert!(selects(
+            &[filter("labels: {team: payments}")],
+            &[filter("resource: web")],
+            &candidate
+        ));
+        assert!(!selects(
+            &[],
+            &[filter("{path: payments/**, labels: {team: payments}}")],
+            &candidate
+        ));
+        assert!(selects(
+            &[],
+            &[filter("{path: payments/**, labels: {team: other}}")],
+            &candidate
+        ));
+    }
+}
@@ -0,0 +1,263 @@
+//! The `Http` precondition, which asks an external service, e.g. a CI
+//! server, whether a transition may go ahead.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::thread;
+use std::time::Duration;
+
+use failure::{bail, format_err, Error};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+
+use super::duration::ConfigDuration;
+use super::precondition::PreconditionResult;
+use super::PendingTransitionInfo;
+
+/// How long to wait before retrying a failed request.
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpConfig {
+    /// The URL to post the pending transition to.
+    pub url: String,
+    /// Additional headers, e.g. for authentication.
+    #[serde(default)]
+    pub headers: BTreeMap<String, HeaderValue>,
+    #[serde(default = "default_timeout")]
+    pub timeout: ConfigDuration,
+    /// How often to retry if the request fails or the server returns an
+    /// error.
+    #[serde(default)]
+    pub retries: u32,
+}
+
+fn default_timeout() -> ConfigDuration {
+    ConfigDuration(chrono::Duration::seconds(30))
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HeaderValue {
+    Value(String),
+    /// The value of an environment variable of the transitioner, so that
+    /// secrets don't need to be in the resource repo.
+    FromEnv {
+        from_env: String,
+    },
+}
+
+impl HeaderValue {
+    pub fn resolve(&self) -> Result<String, Error> {
+        match self {
+            HeaderValue::Value(value) => Ok(value.clone()),
+            HeaderValue::FromEnv { from_env } => env::var(from_env)
+                .map_err(|_| format_err!("environment variable {} is not set", from_env)),
+        }
+    }
+}
+
+/// What the service answers.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "result")]
+enum HttpCheckResponse {
+    Success,
+    Blocked {
+        #[serde(default)]
+        message: String,
+    },
+    Failed {
+        #[serde(default)]
+        message: String,
+    },
+}
+
+pub fn check_http(
+    transition: &PendingTransitionInfo,
+    config: &HttpConfig,
+) -> Result<PreconditionResult, Error> {
+    let headers = config
+        .headers
+        .iter()
+        .map(|(name, value)| Ok((name.as_str(), value.resolve()?)))
+        .collect::<Result<Vec<_>, Error>>()?;
+    let client = reqwest::Client::builder()
+        .timeout(config.timeout.0.to_std()?)
+        .build()?;
+
+    let mut attempt = 0;
+    let response = loop {
+        match post(&client, &config.url, &headers, transition) {
+            Ok(response) => break response,
+            Err(e) if attempt < config.retries => {
+                warn!("Http check against {} failed, retrying: {}", config.url, e);
+                attempt += 1;
+                thread::sleep(RETRY_DELAY);
+            }
+            Err(e) => return Err(e),
+        }
+    };
+
+    Ok(match response {
+        HttpCheckResponse::Success => {
+            info!("Http check ok");
+            PreconditionResult::Success
+        }
+        HttpCheckResponse::Blocked { message } => {
+            info!("Http check blocks transition: {}", message);
+            PreconditionResult::Blocked { message }
+        }
+        HttpCheckResponse::Failed { message } => {
+            warn!("Http check failed: {}", message);
+            PreconditionResult::Failed { message }
+        }
+    })
+}
+
+fn post(
+    client: &reqwest::Client,
+    url: &str,
+    headers: &[(&str, String)],
+    transition: &PendingTransitionInfo,
+) -> Result<HttpCheckResponse, Error> {
+    let mut request = client.post(url).json(transition);
+    for (name, value) in headers {
+        request = request.header(*name, value.as_str());
+    }
+    let mut response = request.send()?;
+    if !response.status().is_success() {
+        bail!(
+            "{} returned {}: {}",
+            url,
+            response.status(),
+            response.text().unwrap_or_default()
+        );
+    }
+    response
+        .json()
+        .map_err(|e| format_err!("invalid response from {}: {}", url, e))
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    /// Answers each request with the next of the given responses, and sends
+    /// the requests it got to the returned channel.
+    pub(crate) fn stub_server(
+        responses: Vec<(u16, &'static str)>,
+    ) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/check", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for (status, body) in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request = String::new();
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line.to_lowercase().starts_with("content-length:") {
+                        content_length = line["content-length:".len()..].trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut content = vec![0; content_length];
+                reader.read_exact(&mut content).unwrap();
+                request.push_str(&String::from_utf8(content).unwrap());
+                tx.send(request).unwrap();
+                write!(
+                    reader.get_mut(),
+                    "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    status,
+                    body.len(),
+                    body
+                )
+                .unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    pub(crate) fn transition() -> PendingTransitionInfo {
+        PendingTransitionInfo {
+            source: "dev".to_string(),
+            target: "prod".to_string(),
+            current_version: "0000000000000000000000000000000000000001".parse().unwrap(),
+            resources: vec!["api".to_string()],
+            versions: vec![("api".to_string(), "23".to_string())]
+                .into_iter()
+                .collect(),
+            change_set: "22817d2a9c7fc1f62d5670ca1e44948446543973".parse().unwrap(),
+            approval: None,
+        }
+    }
+
+    fn config(url: String, retries: u32) -> HttpConfig {
+        let mut headers = BTreeMap::new();
+        headers.insert(
+            "Authorization".to_string(),
+            HeaderValue::Value("Bearer secret".to_string()),
+        );
+        HttpConfig {
+            url,
+            headers,
+            timeout: default_timeout(),
+            retries,
+        }
+    }
+
+    #[test]
+    fn test_http_check() {
+        let (url, requests) = stub_server(vec![
+            (500, "{}"),
+            (200, r#"{"result": "Blocked", "message": "tests running"}"#),
+            (200, r#"{"result": "Failed", "message": "tests failed"}"#),
+            (200, r#"{"result": "Success"}"#),
+        ]);
+
+        assert!(check_http(&transition(), &config(url.clone(), 0)).is_err());
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("POST /check "));
+        assert!(request
+            .to_lowercase()
+            .contains("authorization: bearer secret\r\n"));
+        assert!(request.contains(r#""source":"dev""#));
+        assert!(request.contains(r#""versions":{"api":"23"}"#));
+
+        assert_eq!(
+            check_http(&transition(), &config(url.clone(), 0)).unwrap(),
+            PreconditionResult::Blocked {
+                message: "tests running".to_string()
+            }
+        );
+        assert_eq!(
+            check_http(&transition(), &config(url.clone(), 0)).unwrap(),
+            PreconditionResult::Failed {
+                message: "tests failed".to_string()
+            }
+        );
+        assert_eq!(
+            check_http(&transition(), &config(url, 0)).unwrap(),
+            PreconditionResult::Success
+        );
+    }
+
+    #[test]
+    fn test_http_check_retries() {
+        let (url, _requests) = stub_server(vec![(503, "{}"), (200, r#"{"result": "Success"}"#)]);
+        assert_eq!(
+            check_http(&transition(), &config(url, 1)).unwrap(),
+            PreconditionResult::Success
+        );
+    }
+}
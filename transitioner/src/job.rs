@@ -0,0 +1,373 @@
+//! Runs Kubernetes Jobs for the `Job` precondition, e.g. smoke tests against
+//! the source env. Each Job is named after the change set it tests, so a
+//! change set is only tested once.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use failure::{bail, format_err, Error, ResultExt};
+use git2::{Repository, Tree};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use common::kubectl::{kubectl, KubeTarget};
+use common::repo::{id_to_oid, Id};
+
+use super::precondition::PreconditionResult;
+use super::{PendingTransitionInfo, ServiceState};
+
+const CHANGE_SET_LABEL: &str = "new-dm/change-set";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JobConfig {
+    /// The path of the Job manifest in the resource repo.
+    pub template: String,
+    /// Defaults to the namespace of the source env's Kubernetes deployer.
+    pub namespace: Option<String>,
+    /// Defaults to the context of the source env's Kubernetes deployer.
+    pub context: Option<String>,
+    /// How many lines of the log to report if the Job fails.
+    #[serde(default = "default_log_lines")]
+    pub log_lines: u32,
+}
+
+fn default_log_lines() -> u32 {
+    20
+}
+
+/// The result of a finished Job.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JobOutcome {
+    Succeeded,
+    Failed { message: String },
+}
+
+/// A finished Job of a transition's change set.
+#[derive(Debug, Clone)]
+pub struct JobResult {
+    source: String,
+    target: String,
+    change_set: Id,
+    outcome: JobOutcome,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct DeployersFile {
+    #[serde(default)]
+    deployers: BTreeMap<String, KubeTarget>,
+}
+
+pub fn check_job(
+    transition: &PendingTransitionInfo,
+    config: &JobConfig,
+    service_state: &ServiceState,
+    repo: &Repository,
+) -> Result<PreconditionResult, Error> {
+    let tree = repo
+        .find_commit(id_to_oid(transition.current_version))?
+        .tree()?;
+    let target = kube_target(repo, &tree, &transition.source, config)?;
+    let namespace = match &target.namespace {
+        Some(namespace) => namespace.clone(),
+        None => {
+            return Ok(PreconditionResult::Failed {
+                message: format!("no namespace known for env {}", transition.source),
+            })
+        }
+    };
+    let template = match read_file(repo, &tree, &config.template)? {
+        Some(template) => template,
+        None => {
+            return Ok(PreconditionResult::Failed {
+                message: format!("job template {} not found", config.template),
+            })
+        }
+    };
+    let (name, job) = render_job(&template, transition)
+        .with_context(|_| format!("rendering job template {} failed", config.template))?;
+
+    let key = format!("{}/{}", namespace, name);
+    let cached = {
+        let mut job_results = service_state.job_results.lock().unwrap();
+        // the jobs of the transition's earlier change sets won't be checked again
+        job_results.retain(|_, result| {
+            result.source != transition.source
+                || result.target != transition.target
+                || result.change_set == transition.change_set
+        });
+        job_results.get(&key).map(|result| result.outcome.clone())
+    };
+    let outcome = match cached {
+        Some(outcome) => outcome,
+        None => {
+            let output = kubectl(
+                &target,
+                &["get", "job", &name, "--ignore-not-found", "-o", "json"],
+                None,
+            )?;
+            if output.trim().is_empty() {
+                kubectl(&target, &["create", "-f", "-"], Some(&job.to_string()))?;
+                info!("Started job {} in {}", name, namespace);
+                return Ok(PreconditionResult::Blocked {
+                    message: format!("job {} started", name),
+                });
+            }
+            let current: Value =
+                serde_json::from_str(&output).context("parsing job status failed")?;
+            let outcome = match job_outcome(&current) {
+                Some(JobOutcome::Failed { message }) => JobOutcome::Failed {
+                    message: with_log_tail(&target, &name, message, config.log_lines),
+                },
+                Some(outcome) => outcome,
+                None => {
+                    info!("Transition waits for job {}", name);
+                    return Ok(PreconditionResult::Blocked {
+                        message: format!("job {} is running", name),
+                    });
+                }
+            };
+            service_state.job_results.lock().unwrap().insert(
+                key,
+                JobResult {
+                    source: transition.source.clone(),
+                    target: transition.target.clone(),
+                    change_set: transition.change_set,
+                    outcome: outcome.clone(),
+                },
+            );
+            outcome
+        }
+    };
+
+    Ok(match outcome {
+        JobOutcome::Succeeded => {
+            info!("Job check ok: {} succeeded", name);
+            PreconditionResult::Success
+        }
+        JobOutcome::Failed { message } => {
+            warn!("Job check failed: {}", message);
+            PreconditionResult::Failed { message }
+        }
+    })
+}
+
+/// The kubectl target for the env, from the env's deployer config and the
+/// overrides in the job config.
+fn kube_target(
+    repo: &Repository,
+    tree: &Tree<'_>,
+    env: &str,
+    config: &JobConfig,
+) -> Result<KubeTarget, Error> {
+    let deployers: DeployersFile = match read_file(repo, tree, "deployers.yaml")? {
+        Some(content) => serde_yaml::from_str(&content).context("parsing deployers.yaml failed")?,
+        None => DeployersFile::default(),
+    };
+    let mut target = deployers.deployers.get(env).cloned().unwrap_or_default();
+    if config.namespace.is_some() {
+        target.namespace = config.namespace.clone();
+    }
+    if config.context.is_some() {
+        target.context = config.context.clone();
+    }
+    Ok(target)
+}
+
+fn read_file(repo: &Repository, tree: &Tree<'_>, path: &str) -> Result<Option<String>, Error> {
+    let entry = match tree.get_path(Path::new(path)) {
+        Ok(entry) => entry,
+        Err(_) => return Ok(None),
+    };
+    let blob = match entry.to_object(repo)?.into_blob() {
+        Ok(blob) => blob,
+        Err(_) => return Ok(None),
+    };
+    Ok(Some(
+        String::from_utf8(blob.content().to_vec())
+            .with_context(|_| format!("{} is not valid UTF-8", path))?,
+    ))
+}
+
+/// Fills in the Job template for the transition. The template's name gets
+/// the change set appended, and the placeholders `$source`, `$target`,
+/// `$source_version`, `$change_set`, `$resources` (comma-separated) and
+/// `$versions` (a JSON object with the new version of each resource) are
+/// replaced in all strings.
+fn render_job(
+    template: &str,
+    transition: &PendingTransitionInfo,
+) -> Result<(String, Value), Error> {
+    let mut job: Value = serde_yaml::from_str(template)?;
+    if job["kind"] != "Job" {
+        bail!("the template isn't a Job");
+    }
+
+    let change_set = transition.change_set.to_string();
+    let placeholders = vec![
+        ("$source_version", transition.current_version.to_string()),
+        ("$source", transition.source.clone()),
+        ("$target", transition.target.clone()),
+        ("$change_set", change_set.clone()),
+        ("$resources", transition.resources.join(",")),
+        ("$versions", serde_json::to_string(&transition.versions)?),
+    ];
+    replace_placeholders(&mut job, &placeholders);
+
+    let metadata = job
+        .get_mut("metadata")
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| format_err!("the template has no metadata"))?;
+    let base_name = metadata
+        .get("name")
+        .and_then(Value::as_str)
+        .ok_or_else(|| format_err!("the template has no name"))?;
+    // names can have at most 63 characters
+    let base_name: String = base_name.chars().take(52).collect();
+    let name = format!("{}-{}", base_name.trim_end_matches('-'), &change_set[..10]);
+    metadata.insert("name".to_string(), json!(name));
+    let labels = metadata
+        .entry("labels")
+        .or_insert_with(|| json!({}))
+        .as_object_mut()
+        .ok_or_else(|| format_err!("the template's labels aren't an object"))?;
+    labels.insert(CHANGE_SET_LABEL.to_string(), json!(change_set));
+
+    Ok((name, job))
+}
+
+fn replace_placeholders(value: &mut Value, placeholders: &[(&str, String)]) {
+    match value {
+        Value::String(s) => {
+            for (placeholder, replacement) in placeholders {
+                if s.contains(placeholder) {
+                    *s = s.replace(placeholder, replacement);
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                replace_placeholders(value, placeholders);
+            }
+        }
+        Value::Object(map) => {
+            for (_, value) in map {
+                replace_placeholders(value, placeholders);
+            }
+        }
+        Value::Null | Value::Bool(_) | Value::Number(_) => {}
+    }
+}
+
+/// The outcome of the Job, or `None` if it hasn't finished yet.
+fn job_outcome(job: &Value) -> Option<JobOutcome> {
+    let conditions = job["status"]["conditions"].as_array()?;
+    for condition in conditions {
+        if condition["status"] != "True" {
+            continue;
+        }
+        if condition["type"] == "Complete" {
+            return Some(JobOutcome::Succeeded);
+        }
+        if condition["type"] == "Failed" {
+            let message = condition["message"]
+                .as_str()
+                .or_else(|| condition["reason"].as_str())
+                .unwrap_or("job failed");
+            return Some(JobOutcome::Failed {
+                message: message.to_string(),
+            });
+        }
+    }
+    None
+}
+
+fn with_log_tail(target: &KubeTarget, name: &str, message: String, lines: u32) -> String {
+    if lines == 0 {
+        return message;
+    }
+    let job = format!("job/{}", name);
+    let tail = format!("--tail={}", lines);
+    match kubectl(target, &["logs", &job, &tail], None) {
+        Ok(logs) if !logs.trim().is_empty() => format!("{}\n\n{}", message, logs.trim_end()),
+        Ok(_) => message,
+        Err(e) => {
+            warn!("Getting the logs of job {} failed: {}", name, e);
+            message
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn transition() -> PendingTransitionInfo {
+        let mut versions = BTreeMap::new();
+        versions.insert("api".to_string(), "23".to_string());
+        PendingTransitionInfo {
+            source: "dev".to_string(),
+            target: "prod".to_string(),
+            current_version: "0000000000000000000000000000000000000001".parse().unwrap(),
+            resources: vec!["api".to_string()],
+            versions,
+            change_set: "22817d2a9c7fc1f62d5670ca1e44948446543973".parse().unwrap(),
+            approval: None,
+        }
+    }
+
+    #[test]
+    fn test_render_job() {
+        let template = r#"
+apiVersion: batch/v1
+kind: Job
+metadata:
+  name: smoke-test
+spec:
+  template:
+    spec:
+      containers:
+        - name: test
+          image: smoke-test:latest
+          args: ["--env", "$source", "--versions", "$versions"]
+"#;
+        let (name, job) = render_job(template, &transition()).unwrap();
+        assert_eq!(name, "smoke-test-22817d2a9c");
+        assert_eq!(job["metadata"]["name"], "smoke-test-22817d2a9c");
+        assert_eq!(
+            job["metadata"]["labels"][CHANGE_SET_LABEL],
+            "22817d2a9c7fc1f62d5670ca1e44948446543973"
+        );
+        assert_eq!(
+            job["spec"]["template"]["spec"]["containers"][0]["args"],
+            json!(["--env", "dev", "--versions", "{\"api\":\"23\"}"])
+        );
+
+        assert!(render_job("kind: Deployment\nmetadata: {name: x}\n", &transition()).is_err());
+    }
+
+    #[test]
+    fn test_job_outcome() {
+        assert_eq!(job_outcome(&json!({ "status": { "active": 1 } })), None);
+        assert_eq!(
+            job_outcome(&json!({
+                "status": { "conditions": [{ "type": "Complete", "status": "True" }] }
+            })),
+            Some(JobOutcome::Succeeded)
+        );
+        assert_eq!(
+            job_outcome(&json!({
+                "status": { "conditions": [{
+                    "type": "Failed",
+                    "status": "True",
+                    "reason": "BackoffLimitExceeded",
+                    "message": "Job has reached the specified backoff limit"
+                }] }
+            })),
+            Some(JobOutcome::Failed {
+                message: "Job has reached the specified backoff limit".to_string()
+            })
+        );
+    }
+}
@@ -1,35 +1,51 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Write;
 use std::path::{Path, PathBuf};
 use std::process;
 use std::str::FromStr;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
 
 use chrono::{DateTime, Utc};
 use cron::Schedule;
 use failure::{bail, Error};
-use git2::{ObjectType, Repository, Signature};
-use indexmap::IndexMap;
+use git2::{Commit, ObjectType, Oid, Repository, Signature, Tree};
 use log::{error, info};
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
+use structopt::StructOpt;
 
 use common::git::{self, TreeZipper};
 use common::repo::{oid_to_id, GitResourceRepo, Id, ResourceRepo};
+use common::shutdown::{self, Shutdown};
+use common::transition_state::{TransitionState, TransitionStates};
 use common::transitions::{
-    SkipReason, TransitionResult, TransitionRunInfo, TransitionStatusInfo,
-    TransitionSuccessfulRunInfo,
+    Approval, Approvals, Freezes, Locks, SkipReason, TransitionResult, TransitionRunInfo,
+    TransitionStatusInfo, TransitionSuccessfulRunInfo, TransitionerStatus, TRANSITIONS_FILE,
 };
+use common::watch::{self, Trigger, Watch};
 
 mod api;
+mod change_log;
 mod config;
-mod locks;
+mod deployer_watch;
+mod duration;
+mod explain;
+mod filter;
+mod http_check;
+mod job;
+mod metric;
 mod precondition;
-mod transition_state;
+mod time_window;
 
+use crate::change_log::{version_name, ResourceChange};
 use crate::config::Config;
+use crate::explain::{PreconditionReport, TransitionExplanation};
+use crate::filter::ResourceFilter;
+use crate::job::JobResult;
 use crate::precondition::{Precondition, PreconditionResult};
-use crate::transition_state::{TransitionState, TransitionStates};
+
+/// How soon to retry after running transitions failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Debug, Deserialize)]
 struct Env {
@@ -37,13 +53,26 @@ struct Env {
     common: common::Env,
     api_port: Option<u16>,
     deployer_url: Option<String>,
+    shutdown_deadline_secs: Option<u64>,
+    /// How often to fetch the versions repo if no webhook triggers a fetch.
+    poll_interval_secs: Option<u64>,
 }
 
 pub struct ServiceState {
-    config: Config,
+    config: RwLock<Config>,
     env: Env,
     client: reqwest::Client,
-    transition_status: Mutex<IndexMap<String, TransitionStatusInfo>>,
+    /// The status of all transitions. Its `config_error` is set if the
+    /// current transition config is invalid, in which case the previous one
+    /// is kept.
+    status: Watch<TransitionerStatus>,
+    /// Wakes up the main loop, either to fetch the versions repo or because
+    /// the deployer status changed.
+    trigger: Trigger,
+    /// The outcomes of finished precondition jobs, by namespace and job
+    /// name. Only those of the pending change set of each transition are
+    /// kept.
+    job_results: Mutex<HashMap<String, JobResult>>,
 }
 
 #[derive(Debug, Clone, Deserialize)]
@@ -53,6 +82,13 @@ pub struct Transition {
     #[serde(default)]
     preconditions: Vec<Precondition>,
     schedule: Option<String>,
+    /// If given, the transition only mirrors version files matching one of
+    /// these filters.
+    #[serde(default)]
+    include: Vec<ResourceFilter>,
+    /// The transition doesn't mirror version files matching any of these.
+    #[serde(default)]
+    exclude: Vec<ResourceFilter>,
 }
 
 impl Transition {
@@ -72,143 +108,532 @@ impl Transition {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct PendingTransitionInfo {
     source: String,
     target: String,
     current_version: Id,
+    /// The resources the transition would change.
+    resources: Vec<String>,
+    /// The new version of each of these resources.
+    versions: BTreeMap<String, String>,
+    /// Identifies the changes the transition would make, for approvals.
+    change_set: Id,
+    /// The latest approval of the transition, if any.
+    approval: Option<Approval>,
 }
 
-fn run_transition(
+/// A version file in the source env of a transition.
+struct VersionFile {
+    /// The path below the `version` directory.
+    path: PathBuf,
+    resource: String,
+    id: Oid,
+    filemode: i32,
+}
+
+fn collect_version_files(source: &TreeZipper<'_>) -> Result<Vec<VersionFile>, Error> {
+    let mut files = Vec::new();
+    for (path, entry) in source.walk(false) {
+        let entry = entry?;
+        if entry.kind() != Some(ObjectType::Blob) {
+            continue;
+        }
+        let name = match entry.name() {
+            Some(name) => name,
+            None => continue,
+        };
+        let resource = if name.ends_with(".yaml") {
+            &name[0..name.len() - ".yaml".len()]
+        } else if name.ends_with(".jsonnet") {
+            &name[0..name.len() - ".jsonnet".len()]
+        } else {
+            name
+        };
+        files.push(VersionFile {
+            resource: resource.to_string(),
+            path,
+            id: entry.id(),
+            filemode: entry.filemode(),
+        });
+    }
+    Ok(files)
+}
+
+/// Writes a version file into the env directory the zipper points to.
+fn insert_version_file(target: &mut TreeZipper<'_>, file: &VersionFile) -> Result<(), Error> {
+    target.descend("version")?;
+    let mut depth = 1;
+    let dir = file.path.parent().unwrap_or_else(|| Path::new(""));
+    for component in dir.components() {
+        match component {
+            ::std::path::Component::Normal(part) => {
+                if let Some(part) = part.to_str() {
+                    target.descend(part)?;
+                    depth += 1;
+                } else {
+                    bail!("Non-utf8 path: {:?}", file.path);
+                }
+            }
+            _ => {
+                bail!("unexpected path component in file name: {:?}", component);
+            }
+        }
+    }
+    let name = file
+        .path
+        .file_name()
+        .expect("version file should have a name");
+    target.rebuild(|b| {
+        b.insert(name, file.id, file.filemode)?;
+        Ok(())
+    })?;
+    for _ in 0..depth {
+        target.ascend()?;
+    }
+    Ok(())
+}
+
+/// A version file that a transition would change in the target env.
+struct PendingChange {
+    file: VersionFile,
+    current_oid: Option<Oid>,
+}
+
+/// Finds the version files that the transition selects and that differ
+/// between the source and the target env.
+fn pending_changes(
+    repo: &Repository,
+    tree: &Tree<'_>,
+    transition: &Transition,
+    version_files: Vec<VersionFile>,
+) -> Result<Vec<PendingChange>, Error> {
+    let target_version_dir = Path::new(&transition.target).join("version");
+    let needs_labels =
+        filter::needs_labels(&transition.include) || filter::needs_labels(&transition.exclude);
+
+    let mut pending = Vec::new();
+    for file in version_files {
+        let labels = if needs_labels {
+            let labels = filter::base_labels(repo, tree, &transition.target, &file.path)?;
+            if labels.is_empty() {
+                filter::base_labels(repo, tree, &transition.source, &file.path)?
+            } else {
+                labels
+            }
+        } else {
+            BTreeMap::new()
+        };
+        let path = file.path.to_string_lossy();
+        let candidate = filter::Candidate {
+            path: &path,
+            resource: &file.resource,
+            labels: &labels,
+        };
+        if !filter::selects(&transition.include, &transition.exclude, &candidate) {
+            continue;
+        }
+
+        let current_oid = tree
+            .get_path(&target_version_dir.join(&file.path))
+            .ok()
+            .map(|e| e.id());
+        if current_oid == Some(file.id) {
+            continue;
+        }
+        pending.push(PendingChange { file, current_oid });
+    }
+    Ok(pending)
+}
+
+/// The tree after applying a transition.
+struct AppliedChanges<'repo> {
+    tree: Tree<'repo>,
+    changes: Vec<ResourceChange>,
+    /// The locks that the transition released.
+    released: Vec<String>,
+}
+
+/// Writes the pending changes that the locks allow and that aren't held back
+/// into the target env, and updates the locks.
+fn apply_changes<'repo>(
+    repo: &'repo Repository,
+    tree: &Tree<'repo>,
+    transition: &Transition,
+    pending: &[PendingChange],
+    mut locks: Locks,
+    held: &BTreeMap<String, String>,
+    now: DateTime<Utc>,
+) -> Result<AppliedChanges<'repo>, Error> {
+    // the env lock reasons that are left hold everything for this transition
+    let env_held = locks.env_lock.is_locked(now);
+
+    let mut target = TreeZipper::from(repo, tree.clone());
+    target.descend(&transition.target)?;
+
+    let mut locks_changed = false;
+    let mut released = Vec::new();
+    let mut changes = Vec::new();
+    for PendingChange { file, current_oid } in pending {
+        let resource_lock = locks.resource_locks.get_mut(&file.resource);
+        let allowed = resource_lock
+            .as_ref()
+            .map_or(true, |lock| lock.allows(oid_to_id(file.id), now));
+        let new_oid = if allowed && !env_held && !held.contains_key(&file.resource) {
+            insert_version_file(&mut target, file)?;
+            changes.push(ResourceChange {
+                resource: file.resource.clone(),
+                old_version: *current_oid,
+                new_version: file.id,
+            });
+            Some(file.id)
+        } else {
+            *current_oid
+        };
+        if let Some(lock) = resource_lock {
+            let had_reasons = lock.reasons.len();
+            if lock.after_transition(new_oid.map(oid_to_id), now) {
+                locks_changed = true;
+                if lock.reasons.len() != had_reasons {
+                    released.push(format!("{}/{}", transition.target, file.resource));
+                }
+            }
+        }
+    }
+
+    if !pending.is_empty() {
+        let had_reasons = locks.env_lock.reasons.len();
+        if locks.env_lock.after_transition(None, now) {
+            locks_changed = true;
+            if locks.env_lock.reasons.len() != had_reasons {
+                released.push(transition.target.clone());
+            }
+        }
+    }
+    if locks_changed {
+        locks.remove_empty();
+        locks.save(repo, &mut target)?;
+    }
+
+    target.ascend()?;
+
+    Ok(AppliedChanges {
+        tree: target.into_inner().expect("new tree should not be None"),
+        changes,
+        released,
+    })
+}
+
+/// Identifies the changes a transition would make. It changes whenever a
+/// resource would get a different version.
+fn change_set_id(name: &str, changes: &[ResourceChange]) -> Result<Id, Error> {
+    let mut data = format!("{}\n", name);
+    for change in changes {
+        writeln!(&mut data, "{} {}", change.resource, change.new_version).unwrap();
+    }
+    Ok(oid_to_id(Oid::hash_object(
+        ObjectType::Blob,
+        data.as_bytes(),
+    )?))
+}
+
+fn describe_held(held: &BTreeMap<String, String>) -> String {
+    held.iter()
+        .map(|(resource, message)| format!("{} ({})", resource, message))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// What running a transition now would do.
+enum Plan<'repo> {
+    /// The transition wouldn't commit anything.
+    Done(TransitionResult),
+    Commit {
+        head_commit: Commit<'repo>,
+        applied: AppliedChanges<'repo>,
+        held: BTreeMap<String, String>,
+        /// The new tree, including the updated transition states and
+        /// approvals.
+        tree: Tree<'repo>,
+    },
+}
+
+/// Checks the transition and builds the tree it would commit, without
+/// committing anything. Records what it finds in `explanation`. A `dry_run`
+/// doesn't check preconditions with side effects.
+fn plan_transition<'repo>(
     name: &str,
     transition: &Transition,
-    repo: &Repository,
+    repo: &'repo Repository,
     service_state: &ServiceState,
     now: DateTime<Utc>,
-) -> Result<TransitionResult, Error> {
+    dry_run: bool,
+    explanation: &mut TransitionExplanation,
+) -> Result<Plan<'repo>, Error> {
     let mut transition_states = TransitionStates::load(repo)?;
     let transition_state = transition_states.0.get(name).cloned().unwrap_or_default();
-    if let Some(time) = transition_state.scheduled {
+    if let Some(pause) = transition_state.paused {
+        return Ok(Plan::Done(TransitionResult::Skipped(SkipReason::Paused {
+            message: pause.reason,
+        })));
+    }
+    let mut scheduled = transition_state.scheduled;
+    if transition_state.skipped.is_some() && transition_state.skipped == scheduled {
+        // wait for the scheduled time after the skipped one
+        scheduled = scheduled.and_then(|time| transition.next_scheduled_time(time));
+    }
+    explanation.scheduled = scheduled;
+    if let (Some(time), None) = (scheduled, transition_state.triggered) {
         if time >= now {
             // before scheduled time
-            return Ok(TransitionResult::Skipped(SkipReason::Scheduled { time }));
+            return Ok(Plan::Done(TransitionResult::Skipped(
+                SkipReason::Scheduled { time },
+            )));
         }
     }
 
-    let target_locks = locks::load_locks(repo, &transition.target)?;
-    if target_locks.env_lock.is_locked() {
-        return Ok(TransitionResult::Skipped(SkipReason::TargetLocked));
-    }
-
     let head_commit = git::get_head_commit(repo)?;
+    let tree = head_commit.tree()?;
 
-    let pending_transition = PendingTransitionInfo {
-        source: transition.source.clone(),
-        target: transition.target.clone(),
-        current_version: oid_to_id(head_commit.id()),
-    };
+    let mut target = TreeZipper::from(repo, tree.clone());
+    target.descend(&transition.target)?;
+    let target_locks = Locks::load(&target)?;
+    if target_locks.env_lock.blocks_transitions(now) {
+        return Ok(Plan::Done(TransitionResult::Skipped(
+            SkipReason::TargetLocked,
+        )));
+    }
+
+    let freezes = Freezes::load(&TreeZipper::from(repo, tree.clone()))?;
+    if let Some(freeze) = freezes.active(&transition.target, now) {
+        return Ok(Plan::Done(TransitionResult::Skipped(SkipReason::Frozen {
+            message: freeze.reason.clone(),
+            until: freeze.end,
+        })));
+    }
 
     let new_state = TransitionState {
         scheduled: transition.next_scheduled_time(now),
+        ..TransitionState::default()
     };
+    explanation.next_scheduled = new_state.scheduled;
 
     transition_states.insert(name, new_state);
 
-    let tree = head_commit.tree()?;
     let mut source = TreeZipper::from(repo, tree.clone());
     source.descend(&transition.source)?;
     source.descend("version")?;
     if !source.exists() {
-        return Ok(TransitionResult::Skipped(SkipReason::SourceMissing));
+        return Ok(Plan::Done(TransitionResult::Skipped(
+            SkipReason::SourceMissing,
+        )));
     };
 
-    let mut target = TreeZipper::from(repo, tree.clone());
-    target.descend(&transition.target)?;
-    target.descend("version")?;
-
-    let mut last_path = PathBuf::new();
-    for (path, entry) in source.walk(true) {
-        let entry = entry?;
-        if entry.kind() == Some(ObjectType::Blob) {
-            if let Some(name) = entry.name() {
-                let resource_name = if name.ends_with(".yaml") {
-                    &name[0..name.len() - ".yaml".len()]
-                } else if name.ends_with(".jsonnet") {
-                    &name[0..name.len() - ".jsonnet".len()]
-                } else {
-                    name
-                };
-                if !target_locks.resource_is_locked(resource_name) {
-                    target.rebuild(|b| {
-                        b.insert(entry.name_bytes(), entry.id(), entry.filemode())?;
-                        Ok(())
-                    })?;
-                }
-            }
-        } else if entry.kind() == Some(ObjectType::Tree) {
-            while !path.starts_with(&last_path) {
-                last_path.pop();
-                target.ascend()?;
-            }
-            for component in path
-                .strip_prefix(&last_path)
-                .expect("should be a prefix")
-                .components()
-            {
-                match component {
-                    ::std::path::Component::Normal(part) => {
-                        if let Some(part) = part.to_str() {
-                            target.descend(part)?;
-                        } else {
-                            bail!("Non-utf8 path in env {}: {:?}", name, path);
-                        }
-                    }
-                    _ => {
-                        bail!("unexpected path component in file name: {:?}", component);
-                    }
-                }
-            }
-            last_path.clone_from(&path);
-        }
-    }
-
-    for _ in last_path.components() {
-        target.ascend()?;
-    }
-
-    target.ascend()?;
-    target.ascend()?;
-
-    target.rebuild(|b| transition_states.save(repo, b))?;
+    let pending = pending_changes(repo, &tree, transition, collect_version_files(&source)?)?;
+
+    let mut zip = TreeZipper::from(repo, tree.clone());
+    zip.rebuild(|b| transition_states.save(repo, b))?;
+    let base_tree = zip.into_inner().expect("new tree should not be None");
+
+    let no_holds = BTreeMap::new();
+    let applied = apply_changes(
+        repo,
+        &base_tree,
+        transition,
+        &pending,
+        target_locks.clone(),
+        &no_holds,
+        now,
+    )?;
 
-    let new_tree = target.into_inner().expect("new tree should not be None");
+    explanation.locked = pending
+        .iter()
+        .map(|p| &p.file.resource)
+        .filter(|resource| !applied.changes.iter().any(|c| c.resource == **resource))
+        .cloned()
+        .collect();
 
-    if new_tree.id() == tree.id() {
+    if applied.tree.id() == tree.id() {
         // nothing changed
-        return Ok(TransitionResult::Skipped(SkipReason::NoChange));
+        return Ok(Plan::Done(TransitionResult::Skipped(SkipReason::NoChange)));
     }
+    explanation.changes = explain::planned_changes(repo, &applied.changes)?;
+
+    let mut approvals = Approvals::load(&TreeZipper::from(repo, tree.clone()))?;
+    let pending_transition = PendingTransitionInfo {
+        source: transition.source.clone(),
+        target: transition.target.clone(),
+        current_version: oid_to_id(head_commit.id()),
+        resources: applied.changes.iter().map(|c| c.resource.clone()).collect(),
+        versions: applied
+            .changes
+            .iter()
+            .map(|c| Ok((c.resource.clone(), version_name(repo, c.new_version)?)))
+            .collect::<Result<_, Error>>()?,
+        change_set: change_set_id(name, &applied.changes)?,
+        approval: approvals.0.get(name).cloned(),
+    };
 
+    let mut held = match precondition::check_base_file_exists(&pending_transition, repo)? {
+        PreconditionResult::Held { resources } => resources,
+        _ => BTreeMap::new(),
+    };
+    let soak_time = precondition::soak_time(&transition.preconditions);
     for precondition in &transition.preconditions {
-        match precondition::check_precondition(&pending_transition, precondition, service_state)? {
+        if dry_run && precondition.has_side_effects() {
+            explanation.preconditions.push(PreconditionReport {
+                precondition: precondition.clone(),
+                result: None,
+            });
+            continue;
+        }
+        let result = precondition::check_precondition(
+            &pending_transition,
+            precondition,
+            soak_time,
+            service_state,
+            repo,
+            now,
+        )?;
+        explanation.preconditions.push(PreconditionReport {
+            precondition: precondition.clone(),
+            result: Some(result.clone()),
+        });
+        match result {
             PreconditionResult::Blocked { message } => {
-                return Ok(TransitionResult::Blocked { message });
+                return Ok(Plan::Done(TransitionResult::Blocked { message }));
             }
             PreconditionResult::Failed { message } => {
-                return Ok(TransitionResult::CheckFailed { message });
+                return Ok(Plan::Done(TransitionResult::CheckFailed { message }));
+            }
+            PreconditionResult::Held { resources } => {
+                for (resource, message) in resources {
+                    if pending_transition.resources.contains(&resource) {
+                        held.entry(resource).or_insert(message);
+                    }
+                }
+            }
+            PreconditionResult::Skipped(reason) => {
+                return Ok(Plan::Done(TransitionResult::Skipped(reason)));
+            }
+            PreconditionResult::AwaitingApproval { group } => {
+                return Ok(Plan::Done(TransitionResult::AwaitingApproval {
+                    change_set: pending_transition.change_set,
+                    resources: pending_transition.resources,
+                    group,
+                    // set by update_transition_status
+                    since: None,
+                }));
             }
             PreconditionResult::Success => {}
         }
     }
 
+    let applied = if held.is_empty() {
+        applied
+    } else {
+        let applied = apply_changes(
+            repo,
+            &base_tree,
+            transition,
+            &pending,
+            target_locks,
+            &held,
+            now,
+        )?;
+        explanation.held = held.clone();
+        explanation.changes = explain::planned_changes(repo, &applied.changes)?;
+        if applied.changes.is_empty() {
+            return Ok(Plan::Done(TransitionResult::Blocked {
+                message: format!("all changes held back: {}", describe_held(&held)),
+            }));
+        }
+        applied
+    };
+
+    // an approval only releases one change set
+    let new_tree = if approvals.0.remove(name).is_some() {
+        let mut zip = TreeZipper::from(repo, applied.tree.clone());
+        approvals.save(repo, &mut zip)?;
+        zip.into_inner().expect("new tree should not be None")
+    } else {
+        applied.tree.clone()
+    };
+
+    Ok(Plan::Commit {
+        head_commit,
+        applied,
+        held,
+        tree: new_tree,
+    })
+}
+
+fn run_transition(
+    name: &str,
+    transition: &Transition,
+    repo: &Repository,
+    service_state: &ServiceState,
+    now: DateTime<Utc>,
+) -> Result<TransitionResult, Error> {
+    let mut explanation = TransitionExplanation::default();
+    let (head_commit, applied, held, new_tree) = match plan_transition(
+        name,
+        transition,
+        repo,
+        service_state,
+        now,
+        false,
+        &mut explanation,
+    )? {
+        Plan::Done(result) => return Ok(result),
+        Plan::Commit {
+            head_commit,
+            applied,
+            held,
+            tree,
+        } => (head_commit, applied, held, tree),
+    };
+
     let signature = Signature::now("DM Transitioner", "n/a")?;
 
     let mut message = format!(
         "Mirroring {} to {}\n\n",
         transition.source, transition.target
     );
+    if !applied.changes.is_empty() {
+        message.push_str(&change_log::describe_changes(
+            repo,
+            &head_commit,
+            &applied.changes,
+        )?);
+        message.push('\n');
+    }
+    if !held.is_empty() {
+        message.push_str("Held back:\n");
+        for (resource, reason) in &held {
+            write!(&mut message, "- {}: {}\n", resource, reason).unwrap();
+        }
+        message.push('\n');
+    }
     write!(&mut message, "DM-Transition: {}\n", name).unwrap();
     write!(&mut message, "DM-Source: {}\n", transition.source).unwrap();
     write!(&mut message, "DM-Target: {}\n", transition.target).unwrap();
+    let transitioned: Vec<String> = applied.changes.iter().map(|c| c.resource.clone()).collect();
+    if !transitioned.is_empty() {
+        write!(&mut message, "DM-Resources: {}\n", transitioned.join(", ")).unwrap();
+    }
+    if !held.is_empty() {
+        let held_resources: Vec<&str> = held.keys().map(|r| r.as_str()).collect();
+        write!(
+            &mut message,
+            "DM-Held-Resources: {}\n",
+            held_resources.join(", ")
+        )
+        .unwrap();
+    }
+    for lock in &applied.released {
+        write!(&mut message, "DM-Unlock: {}\n", lock).unwrap();
+    }
 
     let commit = repo.commit(
         Some("refs/dm_head"),
@@ -230,63 +655,206 @@ fn run_transition(
 
     Ok(TransitionResult::Success {
         committed_version: oid_to_id(commit),
+        transitioned,
+        held,
     })
 }
 
-fn run_one_transition(
+/// Tracks which transitions may still run in one iteration, so that
+/// transitions between unrelated envs don't wait for each other.
+#[derive(Debug, Default)]
+struct Batch {
+    /// The target envs of the transitions that committed changes. Their
+    /// deployers haven't caught up yet.
+    changed: HashSet<String>,
+    /// The source envs of blocked or failing transitions, which shouldn't
+    /// change while the transitions wait for them.
+    held: HashSet<String>,
+}
+
+impl Batch {
+    fn allows(&self, transition: &Transition) -> bool {
+        !self.changed.contains(&transition.source)
+            && !self.changed.contains(&transition.target)
+            && !self.held.contains(&transition.target)
+    }
+}
+
+/// Runs the transitions in the order of the config. Each transition whose
+/// envs aren't affected by an earlier one runs on top of the commits made
+/// before it, the others wait for the next iteration.
+fn run_transitions(
     repo: &Repository,
     service_state: &ServiceState,
     now: DateTime<Utc>,
 ) -> Result<(), Error> {
-    for (name, transition) in service_state.config.transitions.iter() {
-        let result = run_transition(name, transition, &repo, service_state, now)?;
+    let config = service_state.config.read().expect("RwLock poisoned");
+    let mut batch = Batch::default();
+    let mut first_error = None;
+    for (name, transition) in config.transitions.iter() {
+        if !batch.allows(transition) {
+            continue;
+        }
+        let result = match run_transition(name, transition, &repo, service_state, now) {
+            Ok(result) => result,
+            Err(error) => {
+                batch.held.insert(transition.source.clone());
+                first_error = first_error.or(Some(error));
+                continue;
+            }
+        };
 
         update_transition_status(service_state, &name, result.clone());
 
         match result {
-            TransitionResult::Success { .. } => break,
-            TransitionResult::Skipped(..) => continue,
-            // TODO we could instead just block transitions that touch the source env
-            TransitionResult::Blocked { .. } => break,
-            TransitionResult::CheckFailed { .. } => continue,
+            TransitionResult::Success { .. } => {
+                batch.changed.insert(transition.target.clone());
+            }
+            TransitionResult::Blocked { .. } => {
+                batch.held.insert(transition.source.clone());
+            }
+            TransitionResult::Skipped(..)
+            | TransitionResult::CheckFailed { .. }
+            | TransitionResult::AwaitingApproval { .. } => {}
         }
     }
-    Ok(())
+
+    if !batch.changed.is_empty() {
+        // run the waiting transitions on top of the new commits right away
+        service_state.trigger.request_fetch();
+        deployer_watch::request_fetch(service_state);
+    }
+    match first_error {
+        Some(error) => Err(error),
+        None => Ok(()),
+    }
 }
 
-fn update_transition_status(service_state: &ServiceState, name: &str, result: TransitionResult) {
-    let mut map = service_state
-        .transition_status
-        .lock()
-        .expect("Mutex poisoned");
+fn update_transition_status(
+    service_state: &ServiceState,
+    name: &str,
+    mut result: TransitionResult,
+) {
+    service_state.status.update(|status| {
+        let transition_status = status
+            .transitions
+            .entry(name.to_string())
+            .or_insert_with(TransitionStatusInfo::new);
+
+        let time = Utc::now();
+
+        if let TransitionResult::AwaitingApproval {
+            change_set, since, ..
+        } = &mut result
+        {
+            // keep the time of the first run that waited for this change set
+            *since = match transition_status.last_run.as_ref().map(|run| &run.result) {
+                Some(TransitionResult::AwaitingApproval {
+                    change_set: previous,
+                    since: previous_since,
+                    ..
+                }) if *previous == *change_set => *previous_since,
+                _ => None,
+            }
+            .or(Some(time));
+        }
 
-    let transition_status = map
-        .entry(name.to_string())
-        .or_insert_with(TransitionStatusInfo::new);
+        if let TransitionResult::Success {
+            committed_version, ..
+        } = result
+        {
+            transition_status
+                .successful_runs
+                .push_front(TransitionSuccessfulRunInfo {
+                    time,
+                    committed_version,
+                });
+            let cap = transition_status.successful_runs.capacity() - 1;
+            transition_status.successful_runs.truncate(cap);
+        }
 
-    let time = Utc::now();
+        transition_status.last_run = Some(TransitionRunInfo {
+            time: Some(time),
+            result,
+        });
+    });
+}
 
-    if let TransitionResult::Success { committed_version } = result {
-        transition_status
-            .successful_runs
-            .push_front(TransitionSuccessfulRunInfo {
-                time,
-                committed_version,
+fn load_config(repo: &impl ResourceRepo) -> Result<Config, Error> {
+    repo.get(Path::new(TRANSITIONS_FILE))?
+        .map_or(Ok(Config::default()), |data| Config::load(&data))
+}
+
+/// Switches to the config from the current repo version, or records the error
+/// and keeps the previous config if it is invalid.
+fn reload_config(repo: &impl ResourceRepo, service_state: &ServiceState) {
+    match load_config(repo) {
+        Ok(config) => {
+            service_state.status.update(|status| {
+                status
+                    .transitions
+                    .retain(|name, _| config.transitions.contains_key(name));
+                status.config_error = None;
             });
-        let cap = transition_status.successful_runs.capacity() - 1;
-        transition_status.successful_runs.truncate(cap);
+            *service_state.config.write().expect("RwLock poisoned") = config;
+        }
+        Err(error) => {
+            error!(
+                "Loading transition config failed, keeping the previous one: {}\n{}",
+                error,
+                error.backtrace()
+            );
+            for cause in error.iter_causes() {
+                error!("caused by: {}", cause);
+            }
+            let config_error = error
+                .iter_chain()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>()
+                .join(": ");
+            service_state
+                .status
+                .update(|status| status.config_error = Some(config_error));
+        }
+    }
+}
+
+/// When a transition that was skipped because of its time window or a freeze
+/// may run again.
+fn skipped_until(status: &TransitionStatusInfo) -> Option<DateTime<Utc>> {
+    match &status.last_run.as_ref()?.result {
+        TransitionResult::Skipped(SkipReason::OutsideTimeWindow { next_start }) => *next_start,
+        TransitionResult::Skipped(SkipReason::Frozen { until, .. }) => Some(*until),
+        _ => None,
     }
+}
 
-    transition_status.last_run = Some(TransitionRunInfo {
-        time: Some(time),
-        result,
-    });
+/// Returns how long to wait until the next scheduled transition is due, or a
+/// skipped one may run.
+fn until_next_scheduled(service_state: &ServiceState, now: DateTime<Utc>) -> Option<Duration> {
+    let config = service_state.config.read().expect("RwLock poisoned");
+    let status = service_state.status.get();
+    let skipped = status
+        .transitions
+        .values()
+        .filter_map(skipped_until)
+        .filter(|time| *time > now);
+    let next = config
+        .transitions
+        .values()
+        .filter_map(|t| t.next_scheduled_time(now))
+        .chain(skipped)
+        .min()?;
+    // transitions only run once their scheduled time has passed
+    (next - now + chrono::Duration::seconds(1)).to_std().ok()
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::explain::PlannedChange;
     use git_fixture::RepoFixture;
+    use indexmap::IndexMap;
 
     fn make_config(s: &str) -> Result<config::Config, Error> {
         let c: config::Config = serde_yaml::from_str(s)?;
@@ -304,6 +872,8 @@ mod test {
             },
             deployer_url: None,
             api_port: None,
+            shutdown_deadline_secs: None,
+            poll_interval_secs: None,
         }
     }
 
@@ -319,18 +889,25 @@ mod test {
         fixture.set_ref("refs/dm_head", "head").unwrap();
         let config = make_config(include_str!("./fixtures/simple_config.yaml")).unwrap();
         let client = reqwest::Client::new();
-        let transition_status = Mutex::new(IndexMap::new());
         let env = make_env(&fixture.repo);
         let state = ServiceState {
-            config,
+            config: RwLock::new(config),
             env,
             client,
-            transition_status,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
         };
 
         let result = run_transition(
             "prod",
-            &state.config.transitions.get("prod").unwrap(),
+            &state
+                .config
+                .read()
+                .unwrap()
+                .transitions
+                .get("prod")
+                .unwrap(),
             &fixture.repo,
             &state,
             test_time(),
@@ -352,16 +929,17 @@ mod test {
         fixture.set_ref("refs/dm_head", "head").unwrap();
         let config = make_config(include_str!("./fixtures/simple_config.yaml")).unwrap();
         let client = reqwest::Client::new();
-        let transition_status = Mutex::new(IndexMap::new());
         let env = make_env(&fixture.repo);
         let state = ServiceState {
-            config,
+            config: RwLock::new(config),
             env,
             client,
-            transition_status,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
         };
 
-        run_one_transition(&fixture.repo, &state, test_time()).unwrap();
+        run_transitions(&fixture.repo, &state, test_time()).unwrap();
 
         fixture.assert_ref_matches("refs/dm_head", "expected");
     }
@@ -374,16 +952,17 @@ mod test {
         fixture.set_ref("refs/dm_head", "head").unwrap();
         let config = make_config(include_str!("./fixtures/simple_config.yaml")).unwrap();
         let client = reqwest::Client::new();
-        let transition_status = Mutex::new(IndexMap::new());
         let env = make_env(&fixture.repo);
         let state = ServiceState {
-            config,
+            config: RwLock::new(config),
             env,
             client,
-            transition_status,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
         };
 
-        run_one_transition(&fixture.repo, &state, test_time()).unwrap();
+        run_transitions(&fixture.repo, &state, test_time()).unwrap();
 
         fixture.assert_ref_matches("refs/dm_head", "expected");
     }
@@ -395,16 +974,39 @@ mod test {
         fixture.set_ref("refs/dm_head", "head").unwrap();
         let config = make_config(include_str!("./fixtures/simple_config.yaml")).unwrap();
         let client = reqwest::Client::new();
-        let transition_status = Mutex::new(IndexMap::new());
         let env = make_env(&fixture.repo);
         let state = ServiceState {
-            config,
+            config: RwLock::new(config),
+            env,
+            client,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
+        };
+
+        run_transitions(&fixture.repo, &state, test_time()).unwrap();
+
+        fixture.assert_ref_matches("refs/dm_head", "expected");
+    }
+
+    #[test]
+    fn test_transition_filters() {
+        let fixture =
+            RepoFixture::from_str(include_str!("./fixtures/transition_filters.yaml")).unwrap();
+        fixture.set_ref("refs/dm_head", "head").unwrap();
+        let config = make_config(include_str!("./fixtures/filters_config.yaml")).unwrap();
+        let client = reqwest::Client::new();
+        let env = make_env(&fixture.repo);
+        let state = ServiceState {
+            config: RwLock::new(config),
             env,
             client,
-            transition_status,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
         };
 
-        run_one_transition(&fixture.repo, &state, test_time()).unwrap();
+        run_transitions(&fixture.repo, &state, test_time()).unwrap();
 
         fixture.assert_ref_matches("refs/dm_head", "expected");
     }
@@ -416,18 +1018,64 @@ mod test {
         fixture.set_ref("refs/dm_head", "head").unwrap();
         let config = make_config(include_str!("./fixtures/three_envs_config.yaml")).unwrap();
         let client = reqwest::Client::new();
-        let transition_status = Mutex::new(IndexMap::new());
         let env = make_env(&fixture.repo);
         let state = ServiceState {
-            config,
+            config: RwLock::new(config),
+            env,
+            client,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
+        };
+
+        run_transitions(&fixture.repo, &state, test_time()).unwrap();
+
+        // prod gets the version from pp before pp changes
+        fixture.assert_tree_matches("refs/dm_head", "expected");
+        let head = git::get_head_commit(&fixture.repo).unwrap();
+        let first = head.parent(0).unwrap();
+        assert!(head.message().unwrap().contains("DM-Transition: pp\n"));
+        assert!(first.message().unwrap().contains("DM-Transition: prod\n"));
+        assert_eq!(
+            first.parent_id(0).unwrap(),
+            fixture.get_commit("head").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_blocked_transition_only_holds_its_source() {
+        let fixture =
+            RepoFixture::from_str(include_str!("./fixtures/independent_transitions.yaml")).unwrap();
+        fixture.set_ref("refs/dm_head", "head").unwrap();
+        let config = make_config(include_str!(
+            "./fixtures/independent_transitions_config.yaml"
+        ))
+        .unwrap();
+        let client = reqwest::Client::new();
+        let env = make_env(&fixture.repo);
+        let state = ServiceState {
+            config: RwLock::new(config),
             env,
             client,
-            transition_status,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
         };
 
-        run_one_transition(&fixture.repo, &state, test_time()).unwrap();
+        run_transitions(&fixture.repo, &state, test_time()).unwrap();
 
+        // pp is blocked because foo has no base file there, so dev must not
+        // change, but qa-eu goes ahead
         fixture.assert_ref_matches("refs/dm_head", "expected");
+        let status = state.status.get();
+        assert!(match &status.transitions["pp"].last_run {
+            Some(run) => match run.result {
+                TransitionResult::Blocked { .. } => true,
+                _ => false,
+            },
+            None => false,
+        });
+        assert!(!status.transitions.contains_key("dev"));
     }
 
     #[test]
@@ -437,16 +1085,17 @@ mod test {
         fixture.set_ref("refs/dm_head", "head").unwrap();
         let config = make_config(include_str!("./fixtures/three_envs_config.yaml")).unwrap();
         let client = reqwest::Client::new();
-        let transition_status = Mutex::new(IndexMap::new());
         let env = make_env(&fixture.repo);
         let state = ServiceState {
-            config,
+            config: RwLock::new(config),
             env,
             client,
-            transition_status,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
         };
 
-        run_one_transition(&fixture.repo, &state, test_time()).unwrap();
+        run_transitions(&fixture.repo, &state, test_time()).unwrap();
 
         fixture.assert_ref_matches("refs/dm_head", "expected");
     }
@@ -457,16 +1106,17 @@ mod test {
         fixture.set_ref("refs/dm_head", "head").unwrap();
         let config = make_config(include_str!("./fixtures/three_envs_config.yaml")).unwrap();
         let client = reqwest::Client::new();
-        let transition_status = Mutex::new(IndexMap::new());
         let env = make_env(&fixture.repo);
         let state = ServiceState {
-            config,
+            config: RwLock::new(config),
             env,
             client,
-            transition_status,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
         };
 
-        run_one_transition(&fixture.repo, &state, test_time()).unwrap();
+        run_transitions(&fixture.repo, &state, test_time()).unwrap();
 
         fixture.assert_ref_matches("refs/dm_head", "expected");
     }
@@ -478,36 +1128,180 @@ mod test {
         fixture.set_ref("refs/dm_head", "head").unwrap();
         let config = make_config(include_str!("./fixtures/simple_config.yaml")).unwrap();
         let client = reqwest::Client::new();
-        let transition_status = Mutex::new(IndexMap::new());
         let env = make_env(&fixture.repo);
         let state = ServiceState {
-            config,
+            config: RwLock::new(config),
             env,
             client,
-            transition_status,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
         };
 
-        run_one_transition(&fixture.repo, &state, test_time()).unwrap();
+        run_transitions(&fixture.repo, &state, test_time()).unwrap();
 
         fixture.assert_ref_matches("refs/dm_head", "expected");
     }
 
+    #[test]
+    fn test_transition_change_log() {
+        let fixture =
+            RepoFixture::from_str(include_str!("./fixtures/transition_change_log.yaml")).unwrap();
+        fixture.set_ref("refs/dm_head", "head").unwrap();
+        let config = make_config(include_str!("./fixtures/simple_config.yaml")).unwrap();
+        let client = reqwest::Client::new();
+        let env = make_env(&fixture.repo);
+        let state = ServiceState {
+            config: RwLock::new(config),
+            env,
+            client,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
+        };
+
+        run_transitions(&fixture.repo, &state, test_time()).unwrap();
+
+        let head = git::get_head_commit(&fixture.repo).unwrap();
+        assert_eq!(
+            head.message().unwrap(),
+            "Mirroring available to prod\n\n\
+             - foo: 1 -> 2\n\n\
+             foo 2:\n\
+             Fixed the frobnicator.\n\n\
+             DM-Transition: prod\n\
+             DM-Source: available\n\
+             DM-Target: prod\n\
+             DM-Resources: foo\n"
+        );
+    }
+
+    #[test]
+    fn test_transition_holds_resources_without_base_file() {
+        let fixture =
+            RepoFixture::from_str(include_str!("./fixtures/transition_base_files.yaml")).unwrap();
+        fixture.set_ref("refs/dm_head", "head").unwrap();
+        let config = make_config(include_str!("./fixtures/simple_config.yaml")).unwrap();
+        let client = reqwest::Client::new();
+        let env = make_env(&fixture.repo);
+        let state = ServiceState {
+            config: RwLock::new(config),
+            env,
+            client,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
+        };
+
+        run_transitions(&fixture.repo, &state, test_time()).unwrap();
+
+        fixture.assert_ref_matches("refs/dm_head", "expected");
+        let head = git::get_head_commit(&fixture.repo).unwrap();
+        assert!(head
+            .message()
+            .unwrap()
+            .contains("Held back:\n- bar: no base file bar.yaml in prod\n"));
+    }
+
+    #[test]
+    fn test_explain_transition() {
+        let fixture =
+            RepoFixture::from_str(include_str!("./fixtures/transition_base_files.yaml")).unwrap();
+        fixture.set_ref("refs/dm_head", "head").unwrap();
+        let config = make_config(include_str!("./fixtures/simple_config.yaml")).unwrap();
+        let transition = config.transitions["prod"].clone();
+        let client = reqwest::Client::new();
+        let env = make_env(&fixture.repo);
+        let state = ServiceState {
+            config: RwLock::new(config),
+            env,
+            client,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
+        };
+
+        let explanation =
+            explain::explain_transition("prod", &transition, &fixture.repo, &state, test_time())
+                .unwrap();
+
+        assert_eq!(
+            explanation.changes,
+            vec![PlannedChange {
+                resource: "foo".to_string(),
+                old_version: Some("1".to_string()),
+                new_version: "2".to_string(),
+            }]
+        );
+        assert_eq!(
+            explanation.held.get("bar").map(|r| r.as_str()),
+            Some("no base file bar.yaml in prod")
+        );
+        assert!(explanation.preconditions.is_empty());
+        assert_eq!(explanation.result, None);
+        // nothing is committed
+        assert_eq!(
+            fixture.repo.refname_to_id("refs/dm_head").unwrap(),
+            fixture.get_commit("head").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_lock_kinds() {
+        let fixture = RepoFixture::from_str(include_str!("./fixtures/lock_kinds.yaml")).unwrap();
+        fixture.set_ref("refs/dm_head", "head").unwrap();
+        let config = make_config(include_str!("./fixtures/simple_config.yaml")).unwrap();
+        let client = reqwest::Client::new();
+        let env = make_env(&fixture.repo);
+        let state = ServiceState {
+            config: RwLock::new(config),
+            env,
+            client,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
+        };
+
+        run_transitions(&fixture.repo, &state, test_time()).unwrap();
+
+        let head = git::get_head_commit(&fixture.repo).unwrap();
+        assert!(head.message().unwrap().contains("DM-Unlock: prod/foo\n"));
+        let mut zip = TreeZipper::from(&fixture.repo, head.tree().unwrap());
+        zip.descend("prod").unwrap();
+        zip.descend("version").unwrap();
+        let content = |name: &str| zip.get_blob(name).unwrap().unwrap().content().to_vec();
+        assert_eq!(content("foo.yaml"), b"y");
+        assert_eq!(content("bar.yaml"), b"x");
+        assert_eq!(content("baz.yaml"), b"y");
+
+        let mut zip = TreeZipper::from(&fixture.repo, head.tree().unwrap());
+        zip.descend("prod").unwrap();
+        let locks = Locks::load(&zip).unwrap();
+        assert!(!locks.resource_locks.contains_key("foo"));
+        assert_eq!(
+            locks.resource_locks["bar"].reasons[0].kind,
+            common::transitions::LockKind::Permanent
+        );
+        assert!(!locks.resource_locks["baz"].reasons[0].kind.is_permanent());
+    }
+
     #[test]
     fn test_both_locked() {
         let fixture = RepoFixture::from_str(include_str!("./fixtures/both_locked.yaml")).unwrap();
         fixture.set_ref("refs/dm_head", "head").unwrap();
         let config = make_config(include_str!("./fixtures/three_envs_config.yaml")).unwrap();
         let client = reqwest::Client::new();
-        let transition_status = Mutex::new(IndexMap::new());
         let env = make_env(&fixture.repo);
         let state = ServiceState {
-            config,
+            config: RwLock::new(config),
             env,
             client,
-            transition_status,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
         };
 
-        run_one_transition(&fixture.repo, &state, test_time()).unwrap();
+        run_transitions(&fixture.repo, &state, test_time()).unwrap();
 
         assert_eq!(
             fixture.repo.refname_to_id("refs/dm_head").unwrap(),
@@ -515,6 +1309,55 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_transition_state_overrides() {
+        let fixture =
+            RepoFixture::from_str(include_str!("./fixtures/transition_state_overrides.yaml"))
+                .unwrap();
+        let config = make_config(include_str!("./fixtures/timed_transition_config.yaml")).unwrap();
+        let transition = config.transitions["prod"].clone();
+        let client = reqwest::Client::new();
+        let env = make_env(&fixture.repo);
+        let state = ServiceState {
+            config: RwLock::new(config),
+            env,
+            client,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
+        };
+        let run = |commit| {
+            fixture.set_ref("refs/dm_head", commit).unwrap();
+            run_transition("prod", &transition, &fixture.repo, &state, test_time()).unwrap()
+        };
+
+        // the next run after the skipped one is due at midnight
+        assert_eq!(
+            run("skipped"),
+            TransitionResult::Skipped(SkipReason::Scheduled {
+                time: "2018-01-01T00:00:00Z".parse().unwrap()
+            })
+        );
+        assert_eq!(
+            run("paused"),
+            TransitionResult::Skipped(SkipReason::Paused {
+                message: "incident".to_string()
+            })
+        );
+        match run("triggered") {
+            TransitionResult::Success { transitioned, .. } => {
+                assert_eq!(transitioned, vec!["foo".to_string()])
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        let head = git::get_head_commit(&fixture.repo).unwrap();
+        let expected = fixture
+            .repo
+            .find_commit(fixture.get_commit("expected").unwrap())
+            .unwrap();
+        assert_eq!(head.tree_id(), expected.tree_id());
+    }
+
     #[test]
     fn test_timed_transition_pending() {
         let fixture =
@@ -523,18 +1366,25 @@ mod test {
         fixture.set_ref("refs/dm_head", "head").unwrap();
         let config = make_config(include_str!("./fixtures/timed_transition_config.yaml")).unwrap();
         let client = reqwest::Client::new();
-        let transition_status = Mutex::new(IndexMap::new());
         let env = make_env(&fixture.repo);
         let state = ServiceState {
-            config,
+            config: RwLock::new(config),
             env,
             client,
-            transition_status,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
         };
 
         let result = run_transition(
             "prod",
-            &state.config.transitions.get("prod").unwrap(),
+            &state
+                .config
+                .read()
+                .unwrap()
+                .transitions
+                .get("prod")
+                .unwrap(),
             &fixture.repo,
             &state,
             "2018-01-01T00:00:00Z".parse().unwrap(),
@@ -561,16 +1411,17 @@ mod test {
         fixture.set_ref("refs/dm_head", "head").unwrap();
         let config = make_config(include_str!("./fixtures/timed_transition_config.yaml")).unwrap();
         let client = reqwest::Client::new();
-        let transition_status = Mutex::new(IndexMap::new());
         let env = make_env(&fixture.repo);
         let state = ServiceState {
-            config,
+            config: RwLock::new(config),
             env,
             client,
-            transition_status,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
         };
 
-        run_one_transition(
+        run_transitions(
             &fixture.repo,
             &state,
             "2018-01-01T00:00:01Z".parse().unwrap(),
@@ -580,6 +1431,40 @@ mod test {
         fixture.assert_ref_matches("refs/dm_head", "expected");
     }
 
+    #[test]
+    fn test_until_skipped_transition_may_run() {
+        let config = make_config(include_str!("./fixtures/simple_config.yaml")).unwrap();
+        let fixture =
+            RepoFixture::from_str(include_str!("./fixtures/transition_target_created.yaml"))
+                .unwrap();
+        let state = ServiceState {
+            config: RwLock::new(config),
+            env: make_env(&fixture.repo),
+            client: reqwest::Client::new(),
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
+        };
+        let now = test_time();
+        assert_eq!(until_next_scheduled(&state, now), None);
+
+        let until = now + chrono::Duration::hours(1);
+        update_transition_status(
+            &state,
+            "prod",
+            TransitionResult::Skipped(SkipReason::Frozen {
+                message: "release".to_string(),
+                until,
+            }),
+        );
+        assert_eq!(
+            until_next_scheduled(&state, now),
+            Some(Duration::from_secs(3601))
+        );
+        // the freeze is over, so the transition runs again anyway
+        assert_eq!(until_next_scheduled(&state, until), None);
+    }
+
     #[test]
     fn test_timed_transition_without_schedule() {
         let fixture = RepoFixture::from_str(include_str!(
@@ -592,16 +1477,17 @@ mod test {
         ))
         .unwrap();
         let client = reqwest::Client::new();
-        let transition_status = Mutex::new(IndexMap::new());
         let env = make_env(&fixture.repo);
         let state = ServiceState {
-            config,
+            config: RwLock::new(config),
             env,
             client,
-            transition_status,
+            status: Default::default(),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
         };
 
-        run_one_transition(
+        run_transitions(
             &fixture.repo,
             &state,
             "2018-01-01T00:00:01Z".parse().unwrap(),
@@ -610,54 +1496,194 @@ mod test {
 
         fixture.assert_ref_matches("refs/dm_head", "expected");
     }
+
+    #[test]
+    fn test_reload_config() {
+        let fixture = RepoFixture::from_str(include_str!("./fixtures/config_reload.yaml")).unwrap();
+        let valid = fixture.get_commit("valid").unwrap();
+        let invalid = fixture.get_commit("invalid").unwrap();
+        let env = make_env(&fixture.repo);
+        let (repo, _tempdir) = fixture.into_inner();
+        let mut repo = GitResourceRepo::from_repo(repo, valid, env.common.clone());
+        let mut transitions = IndexMap::new();
+        transitions.insert("removed".to_string(), TransitionStatusInfo::new());
+        let state = ServiceState {
+            config: RwLock::new(Config::default()),
+            env,
+            client: reqwest::Client::new(),
+            status: Watch::new(TransitionerStatus {
+                transitions,
+                config_error: None,
+            }),
+            trigger: Trigger::new(),
+            job_results: Default::default(),
+        };
+
+        reload_config(&repo, &state);
+        assert!(state
+            .config
+            .read()
+            .unwrap()
+            .transitions
+            .contains_key("prod"));
+        assert_eq!(state.status.get().config_error, None);
+        assert!(state.status.get().transitions.is_empty());
+
+        repo.head = invalid;
+        reload_config(&repo, &state);
+        let config = state.config.read().unwrap();
+        assert_eq!(config.transitions["prod"].schedule, None);
+        assert!(state
+            .status
+            .get()
+            .config_error
+            .unwrap()
+            .contains("every now and then"));
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(name = "transitioner")]
+struct Opt {
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Runs the transitioner service (the default).
+    #[structopt(name = "run")]
+    Run,
+    /// Prints what a transition would do if it ran now as JSON, without
+    /// committing anything.
+    #[structopt(name = "explain")]
+    Explain {
+        /// The name of the transition.
+        name: String,
+    },
+}
+
+fn explain_command(env: Env, name: &str) -> Result<(), Error> {
+    let repo = GitResourceRepo::open(env.common.clone())?;
+    let config = load_config(&repo)?;
+    let transition = match config.transitions.get(name) {
+        Some(transition) => transition.clone(),
+        None => bail!("unknown transition {}", name),
+    };
+    let service_state = ServiceState {
+        config: RwLock::new(config),
+        env,
+        client: reqwest::Client::new(),
+        status: Default::default(),
+        trigger: Trigger::new(),
+        job_results: Default::default(),
+    };
+    let explanation =
+        explain::explain_transition(name, &transition, &repo.repo, &service_state, Utc::now())?;
+    println!("{}", serde_json::to_string_pretty(&explanation)?);
+    Ok(())
 }
 
 fn run() -> Result<(), Error> {
     env_logger::init();
+    let opt = Opt::from_args();
     let env: Env = envy::from_env()?;
-    let mut repo = GitResourceRepo::open(env.common.clone())?;
+    match opt.command {
+        None | Some(Command::Run) => run_service(env),
+        Some(Command::Explain { name }) => explain_command(env, &name),
+    }
+}
 
-    let config = repo
-        .get(Path::new("transitions.yaml"))?
-        .map_or(Ok(Config::default()), |data| Config::load(&data))?;
+fn run_service(env: Env) -> Result<(), Error> {
+    let deadline = env
+        .shutdown_deadline_secs
+        .map_or(shutdown::DEFAULT_DEADLINE, Duration::from_secs);
+    let poll_interval = env
+        .poll_interval_secs
+        .map_or(watch::DEFAULT_POLL_INTERVAL, Duration::from_secs);
+    let shutdown = Shutdown::install(deadline)?;
+    let mut repo = GitResourceRepo::open(env.common.clone())?;
 
     let client = reqwest::Client::new();
-    let transition_status = Mutex::new(IndexMap::new());
     let service_state = Arc::new(ServiceState {
-        config,
+        config: RwLock::new(Config::default()),
         env,
         client,
-        transition_status,
+        status: Default::default(),
+        trigger: Trigger::new(),
+        job_results: Default::default(),
     });
 
-    api::start(service_state.clone());
+    let service_state_1 = service_state.clone();
+    shutdown.on_shutdown(move || service_state_1.trigger.notify());
+
+    let api = api::start(service_state.clone(), &shutdown);
+    let deployer_watch = deployer_watch::start(service_state.clone(), shutdown.clone());
 
     info!("Transitioner running.");
 
+    let mut config_version = None;
+    let mut last_fetch: Option<Instant> = None;
+    let mut fetch_requested = false;
+
     loop {
-        if let Err(error) = repo.update() {
-            // TODO improve this error logging
-            error!(
-                "Updating versions repo failed: {}\n{}",
-                error,
-                error.backtrace()
-            );
-            for cause in error.iter_causes() {
-                error!("caused by: {}", cause);
+        if fetch_requested || last_fetch.map_or(true, |t| t.elapsed() >= poll_interval) {
+            if let Err(error) = repo.update() {
+                // TODO improve this error logging
+                error!(
+                    "Updating versions repo failed: {}\n{}",
+                    error,
+                    error.backtrace()
+                );
+                for cause in error.iter_causes() {
+                    error!("caused by: {}", cause);
+                }
+                if shutdown.sleep(Duration::from_millis(1000)) {
+                    break;
+                }
+                continue;
             }
-            thread::sleep(Duration::from_millis(1000));
-            continue;
+            last_fetch = Some(Instant::now());
+        }
+
+        if config_version != Some(repo.version()) {
+            reload_config(&repo, &service_state);
+            config_version = Some(repo.version());
         }
 
-        if let Err(error) = run_one_transition(&repo.repo, &service_state, Utc::now()) {
+        let result = run_transitions(&repo.repo, &service_state, Utc::now());
+        if let Err(error) = &result {
             error!("Transition failed: {}\n{}", error, error.backtrace());
             for cause in error.iter_causes() {
                 error!("caused by: {}", cause);
             }
         }
 
-        thread::sleep(Duration::from_millis(1000));
+        let mut timeout = last_fetch.map_or(Duration::from_secs(0), |t| {
+            poll_interval - t.elapsed().min(poll_interval)
+        });
+        if let Some(until_scheduled) = until_next_scheduled(&service_state, Utc::now()) {
+            timeout = timeout.min(until_scheduled);
+        }
+        if result.is_err() {
+            timeout = timeout.min(RETRY_INTERVAL);
+        }
+        fetch_requested = service_state.trigger.wait(timeout);
+        if shutdown.is_requested() {
+            break;
+        }
     }
+
+    api.join().expect("API thread panicked");
+    if let Some(deployer_watch) = deployer_watch {
+        deployer_watch
+            .join()
+            .expect("deployer watch thread panicked");
+    }
+
+    info!("Transitioner stopped.");
+
+    Ok(())
 }
 
 fn main() {
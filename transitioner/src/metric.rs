@@ -0,0 +1,307 @@
+//! The `Metric` precondition, which checks a query against a
+//! Prometheus-compatible HTTP API.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use failure::{bail, format_err, Error};
+use log::{info, warn};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use super::duration::ConfigDuration;
+use super::http_check::HeaderValue;
+use super::precondition::PreconditionResult;
+use super::PendingTransitionInfo;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MetricConfig {
+    /// The base URL of the Prometheus-compatible API, e.g.
+    /// `http://prometheus:9090`.
+    pub url: String,
+    /// The PromQL query. `$source`, `$target` and `$resources` (regex
+    /// escaped and joined with `|`, for regex matchers in double-quoted
+    /// strings) are replaced.
+    pub query: String,
+    /// Every value the query returns has to compare like this to the
+    /// threshold, e.g. `error rate < 0.01`.
+    pub comparison: Comparison,
+    pub threshold: f64,
+    /// The resolution of the query over the soak time.
+    #[serde(default = "default_step")]
+    pub step: ConfigDuration,
+    /// Whether the check passes if the query doesn't return any data.
+    #[serde(default)]
+    pub allow_no_data: bool,
+    /// Additional headers, e.g. for authentication.
+    #[serde(default)]
+    pub headers: BTreeMap<String, HeaderValue>,
+}
+
+fn default_step() -> ConfigDuration {
+    ConfigDuration(chrono::Duration::minutes(1))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Comparison {
+    #[serde(rename = "<")]
+    Less,
+    #[serde(rename = "<=")]
+    LessOrEqual,
+    #[serde(rename = ">")]
+    Greater,
+    #[serde(rename = ">=")]
+    GreaterOrEqual,
+    #[serde(rename = "==")]
+    Equal,
+    #[serde(rename = "!=")]
+    NotEqual,
+}
+
+impl Comparison {
+    fn holds(self, value: f64, threshold: f64) -> bool {
+        match self {
+            Comparison::Less => value < threshold,
+            Comparison::LessOrEqual => value <= threshold,
+            Comparison::Greater => value > threshold,
+            Comparison::GreaterOrEqual => value >= threshold,
+            Comparison::Equal => value == threshold,
+            Comparison::NotEqual => value != threshold,
+        }
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Comparison::Less => "<",
+            Comparison::LessOrEqual => "<=",
+            Comparison::Greater => ">",
+            Comparison::GreaterOrEqual => ">=",
+            Comparison::Equal => "==",
+            Comparison::NotEqual => "!=",
+        })
+    }
+}
+
+/// Checks the metric, over the soak time up to now if the transition has
+/// one, so that all values since the rollout count.
+pub fn check_metric(
+    transition: &PendingTransitionInfo,
+    config: &MetricConfig,
+    soak_time: Option<ConfigDuration>,
+    client: &reqwest::Client,
+    now: DateTime<Utc>,
+) -> Result<PreconditionResult, Error> {
+    let query = query(transition, config);
+    let base_url = config.url.trim_end_matches('/');
+    let mut request = match soak_time {
+        Some(soak_time) => client
+            .get(&format!("{}/api/v1/query_range", base_url))
+            .query(&[
+                ("query", query.clone()),
+                ("start", (now - soak_time.0).timestamp().to_string()),
+                ("end", now.timestamp().to_string()),
+                ("step", format!("{}s", config.step.0.num_seconds().max(1))),
+            ]),
+        None => client.get(&format!("{}/api/v1/query", base_url)).query(&[
+            ("query", query.clone()),
+            ("time", now.timestamp().to_string()),
+        ]),
+    };
+    for (name, value) in &config.headers {
+        request = request.header(name.as_str(), value.resolve()?.as_str());
+    }
+    let mut response = request.send()?;
+    if !response.status().is_success() {
+        bail!(
+            "query against {} returned {}: {}",
+            base_url,
+            response.status(),
+            response.text().unwrap_or_default()
+        );
+    }
+    let body: Value = response.json()?;
+    let values = query_values(&body)?;
+
+    let result = evaluate(config, &values);
+    match &result {
+        PreconditionResult::Success => info!("Metric check ok: {}", query),
+        _ => warn!("Metric check negative for {}: {:?}", query, result),
+    }
+    Ok(result)
+}
+
+fn query(transition: &PendingTransitionInfo, config: &MetricConfig) -> String {
+    // PromQL strings have escape sequences of their own, so the backslashes
+    // of the regex need to be escaped again
+    let resources: Vec<String> = transition
+        .resources
+        .iter()
+        .map(|r| regex::escape(r).replace('\\', "\\\\"))
+        .collect();
+    config
+        .query
+        .replace("$source", &transition.source)
+        .replace("$target", &transition.target)
+        .replace("$resources", &resources.join("|"))
+}
+
+fn evaluate(config: &MetricConfig, values: &[f64]) -> PreconditionResult {
+    if values.is_empty() {
+        return if config.allow_no_data {
+            PreconditionResult::Success
+        } else {
+            PreconditionResult::Blocked {
+                message: "metric query returned no data".to_string(),
+            }
+        };
+    }
+    match values
+        .iter()
+        .find(|v| !config.comparison.holds(**v, config.threshold))
+    {
+        Some(value) => PreconditionResult::Blocked {
+            message: format!(
+                "metric is {}, needs to be {} {}",
+                value, config.comparison, config.threshold
+            ),
+        },
+        None => PreconditionResult::Success,
+    }
+}
+
+/// All values in a query response, without NaNs.
+fn query_values(body: &Value) -> Result<Vec<f64>, Error> {
+    if body["status"] != "success" {
+        bail!(
+            "metric query failed: {}",
+            body["error"].as_str().unwrap_or("unknown error")
+        );
+    }
+    let data = &body["data"];
+    let samples: Vec<&Value> = match data["resultType"].as_str() {
+        Some("scalar") => vec![&data["result"]],
+        Some("vector") => data["result"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|series| &series["value"])
+            .collect(),
+        Some("matrix") => data["result"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .flat_map(|series| series["values"].as_array().into_iter().flatten())
+            .collect(),
+        other => bail!("unsupported metric query result type {:?}", other),
+    };
+    let mut values = Vec::with_capacity(samples.len());
+    for sample in samples {
+        let value = sample[1]
+            .as_str()
+            .ok_or_else(|| format_err!("invalid sample {}", sample))?;
+        let value: f64 = value
+            .parse()
+            .map_err(|_| format_err!("invalid sample value {:?}", value))?;
+        if !value.is_nan() {
+            values.push(value);
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::http_check::test::{stub_server, transition};
+    use chrono::TimeZone;
+
+    fn config(url: String) -> MetricConfig {
+        MetricConfig {
+            url,
+            query: r#"rate(errors{env="$source",app=~"$resources"}[5m])"#.to_string(),
+            comparison: Comparison::Less,
+            threshold: 0.01,
+            step: default_step(),
+            allow_no_data: false,
+            headers: Default::default(),
+        }
+    }
+
+    #[test]
+    fn test_metric_check() {
+        let (url, requests) = stub_server(vec![
+            (
+                200,
+                r#"{"status": "success", "data": {"resultType": "vector", "result": [
+                    {"metric": {"app": "api"}, "value": [1570190400, "0.001"]}
+                ]}}"#,
+            ),
+            (
+                200,
+                r#"{"status": "success", "data": {"resultType": "matrix", "result": [
+                    {"metric": {"app": "api"}, "values": [[1570188600, "0.001"], [1570190400, "0.2"]]}
+                ]}}"#,
+            ),
+            (
+                200,
+                r#"{"status": "success", "data": {"resultType": "vector", "result": []}}"#,
+            ),
+        ]);
+        let url = url.trim_end_matches("/check").to_string();
+        let client = reqwest::Client::new();
+        let now = Utc.ymd(2019, 10, 4).and_hms(12, 0, 0);
+
+        assert_eq!(
+            check_metric(&transition(), &config(url.clone()), None, &client, now).unwrap(),
+            PreconditionResult::Success
+        );
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("GET /api/v1/query?query=rate"));
+        assert!(request.contains("dev"));
+        assert!(request.contains("time=1570190400"));
+
+        let soak_time = Some(ConfigDuration(chrono::Duration::minutes(30)));
+        assert_eq!(
+            check_metric(&transition(), &config(url.clone()), soak_time, &client, now).unwrap(),
+            PreconditionResult::Blocked {
+                message: "metric is 0.2, needs to be < 0.01".to_string()
+            }
+        );
+        let request = requests.recv().unwrap();
+        assert!(request.starts_with("GET /api/v1/query_range?"));
+        assert!(request.contains("start=1570188600"));
+        assert!(request.contains("step=60s"));
+
+        assert_eq!(
+            check_metric(&transition(), &config(url), None, &client, now).unwrap(),
+            PreconditionResult::Blocked {
+                message: "metric query returned no data".to_string()
+            }
+        );
+    }
+
+    #[test]
+    fn test_query() {
+        let mut transition = transition();
+        transition.resources = vec!["api".to_string(), "api.v2".to_string()];
+        assert_eq!(
+            query(&transition, &config("http://prometheus".to_string())),
+            r#"rate(errors{env="dev",app=~"api|api\.v2"}[5m])"#
+        );
+    }
+
+    #[test]
+    fn test_query_values() {
+        let scalar = serde_json::json!({
+            "status": "success",
+            "data": { "resultType": "scalar", "result": [1570190400, "3"] }
+        });
+        assert_eq!(query_values(&scalar).unwrap(), vec![3.0]);
+        let error = serde_json::json!({ "status": "error", "error": "parse error" });
+        assert!(query_values(&error).is_err());
+    }
+}
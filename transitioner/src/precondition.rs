@@ -1,95 +1,613 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use chrono::{DateTime, Utc};
 use failure::Error;
+use git2::Repository;
 use log::{error, info, warn};
 use serde_derive::{Deserialize, Serialize};
 
-use common::deployment::{AllDeployerStatus, RolloutStatus};
+use common::deployment::{
+    AllDeployerStatus, DeployerStatus, PolicyViolation, ResourceState, RolloutStatus,
+    RolloutStatusReason,
+};
+use common::git::TreeZipper;
+use common::merge::{self, JsonnetVm};
+use common::policy::Policies;
+use common::repo::id_to_oid;
+use common::transitions::SkipReason;
 
-use super::{PendingTransitionInfo, ServiceState};
+use super::duration::ConfigDuration;
+use super::http_check::{self, HttpConfig};
+use super::job::{self, JobConfig};
+use super::metric::{self, MetricConfig};
+use super::time_window::TimeWindowConfig;
+use super::{collect_version_files, PendingTransitionInfo, ServiceState};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Precondition {
     SourceClean,
+    /// Holds back resources that violate policies, either as deployed in the
+    /// source env or as they would be deployed in the target env.
+    PolicyCompliant,
+    /// Waits until someone approves the transition's change set, for the
+    /// given approver group if there is one.
+    ManualApproval {
+        #[serde(default)]
+        group: Option<String>,
+    },
+    /// Holds back resources until their version has been cleanly rolled out
+    /// in the source env for at least this long.
+    MinimumSoakTime {
+        duration: ConfigDuration,
+    },
+    /// Runs a Kubernetes Job, e.g. smoke tests, in the source env's
+    /// namespace, once per change set.
+    Job(JobConfig),
+    /// Posts the pending transition to a URL, e.g. of a CI server, which
+    /// decides whether it may go ahead.
+    Http(HttpConfig),
+    /// Checks a metric of the source env against a threshold, e.g. its
+    /// error rate.
+    Metric(MetricConfig),
+    /// Only lets the transition run within a time window, e.g. during
+    /// office hours.
+    TimeWindow(TimeWindowConfig),
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl Precondition {
+    /// Whether checking the precondition does more than look, e.g. starts a
+    /// job. Dry runs don't check these.
+    pub fn has_side_effects(&self) -> bool {
+        match self {
+            Precondition::Job(_) | Precondition::Http(_) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(tag = "result")]
 pub enum PreconditionResult {
     Success,
-    // TODO return more information here
-    Blocked { message: String },
-    Failed { message: String },
+    Blocked {
+        message: String,
+    },
+    Failed {
+        message: String,
+    },
+    /// The transition can go ahead without these resources. Maps the
+    /// resources to why they are held back.
+    Held {
+        resources: BTreeMap<String, String>,
+    },
+    /// The change set needs to be approved first.
+    AwaitingApproval {
+        group: Option<String>,
+    },
+    /// The transition shouldn't run now.
+    Skipped(SkipReason),
 }
 
+/// Checks each resource the transition would change, holding back the ones
+/// for which `check` returns a reason, and the ones the deployer doesn't know.
+fn check_resources(
+    transition: &PendingTransitionInfo,
+    env_status: &DeployerStatus,
+    check: impl Fn(&str, &ResourceState) -> Option<String>,
+) -> PreconditionResult {
+    let held: BTreeMap<_, _> = transition
+        .resources
+        .iter()
+        .filter_map(|resource| {
+            let reason = match env_status.status_by_resource.get(resource) {
+                Some(state) => check(resource, state)?,
+                None => "not known to the deployer".to_string(),
+            };
+            Some((resource.clone(), reason))
+        })
+        .collect();
+    if held.is_empty() {
+        PreconditionResult::Success
+    } else {
+        PreconditionResult::Held { resources: held }
+    }
+}
+
+/// The transition's minimum soak time, if it has one.
+pub fn soak_time(preconditions: &[Precondition]) -> Option<ConfigDuration> {
+    preconditions.iter().find_map(|p| match p {
+        Precondition::MinimumSoakTime { duration } => Some(*duration),
+        _ => None,
+    })
+}
+
+/// Checks one precondition; `soak_time` is the transition's minimum soak
+/// time, which `Metric` evaluates its query over.
 pub fn check_precondition(
     transition: &PendingTransitionInfo,
     precondition: &Precondition,
+    soak_time: Option<ConfigDuration>,
     service_state: &ServiceState,
+    repo: &Repository,
+    now: DateTime<Utc>,
 ) -> Result<PreconditionResult, Error> {
     match precondition {
         Precondition::SourceClean => check_source_clean(transition, service_state),
+        Precondition::PolicyCompliant => check_policy_compliant(transition, service_state, repo),
+        Precondition::ManualApproval { group } => Ok(check_manual_approval(transition, group)),
+        Precondition::MinimumSoakTime { duration } => {
+            check_minimum_soak_time(transition, service_state, *duration, now)
+        }
+        Precondition::Job(config) => job::check_job(transition, config, service_state, repo),
+        Precondition::Http(config) => http_check::check_http(transition, config),
+        Precondition::Metric(config) => {
+            metric::check_metric(transition, config, soak_time, &service_state.client, now)
+        }
+        Precondition::TimeWindow(config) => {
+            let window = config.parse()?;
+            if window.contains(now) {
+                info!("TimeWindow check ok");
+                Ok(PreconditionResult::Success)
+            } else {
+                info!("Transition is outside of its time window");
+                Ok(PreconditionResult::Skipped(SkipReason::OutsideTimeWindow {
+                    next_start: window.next_start(now),
+                }))
+            }
+        }
     }
 }
 
-fn check_source_clean(
+fn check_manual_approval(
+    transition: &PendingTransitionInfo,
+    group: &Option<String>,
+) -> PreconditionResult {
+    match &transition.approval {
+        Some(approval)
+            if approval.change_set == transition.change_set
+                && (group.is_none() || approval.group == *group) =>
+        {
+            info!(
+                "ManualApproval check ok: approved by {} at {}",
+                approval.author, approval.time
+            );
+            PreconditionResult::Success
+        }
+        _ => {
+            info!("Transition waits for approval of {}", transition.change_set);
+            PreconditionResult::AwaitingApproval {
+                group: group.clone(),
+            }
+        }
+    }
+}
+
+/// Gets the deployer status of the transition's source env, or the result of
+/// the check if it can't be determined.
+fn get_source_status(
     transition: &PendingTransitionInfo,
     service_state: &ServiceState,
-) -> Result<PreconditionResult, Error> {
+    check: &str,
+) -> Result<Result<DeployerStatus, PreconditionResult>, Error> {
     let deployer_url = if let Some(url) = service_state.env.deployer_url.as_ref() {
         url
     } else {
         error!(
-            "Transition failed: SourceClean check failed because no deployer url is configured!"
+            "Transition failed: {} check failed because no deployer url is configured!",
+            check
         );
-        return Ok(PreconditionResult::Failed {
+        return Ok(Err(PreconditionResult::Failed {
             message: "no deployer url configured".to_string(),
-        });
+        }));
     };
     let url = format!("{}/status", deployer_url);
-    let status: AllDeployerStatus = service_state
+    let mut status: AllDeployerStatus = service_state
         .client
         .get(&url)
         .send()?
         .error_for_status()?
         .json()?;
 
-    let env_status = if let Some(env_status) = status.deployers.get(&transition.source) {
+    let env_status = if let Some(env_status) = status.deployers.remove(&transition.source) {
         env_status
     } else {
-        return Ok(PreconditionResult::Blocked {
+        return Ok(Err(PreconditionResult::Blocked {
             message: "deployer does not yet know about source env".to_string(),
-        });
+        }));
     };
 
     if env_status.deployed_version != transition.current_version {
-        return Ok(PreconditionResult::Blocked {
+        return Ok(Err(PreconditionResult::Blocked {
             message: format!(
                 "deployer is on version {}, we're on version {}",
                 env_status.deployed_version, transition.current_version
             ),
+        }));
+    }
+
+    Ok(Ok(env_status))
+}
+
+/// Why a resource isn't clean in the source env, if it isn't.
+fn source_clean_verdict(state: &ResourceState) -> Option<String> {
+    match state {
+        ResourceState::NotDeployed => Some("not deployed".to_string()),
+        ResourceState::Deployed {
+            version,
+            expected_version,
+            status,
+        } => match status {
+            RolloutStatusReason::Failed { message } => Some(format!("rollout failed: {}", message)),
+            RolloutStatusReason::Clean if version == expected_version => None,
+            _ => Some("rollout still in progress".to_string()),
+        },
+        ResourceState::PolicyViolation { .. } => Some("violates policies".to_string()),
+        ResourceState::MissingBaseFile { base_file, .. } => {
+            Some(format!("base file {} is missing", base_file))
+        }
+    }
+}
+
+fn check_source_clean(
+    transition: &PendingTransitionInfo,
+    service_state: &ServiceState,
+) -> Result<PreconditionResult, Error> {
+    let env_status = match get_source_status(transition, service_state, "SourceClean")? {
+        Ok(env_status) => env_status,
+        Err(result) => return Ok(result),
+    };
+
+    if env_status.rollout_status == RolloutStatus::Outdated {
+        info!("Transition blocked: Changes pending");
+        return Ok(PreconditionResult::Blocked {
+            message: "changes pending".to_string(),
         });
     }
 
-    match env_status.rollout_status {
-        RolloutStatus::InProgress => {
-            info!("Transition blocked: Rollout still in progress");
-            Ok(PreconditionResult::Blocked {
-                message: "rollout still in progress".to_string(),
-            })
+    let result = check_resources(transition, &env_status, |_, state| {
+        source_clean_verdict(state)
+    });
+    match &result {
+        PreconditionResult::Held { resources } => {
+            warn!("SourceClean check holds back {:?}", resources);
+        }
+        _ => info!("SourceClean check ok"),
+    }
+    Ok(result)
+}
+
+fn policy_names(violations: &[PolicyViolation]) -> String {
+    violations
+        .iter()
+        .map(|v| v.policy.as_str())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Evaluates the target env's policies against the resources as the
+/// transition would deploy them there, i.e. with the source env's version
+/// files merged into the target env's base files. Returns why resources would
+/// violate them. Resources without a base file in the target are held back
+/// before preconditions are checked.
+fn target_policy_violations(
+    transition: &PendingTransitionInfo,
+    repo: &Repository,
+) -> Result<BTreeMap<String, String>, Error> {
+    let tree = repo
+        .find_commit(id_to_oid(transition.current_version))?
+        .tree()?;
+    let policies = Policies::load_tree(repo, tree.clone())?;
+    let mut source = TreeZipper::from(repo, tree.clone());
+    source.descend(&transition.source)?;
+    source.descend("version")?;
+    let target_base = Path::new(&transition.target).join("base");
+    let mut vm = JsonnetVm::new();
+    let mut held = BTreeMap::new();
+    for file in collect_version_files(&source)? {
+        if !transition.resources.contains(&file.resource) {
+            continue;
+        }
+        let base_file_name = target_base.join(&file.path);
+        let base_file = match tree.get_path(&base_file_name) {
+            Ok(entry) => entry.to_object(repo)?.peel_to_blob()?,
+            Err(_) => continue,
+        };
+        let version_file = repo.find_blob(file.id)?;
+        let merged = merge::merge_version_file(
+            &mut vm,
+            &file.path,
+            &base_file_name,
+            base_file.content(),
+            version_file.content(),
+        );
+        let reason = match merged {
+            Ok(merged) => {
+                let violations = policies.check(&transition.target, &merged);
+                if violations.is_empty() {
+                    continue;
+                }
+                format!(
+                    "violates {} in {}",
+                    policy_names(&violations),
+                    transition.target
+                )
+            }
+            Err(e) => format!("can't be merged into its base file: {}", e),
+        };
+        held.insert(file.resource, reason);
+    }
+    Ok(held)
+}
+
+fn check_policy_compliant(
+    transition: &PendingTransitionInfo,
+    service_state: &ServiceState,
+    repo: &Repository,
+) -> Result<PreconditionResult, Error> {
+    let mut held = target_policy_violations(transition, repo)?;
+
+    let env_status = match get_source_status(transition, service_state, "PolicyCompliant")? {
+        Ok(env_status) => env_status,
+        Err(result) => return Ok(result),
+    };
+    let source_result = check_resources(transition, &env_status, |_, state| match state {
+        ResourceState::PolicyViolation { violations, .. } => {
+            Some(format!("violates {}", policy_names(violations)))
         }
-        RolloutStatus::Outdated => {
-            info!("Transition blocked: Changes pending");
-            Ok(PreconditionResult::Blocked {
-                message: "changes pending".to_string(),
-            })
+        _ => None,
+    });
+    if let PreconditionResult::Held { resources } = source_result {
+        for (resource, reason) in resources {
+            held.entry(resource).or_insert(reason);
         }
-        RolloutStatus::Clean => {
-            info!("SourceClean check ok");
-            Ok(PreconditionResult::Success)
+    }
+
+    if held.is_empty() {
+        info!("PolicyCompliant check ok");
+        Ok(PreconditionResult::Success)
+    } else {
+        info!("PolicyCompliant check holds back {:?}", held);
+        Ok(PreconditionResult::Held { resources: held })
+    }
+}
+
+/// Holds back resources that have no base file in the target env, so that the
+/// deployer doesn't get version files it can't deploy. Every transition
+/// checks this, before its preconditions.
+pub fn check_base_file_exists(
+    transition: &PendingTransitionInfo,
+    repo: &Repository,
+) -> Result<PreconditionResult, Error> {
+    let tree = repo
+        .find_commit(id_to_oid(transition.current_version))?
+        .tree()?;
+    let mut source = TreeZipper::from(repo, tree.clone());
+    source.descend(&transition.source)?;
+    source.descend("version")?;
+    let target_base = Path::new(&transition.target).join("base");
+    let held: BTreeMap<_, _> = collect_version_files(&source)?
+        .into_iter()
+        .filter(|file| transition.resources.contains(&file.resource))
+        .filter(|file| tree.get_path(&target_base.join(&file.path)).is_err())
+        .map(|file| {
+            let reason = format!(
+                "no base file {} in {}",
+                file.path.display(),
+                transition.target
+            );
+            (file.resource, reason)
+        })
+        .collect();
+    if held.is_empty() {
+        info!("Base file check ok");
+        Ok(PreconditionResult::Success)
+    } else {
+        warn!("Base file check holds back {:?}", held);
+        Ok(PreconditionResult::Held { resources: held })
+    }
+}
+
+/// Why a resource hasn't soaked long enough in the source env, if it hasn't.
+fn soak_time_verdict(
+    env_status: &DeployerStatus,
+    resource: &str,
+    state: &ResourceState,
+    duration: ConfigDuration,
+    now: DateTime<Utc>,
+) -> Option<String> {
+    if let Some(verdict) = source_clean_verdict(state) {
+        return Some(verdict);
+    }
+    let clean_since = match (state, env_status.clean_since.get(resource)) {
+        (ResourceState::Deployed { version, .. }, Some(clean_since))
+            if clean_since.version == *version =>
+        {
+            clean_since.since
         }
-        RolloutStatus::Failed => {
-            warn!("Transition failed: Source rollout failed");
-            Ok(PreconditionResult::Failed {
-                message: "rollout failed".to_string(),
-            })
+        _ => return Some("not known to be clean yet".to_string()),
+    };
+    let soaked = now.signed_duration_since(clean_since);
+    if soaked >= duration.0 {
+        None
+    } else {
+        Some(format!(
+            "clean for only {} of {}",
+            ConfigDuration(soaked),
+            duration
+        ))
+    }
+}
+
+fn check_minimum_soak_time(
+    transition: &PendingTransitionInfo,
+    service_state: &ServiceState,
+    duration: ConfigDuration,
+    now: DateTime<Utc>,
+) -> Result<PreconditionResult, Error> {
+    let env_status = match get_source_status(transition, service_state, "MinimumSoakTime")? {
+        Ok(env_status) => env_status,
+        Err(result) => return Ok(result),
+    };
+
+    let result = check_resources(transition, &env_status, |resource, state| {
+        soak_time_verdict(&env_status, resource, state, duration, now)
+    });
+    match &result {
+        PreconditionResult::Held { resources } => {
+            info!("MinimumSoakTime check holds back {:?}", resources);
         }
+        _ => info!("MinimumSoakTime check ok"),
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_source_clean_holds_failed_resources() {
+        let version = "22817d2a9c7fc1f62d5670ca1e44948446543973".parse().unwrap();
+        let deployed = |status| ResourceState::Deployed {
+            version,
+            expected_version: version,
+            status,
+        };
+        let mut status_by_resource = HashMap::new();
+        status_by_resource.insert("ok".to_string(), deployed(RolloutStatusReason::Clean));
+        status_by_resource.insert(
+            "broken".to_string(),
+            deployed(RolloutStatusReason::Failed {
+                message: "crash loop".to_string(),
+            }),
+        );
+        status_by_resource.insert("unchanged".to_string(), ResourceState::NotDeployed);
+        let env_status = DeployerStatus {
+            deployed_version: version,
+            last_successfully_deployed_version: None,
+            rollout_status: RolloutStatus::Failed,
+            status_by_resource,
+            clean_since: HashMap::new(),
+        };
+        let transition = PendingTransitionInfo {
+            source: "dev".to_string(),
+            target: "prod".to_string(),
+            current_version: version,
+            resources: vec!["ok".to_string(), "broken".to_string(), "new".to_string()],
+            versions: BTreeMap::new(),
+            change_set: version,
+            approval: None,
+        };
+
+        let mut expected = BTreeMap::new();
+        expected.insert(
+            "broken".to_string(),
+            "rollout failed: crash loop".to_string(),
+        );
+        expected.insert("new".to_string(), "not known to the deployer".to_string());
+        assert_eq!(
+            check_resources(&transition, &env_status, |_, state| source_clean_verdict(
+                state
+            )),
+            PreconditionResult::Held {
+                resources: expected
+            }
+        );
+    }
+
+    #[test]
+    fn test_minimum_soak_time_holds_fresh_versions() {
+        use chrono::{Duration, TimeZone};
+        use common::deployment::CleanSince;
+
+        let version = "22817d2a9c7fc1f62d5670ca1e44948446543973".parse().unwrap();
+        let old_version = "0000000000000000000000000000000000000001".parse().unwrap();
+        let now = Utc.ymd(2019, 10, 4).and_hms(12, 0, 0);
+        let clean = ResourceState::Deployed {
+            version,
+            expected_version: version,
+            status: RolloutStatusReason::Clean,
+        };
+        let mut status_by_resource = HashMap::new();
+        let mut clean_since = HashMap::new();
+        for (resource, clean_version, hours) in &[
+            ("soaked", version, 2),
+            ("fresh", version, 0),
+            ("redeployed", old_version, 5),
+        ] {
+            status_by_resource.insert(resource.to_string(), clean.clone());
+            clean_since.insert(
+                resource.to_string(),
+                CleanSince {
+                    version: *clean_version,
+                    since: now - Duration::hours(*hours) - Duration::minutes(10),
+                },
+            );
+        }
+        let env_status = DeployerStatus {
+            deployed_version: version,
+            last_successfully_deployed_version: Some(version),
+            rollout_status: RolloutStatus::Clean,
+            status_by_resource,
+            clean_since,
+        };
+        let transition = PendingTransitionInfo {
+            source: "dev".to_string(),
+            target: "prod".to_string(),
+            current_version: version,
+            resources: vec![
+                "soaked".to_string(),
+                "fresh".to_string(),
+                "redeployed".to_string(),
+            ],
+            versions: BTreeMap::new(),
+            change_set: version,
+            approval: None,
+        };
+        let duration = ConfigDuration(Duration::hours(1));
+
+        let mut expected = BTreeMap::new();
+        expected.insert("fresh".to_string(), "clean for only 10m of 1h".to_string());
+        expected.insert(
+            "redeployed".to_string(),
+            "not known to be clean yet".to_string(),
+        );
+        assert_eq!(
+            check_resources(&transition, &env_status, |resource, state| {
+                soak_time_verdict(&env_status, resource, state, duration, now)
+            }),
+            PreconditionResult::Held {
+                resources: expected
+            }
+        );
+    }
+
+    #[test]
+    fn test_target_policy_violations() {
+        use common::repo::oid_to_id;
+        use git_fixture::RepoFixture;
+
+        let fixture =
+            RepoFixture::from_str(include_str!("./fixtures/target_policies.yaml")).unwrap();
+        let version = oid_to_id(fixture.get_commit("head").unwrap());
+        let transition = PendingTransitionInfo {
+            source: "dev".to_string(),
+            target: "prod".to_string(),
+            current_version: version,
+            resources: vec!["foo".to_string(), "bar".to_string(), "baz".to_string()],
+            versions: BTreeMap::new(),
+            change_set: version,
+            approval: None,
+        };
+
+        // the policy only applies to prod, and baz has no base file there
+        let mut expected = BTreeMap::new();
+        expected.insert("bar".to_string(), "violates no-latest in prod".to_string());
+        assert_eq!(
+            target_policy_violations(&transition, &fixture.repo).unwrap(),
+            expected
+        );
     }
 }
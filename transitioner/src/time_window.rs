@@ -0,0 +1,136 @@
+//! The `TimeWindow` precondition, which only lets transitions run at
+//! certain times of the week.
+
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Duration, NaiveTime, TimeZone, Utc, Weekday};
+use chrono_tz::Tz;
+use failure::{format_err, Error};
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeWindowConfig {
+    /// An IANA time zone like `Europe/Berlin`. Defaults to UTC.
+    pub timezone: Option<String>,
+    /// The days on which the window starts, like `Mon`. Defaults to every
+    /// day.
+    #[serde(default)]
+    pub days: Vec<String>,
+    /// The start of the window, like `09:00`.
+    pub start: String,
+    /// The end of the window. If it's before the start, the window ends on
+    /// the next day.
+    pub end: String,
+}
+
+/// A parsed `TimeWindowConfig`.
+#[derive(Debug)]
+pub struct TimeWindow {
+    timezone: Tz,
+    days: Vec<Weekday>,
+    start: NaiveTime,
+    end: NaiveTime,
+}
+
+impl TimeWindowConfig {
+    pub fn parse(&self) -> Result<TimeWindow, Error> {
+        let timezone = match &self.timezone {
+            Some(timezone) => timezone
+                .parse()
+                .map_err(|e| format_err!("invalid time zone {:?}: {}", timezone, e))?,
+            None => Tz::UTC,
+        };
+        let days = self
+            .days
+            .iter()
+            .map(|day| Weekday::from_str(day).map_err(|_| format_err!("invalid day {:?}", day)))
+            .collect::<Result<_, _>>()?;
+        let parse_time = |time: &str| {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .map_err(|_| format_err!("invalid time {:?}, expected HH:MM", time))
+        };
+        Ok(TimeWindow {
+            timezone,
+            days,
+            start: parse_time(&self.start)?,
+            end: parse_time(&self.end)?,
+        })
+    }
+}
+
+impl TimeWindow {
+    fn on_day(&self, day: Weekday) -> bool {
+        self.days.is_empty() || self.days.contains(&day)
+    }
+
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        let local = now.with_timezone(&self.timezone);
+        let time = local.time();
+        let day = local.weekday();
+        if self.start < self.end {
+            self.on_day(day) && self.start <= time && time < self.end
+        } else {
+            (self.on_day(day) && self.start <= time) || (self.on_day(day.pred()) && time < self.end)
+        }
+    }
+
+    /// When the next window starts after `now`.
+    pub fn next_start(&self, now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let today = now.with_timezone(&self.timezone).date().naive_local();
+        (0..=7)
+            .map(|days| today + Duration::days(days))
+            .filter(|date| self.on_day(date.weekday()))
+            .filter_map(|date| {
+                self.timezone
+                    .from_local_datetime(&date.and_time(self.start))
+                    .earliest()
+            })
+            .map(|start| start.with_timezone(&Utc))
+            .find(|start| *start > now)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn window(days: &[&str], start: &str, end: &str) -> TimeWindow {
+        TimeWindowConfig {
+            timezone: Some("Europe/Berlin".to_string()),
+            days: days.iter().map(|d| d.to_string()).collect(),
+            start: start.to_string(),
+            end: end.to_string(),
+        }
+        .parse()
+        .unwrap()
+    }
+
+    #[test]
+    fn test_time_window() {
+        let office_hours = window(&["Mon", "Tue", "Wed", "Thu"], "09:00", "16:00");
+        // 2019-10-03 is a Thursday; Berlin is at UTC+2
+        assert!(office_hours.contains(Utc.ymd(2019, 10, 3).and_hms(7, 0, 0)));
+        assert!(!office_hours.contains(Utc.ymd(2019, 10, 3).and_hms(6, 59, 0)));
+        assert!(!office_hours.contains(Utc.ymd(2019, 10, 3).and_hms(14, 0, 0)));
+        assert!(!office_hours.contains(Utc.ymd(2019, 10, 4).and_hms(8, 0, 0)));
+        assert_eq!(
+            office_hours.next_start(Utc.ymd(2019, 10, 3).and_hms(15, 0, 0)),
+            Some(Utc.ymd(2019, 10, 7).and_hms(7, 0, 0))
+        );
+
+        let nights = window(&["Fri"], "22:00", "02:00");
+        assert!(nights.contains(Utc.ymd(2019, 10, 4).and_hms(21, 0, 0)));
+        assert!(nights.contains(Utc.ymd(2019, 10, 4).and_hms(23, 0, 0)));
+        assert!(!nights.contains(Utc.ymd(2019, 10, 5).and_hms(21, 0, 0)));
+
+        assert!(TimeWindowConfig {
+            timezone: Some("Mars/Olympus".to_string()),
+            days: vec![],
+            start: "09:00".to_string(),
+            end: "16:00".to_string(),
+        }
+        .parse()
+        .is_err());
+    }
+}
@@ -1,5 +1,6 @@
 import * as React from "react";
 
+import Button from "@material-ui/core/Button";
 import Grid from "@material-ui/core/Grid";
 import Paper from "@material-ui/core/Paper";
 import Table from "@material-ui/core/Table";
@@ -17,6 +18,7 @@ import {
 
 interface IHistoryViewProps {
     data: IUiData;
+    loadOlderHistory: () => void;
 }
 
 function getGroup(change: ResourceRepoChange): [string, string] | null {
@@ -94,6 +96,13 @@ export function HistoryView(props: IHistoryViewProps) {
                     </Table>
                 </Paper>
             </Grid>
+            {props.data.historyOffset > 0 && (
+                <Grid item xs={12}>
+                    <Button onClick={props.loadOlderHistory}>
+                        Load older commits
+                    </Button>
+                </Grid>
+            )}
         </Grid>
     );
 }
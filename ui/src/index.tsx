@@ -29,6 +29,16 @@ export type IDeployerResourceState =
           updated?: number;
           number?: number;
           available?: number;
+      }
+    | {
+          state: "PolicyViolation";
+          version: string;
+          violations: Array<{ policy: string; message: string }>;
+      }
+    | {
+          state: "MissingBaseFile";
+          version: string;
+          base_file: string;
       };
 
 interface IDeployerStatus {
@@ -36,13 +46,14 @@ interface IDeployerStatus {
     last_successfully_deployed_version: string | null;
     rollout_status: "InProgress" | "Clean" | "Outdated" | "Failed";
     status_by_resource: { [resource: string]: IDeployerResourceState };
+    clean_since: { [resource: string]: { version: string; since: string } };
 }
 
 interface ITransitionStatus {
     successful_runs: Array<{ time: string; committed_version: string }>;
     last_run: null | {
         time: string | null;
-        result: "Success" | "Skipped" | "Blocked" | "CheckFailed";
+        result: "Success" | "Skipped" | "Blocked" | "CheckFailed" | "AwaitingApproval";
     };
 }
 
@@ -50,21 +61,28 @@ interface IFullStatusMessage {
     type: "FullStatus";
     counter: number;
     deployers: { [key: string]: IDeployerStatus };
+    config_error: string | null;
     transitions: { [key: string]: ITransitionStatus };
-    resources: any;
-    history: any;
+    transitioner_config_error: string | null;
+    resources: { [name: string]: IResourceStatus };
+    history: IResourceRepoCommit[];
+    history_offset: number;
 }
 
 interface IDeployerStatusMessage {
     type: "DeployerStatus";
     counter: number;
-    deployers: { [key: string]: IDeployerStatus };
+    changed: { [key: string]: IDeployerStatus };
+    removed: string[];
+    config_error: string | null;
 }
 
 interface ITransitionStatusMessage {
     type: "TransitionStatus";
     counter: number;
-    transitions: { [key: string]: ITransitionStatus };
+    changed: { [key: string]: ITransitionStatus };
+    removed: string[];
+    config_error: string | null;
 }
 
 export interface IResourceVersion {
@@ -128,30 +146,45 @@ export interface IVersionsMessage {
     type: "Versions";
     counter: number;
     resources: { [name: string]: IResourceStatus };
-    history: IResourceRepoCommit[];
+    new_commits: IResourceRepoCommit[];
+}
+
+export interface IHistoryMessage {
+    type: "History";
+    offset: number;
+    commits: IResourceRepoCommit[];
 }
 
 export type Message =
     | IFullStatusMessage
     | IDeployerStatusMessage
     | ITransitionStatusMessage
-    | IVersionsMessage;
+    | IVersionsMessage
+    | IHistoryMessage;
 
 export interface IUiData {
     counter: number;
     deployers: { [key: string]: IDeployerStatus };
+    deployerConfigError: string | null;
     transitions: { [key: string]: ITransitionStatus };
+    transitionerConfigError: string | null;
     resources: { [name: string]: IResourceStatus };
     history: IResourceRepoCommit[];
+    /** The index of the first commit in `history` within the full history. */
+    historyOffset: number;
 }
 
-function useWebSocket(url: string, onMessage: (ev: MessageEvent) => void) {
+function useWebSocket(
+    getUrl: () => string,
+    onMessage: (ev: MessageEvent, socket: WebSocket) => void
+) {
     const ws: { current: WebSocket | null } = useRef(null);
     const timeout: { current: number | null } = useRef(null);
     const connect = () => {
-        ws.current = new WebSocket(url);
-        ws.current.onmessage = onMessage;
-        ws.current.onclose = (ev: CloseEvent) => {
+        const socket = new WebSocket(getUrl());
+        ws.current = socket;
+        socket.onmessage = (ev: MessageEvent) => onMessage(ev, socket);
+        socket.onclose = (ev: CloseEvent) => {
             if (ev.code === 1000) {
                 // ok
                 return;
@@ -174,22 +207,63 @@ function useWebSocket(url: string, onMessage: (ev: MessageEvent) => void) {
                 timeout.current = null;
             }
         };
-    }, [url]);
+    }, []);
+    return ws;
 }
 
-function applyMessage(data: IUiData, message: Message): IUiData {
-    const newData = { ...data };
-    if (message.type === "FullStatus" || message.type === "DeployerStatus") {
-        Object.assign(newData.deployers, message.deployers);
-    }
-
-    if (message.type === "FullStatus" || message.type === "TransitionStatus") {
-        Object.assign(newData.transitions, message.transitions);
+function withChanges<T>(
+    map: { [key: string]: T },
+    changed: { [key: string]: T },
+    removed: string[]
+): { [key: string]: T } {
+    const result = { ...map, ...changed };
+    for (const key of removed) {
+        delete result[key];
     }
+    return result;
+}
 
-    if (message.type === "FullStatus" || message.type === "Versions") {
-        Object.assign(newData.resources, message.resources);
-        newData.history = message.history;
+function applyMessage(data: IUiData, message: Message): IUiData {
+    const newData = { ...data };
+    switch (message.type) {
+        case "FullStatus":
+            newData.deployers = message.deployers;
+            newData.deployerConfigError = message.config_error;
+            newData.transitions = message.transitions;
+            newData.transitionerConfigError = message.transitioner_config_error;
+            newData.resources = message.resources;
+            newData.history = message.history;
+            newData.historyOffset = message.history_offset;
+            break;
+        case "DeployerStatus":
+            newData.deployers = withChanges(
+                data.deployers,
+                message.changed,
+                message.removed
+            );
+            newData.deployerConfigError = message.config_error;
+            break;
+        case "TransitionStatus":
+            newData.transitions = withChanges(
+                data.transitions,
+                message.changed,
+                message.removed
+            );
+            newData.transitionerConfigError = message.config_error;
+            break;
+        case "Versions":
+            newData.resources = { ...data.resources, ...message.resources };
+            newData.history = data.history.concat(message.new_commits);
+            break;
+        case "History":
+            // ignore pages that don't fit, e.g. after a reconnect
+            if (
+                message.offset + message.commits.length === data.historyOffset
+            ) {
+                newData.history = message.commits.concat(data.history);
+                newData.historyOffset = message.offset;
+            }
+            return newData;
     }
 
     newData.counter = message.counter;
@@ -209,17 +283,56 @@ function Page() {
         {
             counter: 0,
             deployers: {},
+            deployerConfigError: null,
             transitions: {},
+            transitionerConfigError: null,
             resources: {},
-            history: []
+            history: [],
+            historyOffset: 0
         }
     );
     const host = document.location ? document.location.host : "";
+    // the counter of the last update, to resume from after reconnecting
+    const counter: { current: number | null } = useRef(null);
 
-    useWebSocket("ws://" + host + "/api", (ev: MessageEvent) => {
-        const message: Message = JSON.parse(ev.data);
-        dispatchMessage(message);
-    });
+    const ws = useWebSocket(
+        () =>
+            "ws://" +
+            host +
+            "/api" +
+            (counter.current === null ? "" : "?since=" + counter.current),
+        (ev: MessageEvent, socket: WebSocket) => {
+            if (socket.readyState !== WebSocket.OPEN) {
+                return;
+            }
+            const message: Message = JSON.parse(ev.data);
+            if (message.type !== "History") {
+                if (
+                    message.type !== "FullStatus" &&
+                    counter.current !== null &&
+                    message.counter !== counter.current + 1
+                ) {
+                    // we missed an update, so reconnect to catch up
+                    socket.close(4000, "missed update");
+                    return;
+                }
+                counter.current = message.counter;
+            }
+            dispatchMessage(message);
+        }
+    );
+
+    const loadOlderHistory = () => {
+        if (ws.current !== null && ws.current.readyState === WebSocket.OPEN) {
+            ws.current.send(
+                JSON.stringify({
+                    type: "GetHistory",
+                    before: data.historyOffset,
+                    limit: 50
+                })
+            );
+        }
+    };
 
     return (
         <MuiThemeProvider theme={theme}>
@@ -234,7 +347,12 @@ function Page() {
                     </Tabs>
                 </AppBar>
                 {tab === 0 && <ResourcesView data={data} />}
-                {tab === 1 && <HistoryView data={data} />}
+                {tab === 1 && (
+                    <HistoryView
+                        data={data}
+                        loadOlderHistory={loadOlderHistory}
+                    />
+                )}
                 {tab === 2 && <pre>{JSON.stringify(data, null, 4)}</pre>}
             </div>
         </MuiThemeProvider>